@@ -0,0 +1,550 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter, SeekFrom};
+use std::path::Path;
+use std::ptr::NonNull;
+
+use log::checksum;
+use storage::{Slot, Storage, StorageImpl};
+use ttl;
+
+const RECORD_SIZE: usize = 46;
+const CHECKSUM_SIZE: usize = 8;
+
+/// A change of a value, as archived
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Record {
+    /// Increases by one with every record, across the files of an archive
+    pub seq: u64,
+    /// When the change was made, in milliseconds since the epoch
+    pub time: u64,
+    pub key: u32,
+    /// `None` for deletes
+    pub value: Option<u64>,
+    pub expires_at: u64,
+    /// Id of the transaction making the change, which is the sequence number of its first
+    /// record, or 0 outside transactions
+    pub txn: u64,
+    /// Whether it is the last change of its transaction
+    pub end: bool,
+}
+
+impl Record {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        buf.extend_from_slice(&self.seq.to_le_bytes());
+        buf.extend_from_slice(&self.time.to_le_bytes());
+        buf.extend_from_slice(&self.key.to_le_bytes());
+        buf.push(self.value.is_some() as u8);
+        buf.extend_from_slice(&self.value.unwrap_or(0).to_le_bytes());
+        buf.extend_from_slice(&self.expires_at.to_le_bytes());
+        buf.extend_from_slice(&self.txn.to_le_bytes());
+        buf.push(self.end as u8);
+        let checksum = checksum(&buf[start..]);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+    }
+
+    /// Returns `None` unless `buf` holds a whole record with a valid checksum
+    fn decode(buf: &[u8; RECORD_SIZE + CHECKSUM_SIZE]) -> Option<Record> {
+        if u64_at(buf, RECORD_SIZE) != checksum(&buf[..RECORD_SIZE]) {
+            return None;
+        }
+        Some(Record {
+            seq: u64_at(buf, 0),
+            time: u64_at(buf, 8),
+            key: u32_at(buf, 16),
+            value: match buf[20] {
+                0 => None,
+                _ => Some(u64_at(buf, 21)),
+            },
+            expires_at: u64_at(buf, 29),
+            txn: u64_at(buf, 37),
+            end: buf[45] != 0,
+        })
+    }
+}
+
+/// Append-only file of every change made through a buffer, to restore a backup to any point
+/// after it was taken. Records are buffered and reach disk at every sync of the buffer.
+pub struct Archive {
+    out: BufWriter<File>,
+    next_seq: u64,
+    /// A failed write loses records, so every flush reports it from then on. The buffer replaces
+    /// the archive to start over.
+    error: Option<String>,
+}
+
+impl Archive {
+    /// Open the archive file at `path`, appending after its records. A record torn by a crash
+    /// is cut off, but a file corrupted before its last record is refused. Numbering continues
+    /// from the last record, or starts at `first_seq`, which must be positive.
+    pub fn open<P: AsRef<Path>>(path: P, first_seq: u64) -> io::Result<Archive> {
+        assert!(first_seq > 0);
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.as_ref())?;
+
+        let mut next_seq = first_seq;
+        let mut len = 0;
+        for record in Records::new(BufReader::new(&mut file)) {
+            next_seq = record?.seq + 1;
+            len += (RECORD_SIZE + CHECKSUM_SIZE) as u64;
+        }
+        file.set_len(len)?;
+        file.seek(SeekFrom::End(0))?;
+
+        Ok(Archive {
+            out: BufWriter::new(file),
+            next_seq,
+            error: None,
+        })
+    }
+
+    /// Sequence number of the next record
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    pub(crate) fn continue_from(&mut self, previous: &Archive) {
+        self.next_seq = self.next_seq.max(previous.next_seq);
+    }
+
+    /// Append a change made outside transactions
+    pub(crate) fn append(&mut self, key: u32, value: Option<u64>, expires_at: u64) {
+        self.append_record(key, value, expires_at, 0, false);
+    }
+
+    /// Append a change made by a transaction. `txn` is `None` for its first change, and then set
+    /// to the id of the transaction, so that ids never repeat across the files of an archive.
+    pub(crate) fn append_txn(
+        &mut self,
+        key: u32,
+        value: Option<u64>,
+        expires_at: u64,
+        txn: &mut Option<u64>,
+        end: bool,
+    ) {
+        let id = *txn.get_or_insert(self.next_seq);
+        self.append_record(key, value, expires_at, id, end);
+    }
+
+    fn append_record(
+        &mut self,
+        key: u32,
+        value: Option<u64>,
+        expires_at: u64,
+        txn: u64,
+        end: bool,
+    ) {
+        if self.error.is_some() {
+            return;
+        }
+        let record = Record {
+            seq: self.next_seq,
+            time: ttl::now(),
+            key,
+            value,
+            expires_at,
+            txn,
+            end,
+        };
+        let mut buf = Vec::with_capacity(RECORD_SIZE + CHECKSUM_SIZE);
+        record.encode(&mut buf);
+        match self.out.write_all(&buf) {
+            Ok(()) => self.next_seq += 1,
+            Err(err) => self.error = Some(err.to_string()),
+        }
+    }
+
+    /// Durably write the buffered records
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(ref message) = self.error {
+            return Err(io::Error::other(format!(
+                "archive write failed: {}",
+                message
+            )));
+        }
+        self.out.flush()?;
+        self.out.get_ref().sync_data()
+    }
+}
+
+/// Records read in order, up to the end of the file or a torn final record. A record failing its
+/// checksum before the last one is an error.
+pub struct Records<R> {
+    reader: R,
+}
+
+impl<R: Read> Records<R> {
+    pub fn new(reader: R) -> Records<R> {
+        Records { reader }
+    }
+}
+
+impl<R: Read> Iterator for Records<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        let mut buf = [0; RECORD_SIZE + CHECKSUM_SIZE];
+        match self.reader.read_exact(&mut buf) {
+            Ok(()) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(err) => return Some(Err(err)),
+        }
+        if let Some(record) = Record::decode(&buf) {
+            return Some(Ok(record));
+        }
+        // Only the last record can have been torn by a crash
+        match self.reader.read(&mut [0]) {
+            Ok(0) => None,
+            Ok(_) => Some(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "corrupted record before the end of the archive",
+            ))),
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+/// Read the records of the archive file at `path`
+pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Records<BufReader<File>>> {
+    Ok(Records::new(BufReader::new(File::open(path)?)))
+}
+
+/// Where a restore stops
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Until {
+    /// After the record with this sequence number
+    Seq(u64),
+    /// After the last record made at or before this time, in milliseconds since the epoch
+    Time(u64),
+}
+
+impl Until {
+    fn includes(&self, record: &Record) -> bool {
+        match *self {
+            Until::Seq(seq) => record.seq <= seq,
+            Until::Time(time) => record.time <= time,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Restored {
+    /// Records written to storage
+    pub n_records: u64,
+    /// Records of transactions not complete at the stopping point, left out
+    pub n_skipped: u64,
+    /// Last record up to the stopping point
+    pub last: Option<Record>,
+}
+
+/// Replay the records of the archive files at `paths`, in order, onto `storage` up to `until`,
+/// then sync it. Storage must be a backup taken after the first file was started, and the files
+/// must follow each other with no record missing. Since records hold whole values, those from
+/// before the backup only rewrite what it already holds. Transactions not complete at the
+/// stopping point are left out entirely. Fails if the stopping point is before the backup was
+/// taken, as its header records, since the backup already holds later changes.
+pub fn restore<P: AsRef<Path>>(
+    storage: &mut StorageImpl,
+    paths: &[P],
+    until: Until,
+) -> io::Result<Restored> {
+    let taken_at = storage.backup_info()?.archive_seq;
+
+    // First find the stopping point and the transactions still open there
+    let mut restored = Restored::default();
+    let mut open = HashSet::new();
+    let mut first_seq = None;
+    'files: for path in paths {
+        for record in read(path)? {
+            let record = record?;
+            if first_seq.is_none() {
+                first_seq = Some(record.seq);
+                if taken_at != 0 && record.seq > taken_at {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("records {} to {} are missing", taken_at, record.seq - 1),
+                    ));
+                }
+            }
+            if let Some(ref last) = restored.last {
+                if record.seq != last.seq + 1 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("records {} to {} are missing", last.seq + 1, record.seq),
+                    ));
+                }
+            }
+            if !until.includes(&record) {
+                break 'files;
+            }
+            if record.txn != 0 {
+                if record.end {
+                    open.remove(&record.txn);
+                } else {
+                    open.insert(record.txn);
+                }
+            }
+            restored.last = Some(record);
+        }
+    }
+
+    // Sequence number of the first record left out
+    let stop = restored
+        .last
+        .map_or(first_seq.unwrap_or(taken_at), |last| last.seq + 1);
+    if stop < taken_at {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "cannot stop before record {}, where the backup was taken",
+                taken_at
+            ),
+        ));
+    }
+    let last_seq = match restored.last {
+        Some(ref last) => last.seq,
+        None => return Ok(restored),
+    };
+    let n_keys = storage.n_keys();
+    'replay: for path in paths {
+        for record in read(path)? {
+            let record = record?;
+            if record.seq > last_seq {
+                break 'replay;
+            }
+            if open.contains(&record.txn) {
+                restored.n_skipped += 1;
+                continue;
+            }
+            if record.key >= n_keys {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("key {} beyond the {} keys of storage", record.key, n_keys),
+                ));
+            }
+            let mut slot = Slot::new(record.value, record.expires_at);
+            storage.write(record.key, NonNull::from(&mut slot))?;
+            restored.n_records += 1;
+        }
+    }
+
+    storage.sync()?;
+    Ok(restored)
+}
+
+fn u32_at(buf: &[u8], pos: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[pos..pos + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn u64_at(buf: &[u8], pos: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[pos..pos + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::Duration;
+
+    use buffer::{Buffer, BufferImpl};
+    use cache::LruCache;
+    use log::Log;
+    use storage::StorageImpl;
+
+    use super::*;
+
+    fn read_value(storage: &mut dyn Storage, key: u32) -> Option<u64> {
+        let mut slot = Slot::default();
+        storage.read(key, NonNull::from(&mut slot)).unwrap();
+        slot.value
+    }
+
+    fn last_seq(path: &str) -> u64 {
+        read(path).unwrap().last().unwrap().unwrap().seq
+    }
+
+    #[test]
+    fn restore_before_a_bad_batch() {
+        let (first, second) = ("tmp/archive_1.1.archive", "tmp/archive_1.2.archive");
+        let _ = fs::remove_file(first);
+        let _ = fs::remove_file(second);
+
+        let cache = Box::new(LruCache::new(10));
+        let storage = Box::new(StorageImpl::new("tmp/archive_1.db", 100).unwrap());
+        let buffer = BufferImpl::new(cache, storage);
+        buffer
+            .set_archive(Archive::open(first, 1).unwrap())
+            .1
+            .unwrap();
+
+        buffer.put(1, 10).unwrap();
+        let _ = fs::remove_file("tmp/archive_1.bak");
+        buffer.backup("tmp/archive_1.bak").unwrap();
+        buffer.put(2, 20).unwrap();
+        buffer.put_with_ttl(3, 30, Duration::from_secs(60)).unwrap();
+        let mut tx = buffer.transaction(&[4, 5]);
+        tx.put(4, 40).unwrap();
+        tx.put(5, 50).unwrap();
+        tx.commit().unwrap();
+
+        // Rotating keeps the numbering going
+        let (_, flushed) = buffer.set_archive(Archive::open(second, 1).unwrap());
+        flushed.unwrap();
+        buffer.delete(1).unwrap();
+        buffer.sync().unwrap();
+        let good = last_seq(second);
+
+        for key in 0..100 {
+            buffer.fetch_add(key, 1000).unwrap();
+        }
+        buffer.sync().unwrap();
+
+        let paths = [first, second];
+        fs::copy("tmp/archive_1.bak", "tmp/archive_1.restored").unwrap();
+        let mut copy = StorageImpl::open("tmp/archive_1.restored", 1).unwrap();
+        let restored = restore(&mut copy, &paths, Until::Seq(good)).unwrap();
+        assert_eq!(restored.last.unwrap().seq, good);
+        assert_eq!(restored.n_skipped, 0);
+        let values = (0..6)
+            .map(|key| read_value(&mut copy, key))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            vec![None, None, Some(20), Some(30), Some(40), Some(50)]
+        );
+        let mut slot = Slot::default();
+        copy.read(3, NonNull::from(&mut slot)).unwrap();
+        assert!(slot.expires_at > 0);
+
+        let restored = restore(&mut copy, &paths, Until::Seq(u64::MAX)).unwrap();
+        assert_eq!(restored.last.unwrap().seq, last_seq(second));
+        assert_eq!(read_value(&mut copy, 99), Some(1000));
+        assert_eq!(read_value(&mut copy, 2), Some(1020));
+    }
+
+    #[test]
+    fn skip_incomplete_transactions() {
+        let path = "tmp/archive_2.archive";
+        let _ = fs::remove_file(path);
+
+        let mut archive = Archive::open(path, 7).unwrap();
+        let mut txn = None;
+        archive.append(0, Some(1), 0);
+        archive.append_txn(1, Some(2), 0, &mut txn, false);
+        archive.append(2, Some(3), 0);
+        archive.append_txn(3, Some(4), 0, &mut txn, true);
+        assert_eq!(txn, Some(8));
+        archive.flush().unwrap();
+        drop(archive);
+
+        // Tear a record as if crashed while writing it, then keep appending
+        let len = fs::metadata(path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+        let mut archive = Archive::open(path, 1).unwrap();
+        assert_eq!(archive.next_seq(), 10);
+        archive.append(3, Some(5), 0);
+        archive.flush().unwrap();
+        let seqs = read(path)
+            .unwrap()
+            .map(|record| record.unwrap().seq)
+            .collect::<Vec<_>>();
+        assert_eq!(seqs, vec![7, 8, 9, 10]);
+
+        let mut storage = StorageImpl::new("tmp/archive_2.db", 10).unwrap();
+        let restored = restore(&mut storage, &[path], Until::Seq(9)).unwrap();
+        assert_eq!((restored.n_records, restored.n_skipped), (2, 1));
+        assert_eq!(read_value(&mut storage, 1), None);
+        assert_eq!(read_value(&mut storage, 2), Some(3));
+
+        // Damage before the last record is not a crash, and must not cost the records after it
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(21)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+        drop(file);
+        let err = Archive::open(path, 1).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::metadata(path).unwrap().len(), 4 * 54);
+    }
+
+    #[test]
+    fn refuse_stopping_before_the_backup() {
+        let path = "tmp/archive_3.archive";
+        let _ = fs::remove_file(path);
+
+        let cache = Box::new(LruCache::new(10));
+        let storage = Box::new(StorageImpl::new("tmp/archive_3.db", 10).unwrap());
+        let buffer = BufferImpl::new(cache, storage);
+        buffer
+            .set_archive(Archive::open(path, 1).unwrap())
+            .1
+            .unwrap();
+        buffer.put(1, 10).unwrap();
+        buffer.put(2, 20).unwrap();
+        let _ = fs::remove_file("tmp/archive_3.bak");
+        buffer.backup("tmp/archive_3.bak").unwrap();
+        buffer.put(3, 30).unwrap();
+        buffer.sync().unwrap();
+
+        let mut copy = StorageImpl::open("tmp/archive_3.bak", 1).unwrap();
+        assert_eq!(copy.backup_info().unwrap().archive_seq, 3);
+        let err = restore(&mut copy, &[path], Until::Seq(1)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(read_value(&mut copy, 2), Some(20));
+
+        let restored = restore(&mut copy, &[path], Until::Seq(2)).unwrap();
+        assert_eq!(restored.last.unwrap().seq, 2);
+        let restored = restore(&mut copy, &[path], Until::Seq(3)).unwrap();
+        assert_eq!(restored.n_records, 3);
+        assert_eq!(read_value(&mut copy, 3), Some(30));
+    }
+
+    #[test]
+    fn archive_replayed_commits() {
+        let (path, log_path) = ("tmp/archive_4.archive", "tmp/archive_4.log");
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(log_path);
+
+        // Logged by a run that crashed before syncing its archive
+        let mut log = Log::open(log_path).unwrap();
+        log.append(&[(1, Some(10)), (2, None)]).unwrap();
+        log.append(&[(3, Some(30))]).unwrap();
+        drop(log);
+
+        let cache = Box::new(LruCache::new(10));
+        let storage = Box::new(StorageImpl::new("tmp/archive_4.db", 10).unwrap());
+        let log = Log::open(log_path).unwrap();
+        let archive = Archive::open(path, 5).unwrap();
+        let buffer = BufferImpl::with_log_and_archive(cache, storage, log, archive).unwrap();
+        assert_eq!(buffer.get(1).unwrap(), Some(10));
+
+        let records = read(path)
+            .unwrap()
+            .map(|record| record.unwrap())
+            .map(|record| (record.seq, record.key, record.value, record.txn, record.end))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            records,
+            vec![
+                (5, 1, Some(10), 5, false),
+                (6, 2, None, 5, true),
+                (7, 3, Some(30), 7, true),
+            ]
+        );
+        assert_eq!(buffer.archive_seq(), 8);
+    }
+}
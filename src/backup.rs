@@ -0,0 +1,604 @@
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::mem;
+use std::path::Path;
+use std::ptr::NonNull;
+
+use buffer::BufferImpl;
+use error::Result;
+use scan::CHUNK_LEN;
+use storage::{self, BackupInfo, Slot, Storage, StorageImpl, SLOT_WORDS};
+use ttl;
+
+/// Number of keys in a page, the unit of incremental backups
+pub const PAGE_LEN: u32 = 256;
+
+const DELTA_MAGIC: &[u8; 8] = b"PIYODLT2";
+
+/// Keys as a bitmap, one bit per key up to the highest one inserted
+#[derive(Debug, Default)]
+struct KeySet {
+    words: Vec<u64>,
+}
+
+impl KeySet {
+    fn insert_range(&mut self, first: u32, len: usize) {
+        for key in (first..).take(len) {
+            let i = key as usize / 64;
+            if i >= self.words.len() {
+                self.words.resize(i + 1, 0);
+            }
+            self.words[i] |= 1 << (key % 64);
+        }
+    }
+
+    /// Keys in ascending order
+    fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.words.iter().enumerate().flat_map(|(i, &word)| {
+            (0..64)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| (i * 64 + bit) as u32)
+        })
+    }
+}
+
+/// Pages written since the last backup of a chain, which starts with a full backup
+struct Changes {
+    chain: u64,
+    seq: u32,
+    pages: BTreeSet<u32>,
+}
+
+/// Storage of a buffer, recording the keys written while a backup copies it and the pages
+/// written since the last backup
+pub(crate) struct Tracked {
+    inner: Box<dyn Storage + Send>,
+    captured: Option<KeySet>,
+    changes: Option<Changes>,
+}
+
+impl Tracked {
+    pub(crate) fn new(inner: Box<dyn Storage + Send>) -> Tracked {
+        Tracked {
+            inner,
+            captured: None,
+            changes: None,
+        }
+    }
+
+    /// Start recording written keys. Fails if a backup is already recording them.
+    fn start_capture(&mut self) -> io::Result<()> {
+        if self.captured.is_some() {
+            return Err(io::Error::other("a backup is already running"));
+        }
+        self.captured = Some(KeySet::default());
+        Ok(())
+    }
+
+    /// Stop recording and return the keys written since the capture started
+    fn stop_capture(&mut self) -> KeySet {
+        self.captured.take().unwrap_or_default()
+    }
+
+    /// Take the keys written since the capture started, recording on
+    fn take_captured(&mut self) -> KeySet {
+        self.captured.as_mut().map(mem::take).unwrap_or_default()
+    }
+
+    fn record(&mut self, first: u32, len: usize) {
+        if len == 0 {
+            return;
+        }
+        if let Some(ref mut captured) = self.captured {
+            captured.insert_range(first, len);
+        }
+        if let Some(ref mut changes) = self.changes {
+            let last = first + (len as u32 - 1);
+            changes.pages.extend(first / PAGE_LEN..=last / PAGE_LEN);
+        }
+    }
+
+    /// Read the slots of `page`, the last page being shorter unless `n_keys` is a multiple of
+    /// `PAGE_LEN`
+    fn read_page(&mut self, page: u32) -> io::Result<Vec<Slot>> {
+        let first = page * PAGE_LEN;
+        let len = cmp::min(PAGE_LEN, self.n_keys() - first);
+        let mut slots = vec![Slot::default(); len as usize];
+        self.inner.read_range(first, &mut slots)?;
+        Ok(slots)
+    }
+}
+
+impl Storage for Tracked {
+    fn read(&mut self, key: u32, dst: NonNull<Slot>) -> io::Result<()> {
+        self.inner.read(key, dst)
+    }
+
+    fn write(&mut self, key: u32, src: NonNull<Slot>) -> io::Result<()> {
+        self.record(key, 1);
+        self.inner.write(key, src)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync()
+    }
+
+    fn n_keys(&self) -> u32 {
+        self.inner.n_keys()
+    }
+
+    fn read_range(&mut self, key: u32, dst: &mut [Slot]) -> io::Result<()> {
+        self.inner.read_range(key, dst)
+    }
+
+    fn write_range(&mut self, key: u32, src: &[Slot]) -> io::Result<()> {
+        self.record(key, src.len());
+        self.inner.write_range(key, src)
+    }
+}
+
+impl BufferImpl {
+    /// Write a consistent copy of the storage to a new data file at `dest`, failing if a file is
+    /// already there, while the buffer
+    /// stays in use. Storage is copied a chunk at a time, then the keys written meanwhile are
+    /// copied again within a final sync, so the copy holds the values at the end of that sync.
+    /// With a log, it holds whole transactions.
+    ///
+    /// The backup starts a new chain of incremental backups, recorded in its header.
+    pub fn backup<P: AsRef<Path>>(&self, dest: P) -> Result<()> {
+        let n_keys = self.n_keys();
+        let mut dest = StorageImpl::create_new(dest, cmp::max(n_keys, 1))?;
+
+        self.lock_storage().start_capture()?;
+        let result = self.copy_to(&mut dest, n_keys);
+        // Already stopped unless copying failed
+        self.lock_storage().stop_capture();
+        let info = result?;
+
+        dest.set_backup_info(info)?;
+        dest.sync()?;
+        Ok(())
+    }
+
+    fn copy_to(&self, dest: &mut StorageImpl, n_keys: u32) -> Result<BackupInfo> {
+        let mut slots = Vec::new();
+        let mut first = 0;
+        while first < n_keys {
+            let len = cmp::min(CHUNK_LEN, n_keys - first);
+            slots.resize(len as usize, Slot::default());
+            self.lock_storage().read_range(first, &mut slots)?;
+            dest.write_range(first, &slots)?;
+            first += len;
+        }
+
+        let mut info = BackupInfo::default();
+        self.sync_and(&mut |storage| {
+            let mut slot = Slot::default();
+            for key in storage.stop_capture().iter() {
+                storage.read(key, NonNull::from(&mut slot))?;
+                dest.write(key, NonNull::from(&mut slot))?;
+            }
+            // Distinct from the previous chain even if started within the same millisecond
+            info.chain = storage.changes.as_ref().map_or(ttl::now(), |changes| {
+                cmp::max(ttl::now(), changes.chain + 1)
+            });
+            info.archive_seq = self.archive_seq();
+            storage.changes = Some(Changes {
+                chain: info.chain,
+                seq: 0,
+                pages: BTreeSet::new(),
+            });
+            Ok(())
+        })?;
+        Ok(info)
+    }
+
+    /// Write the pages changed since the last backup to a delta file at `dest`, to apply with
+    /// `apply_incremental` on top of that backup. Pages are copied like in `backup`, so the
+    /// delta holds the values at the end of a sync. Fails unless a full backup was taken since
+    /// the buffer was created, as changes are only tracked in memory.
+    pub fn backup_incremental<P: AsRef<Path>>(&self, dest: P) -> Result<DeltaInfo> {
+        let dest = dest.as_ref();
+        let pages = {
+            let mut storage = self.lock_storage();
+            let pages = match storage.changes {
+                Some(ref changes) => changes.pages.clone(),
+                None => return Err(io::Error::other("no full backup to start from").into()),
+            };
+            storage.start_capture()?;
+            pages
+        };
+        let created = OpenOptions::new().write(true).create_new(true).open(dest);
+        let out = match created {
+            Ok(file) => BufWriter::new(file),
+            Err(err) => {
+                self.lock_storage().stop_capture();
+                return Err(err.into());
+            }
+        };
+
+        let delta = self.copy_changes(pages);
+        let written = match delta {
+            Ok(ref delta) => write_delta(out, delta),
+            Err(_) => Ok(()),
+        };
+
+        if delta.is_err() || written.is_err() {
+            // A torn delta must not be taken for one of the good ones next to it
+            let _ = fs::remove_file(dest);
+        }
+        let mut storage = self.lock_storage();
+        storage.stop_capture();
+        let delta = delta?;
+        if let Err(err) = written {
+            // The next delta must cover these pages. The capture kept other backups out, so
+            // the chain has not moved on.
+            if let Some(ref mut changes) = storage.changes {
+                changes.seq -= 1;
+                changes.pages.extend(delta.pages.keys());
+            }
+            return Err(err.into());
+        }
+        Ok(delta.info)
+    }
+
+    fn copy_changes(&self, pages: BTreeSet<u32>) -> Result<Delta> {
+        let mut copied = BTreeMap::new();
+        for page in pages {
+            let slots = self.lock_storage().read_page(page)?;
+            copied.insert(page, slots);
+        }
+
+        let mut delta = None;
+        self.sync_and(&mut |storage| {
+            let captured = storage.take_captured();
+            let pages = captured
+                .iter()
+                .map(|key| key / PAGE_LEN)
+                .collect::<BTreeSet<_>>();
+            for page in pages {
+                copied.insert(page, storage.read_page(page)?);
+            }
+
+            let n_keys = storage.n_keys();
+            let changes = storage.changes.as_mut().expect("changes stay tracked");
+            changes.seq += 1;
+            changes.pages.clear();
+            delta = Some(Delta {
+                info: DeltaInfo {
+                    chain: changes.chain,
+                    seq: changes.seq,
+                    n_keys,
+                    n_pages: copied.len() as u32,
+                    archive_seq: self.archive_seq(),
+                },
+                pages: mem::take(&mut copied),
+            });
+            Ok(())
+        })?;
+        Ok(delta.expect("set by the sync"))
+    }
+}
+
+/// What a delta file holds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeltaInfo {
+    /// When the full backup this delta follows was taken, in milliseconds since the epoch
+    pub chain: u64,
+    /// Position in the chain, from 1 for the first delta after the full backup
+    pub seq: u32,
+    pub n_keys: u32,
+    pub n_pages: u32,
+    /// As in `BackupInfo::archive_seq`
+    pub archive_seq: u64,
+}
+
+struct Delta {
+    info: DeltaInfo,
+    pages: BTreeMap<u32, Vec<Slot>>,
+}
+
+fn write_delta(mut out: BufWriter<File>, delta: &Delta) -> io::Result<()> {
+    let info = &delta.info;
+    out.write_all(DELTA_MAGIC)?;
+    out.write_all(&info.chain.to_le_bytes())?;
+    out.write_all(&info.seq.to_le_bytes())?;
+    out.write_all(&info.n_keys.to_le_bytes())?;
+    out.write_all(&info.n_pages.to_le_bytes())?;
+    out.write_all(&info.archive_seq.to_le_bytes())?;
+
+    let mut words = [0; SLOT_WORDS];
+    for (&page, slots) in &delta.pages {
+        out.write_all(&page.to_le_bytes())?;
+        for &slot in slots {
+            storage::encode(slot, &mut words);
+            for word in &words {
+                out.write_all(&word.to_le_bytes())?;
+            }
+        }
+    }
+    out.into_inner().map_err(|err| err.into_error())?.sync_all()
+}
+
+fn read_info<R: Read>(input: &mut R) -> io::Result<DeltaInfo> {
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != DELTA_MAGIC {
+        return Err(invalid("not a delta file".to_string()));
+    }
+    Ok(DeltaInfo {
+        chain: read_u64(input)?,
+        seq: read_u32(input)?,
+        n_keys: read_u32(input)?,
+        n_pages: read_u32(input)?,
+        archive_seq: read_u64(input)?,
+    })
+}
+
+/// Read the header of the delta file at `path`
+pub fn delta_info<P: AsRef<Path>>(path: P) -> io::Result<DeltaInfo> {
+    read_info(&mut File::open(path)?)
+}
+
+/// Read the whole delta file at `path`, failing if it is truncated or malformed
+pub fn check_delta<P: AsRef<Path>>(path: P) -> io::Result<DeltaInfo> {
+    for_each_page(path, &mut |_, _| Ok(()))
+}
+
+/// Call `f` with the first key and the slots of every page of the delta file at `path`, then
+/// check that nothing follows the last page
+fn for_each_page<P: AsRef<Path>>(
+    path: P,
+    f: &mut dyn FnMut(u32, &[Slot]) -> io::Result<()>,
+) -> io::Result<DeltaInfo> {
+    let mut input = BufReader::new(File::open(path)?);
+    let info = read_info(&mut input)?;
+
+    let mut words = [0; SLOT_WORDS];
+    let mut slots = Vec::new();
+    for _ in 0..info.n_pages {
+        let page = read_u32(&mut input)?;
+        let first = u64::from(page) * u64::from(PAGE_LEN);
+        if first >= u64::from(info.n_keys) {
+            return Err(invalid(format!("page {} beyond the last key", page)));
+        }
+        let first = first as u32;
+        slots.clear();
+        for _ in 0..cmp::min(PAGE_LEN, info.n_keys - first) {
+            for word in &mut words {
+                *word = read_u64(&mut input)?;
+            }
+            slots.push(storage::decode(&words));
+        }
+        f(first, &slots)?;
+    }
+
+    if input.fill_buf()?.is_empty() {
+        Ok(info)
+    } else {
+        Err(invalid("trailing bytes after the last page".to_string()))
+    }
+}
+
+/// Read the backup info in the header of the data file at `path`
+pub fn backup_info<P: AsRef<Path>>(path: P) -> io::Result<BackupInfo> {
+    let mut file = File::open(path)?;
+    storage::check_header(&mut file)?;
+    storage::read_backup_info(&mut file)
+}
+
+/// Write the pages of the delta file at `delta` to the data file at `data`, which must be the
+/// full backup the delta follows with the previous deltas of the chain applied, as recorded in
+/// its header. The whole delta is read first, so a bad one leaves `data` untouched. Never run
+/// it on a file a server has open.
+pub fn apply_incremental<P, Q>(delta: P, data: Q) -> io::Result<DeltaInfo>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let info = check_delta(delta.as_ref())?;
+    let backup = backup_info(data.as_ref())?;
+    if backup.chain != info.chain || backup.seq + 1 != info.seq {
+        return Err(invalid(format!(
+            "delta of chain {} seq {} does not follow chain {} seq {}",
+            info.chain, info.seq, backup.chain, backup.seq
+        )));
+    }
+    let mut storage = StorageImpl::open(data, cmp::max(info.n_keys, 1))?;
+    if storage.n_keys() != info.n_keys {
+        return Err(invalid(format!(
+            "delta of {} keys for a file of {}",
+            info.n_keys,
+            storage.n_keys()
+        )));
+    }
+
+    // Checked above, but the file may have changed since
+    let read = for_each_page(delta, &mut |first, slots| storage.write_range(first, slots))?;
+    if read != info {
+        return Err(invalid("delta changed while applied".to_string()));
+    }
+    storage.set_backup_info(BackupInfo {
+        chain: info.chain,
+        seq: info.seq,
+        archive_seq: info.archive_seq,
+    })?;
+    storage.sync()?;
+    Ok(info)
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use buffer::Buffer;
+    use cache::LruCache;
+    use check;
+    use log::Log;
+
+    use super::*;
+
+    fn new_buffer(path: &str, n_keys: u32) -> BufferImpl {
+        let cache = Box::new(LruCache::new(100));
+        let storage = Box::new(StorageImpl::new(path, n_keys).unwrap());
+        let log_path = format!("{}.log", path);
+        let _ = fs::remove_file(&log_path);
+        let log = Log::open(log_path).unwrap();
+        BufferImpl::with_log(cache, storage, log).unwrap()
+    }
+
+    fn open(path: &str) -> BufferImpl {
+        let cache = Box::new(LruCache::new(100));
+        let storage = Box::new(StorageImpl::open(path, 1).unwrap());
+        BufferImpl::new(cache, storage)
+    }
+
+    #[test]
+    fn backup_copies_dirty_entries() {
+        let buffer = new_buffer("tmp/backup_1.db", 3000);
+        for key in 0..3000 {
+            buffer.put(key, u64::from(key)).unwrap();
+        }
+        buffer.delete(7).unwrap();
+
+        let _ = fs::remove_file("tmp/backup_1.bak");
+        buffer.backup("tmp/backup_1.bak").unwrap();
+        // Neither an earlier backup nor the data file itself is overwritten
+        assert!(buffer.backup("tmp/backup_1.bak").is_err());
+        assert!(buffer.backup("tmp/backup_1.db").is_err());
+        assert_eq!(buffer.get(2999).unwrap(), Some(2999));
+
+        let copy = open("tmp/backup_1.bak");
+        assert_eq!(copy.get(7).unwrap(), None);
+        assert_eq!(copy.get(2999).unwrap(), Some(2999));
+        let sum: u64 = copy.scan().map(|item| item.unwrap().1).sum();
+        assert_eq!(sum, (0..3000).sum::<u64>() - 7);
+    }
+
+    #[test]
+    fn backup_while_writing() {
+        let buffer = Arc::new(new_buffer("tmp/backup_2.db", 5000));
+        let done = Arc::new(AtomicBool::new(false));
+
+        // Every transaction moves one unit between two keys, so the sum stays 0 only if the copy
+        // holds whole transactions
+        let writer = {
+            let (buffer, done) = (buffer.clone(), done.clone());
+            thread::spawn(move || {
+                let mut i = 0u32;
+                while !done.load(Ordering::Relaxed) {
+                    let keys = [i % 5000, (i * 7 + 1) % 5000];
+                    if keys[0] != keys[1] {
+                        let mut tx = buffer.transaction(&keys);
+                        let a = tx.get(keys[0]).unwrap().unwrap_or(0);
+                        let b = tx.get(keys[1]).unwrap().unwrap_or(0);
+                        tx.put(keys[0], a.wrapping_add(1)).unwrap();
+                        tx.put(keys[1], b.wrapping_sub(1)).unwrap();
+                        tx.commit().unwrap();
+                    }
+                    i = i.wrapping_add(1);
+                }
+            })
+        };
+
+        thread::sleep(Duration::from_millis(20));
+        let _ = fs::remove_file("tmp/backup_2.bak");
+        buffer.backup("tmp/backup_2.bak").unwrap();
+        done.store(true, Ordering::Relaxed);
+        writer.join().unwrap();
+
+        let copy = open("tmp/backup_2.bak");
+        let sum = copy
+            .scan()
+            .fold(0u64, |sum, item| sum.wrapping_add(item.unwrap().1));
+        assert_eq!(sum, 0);
+    }
+
+    #[test]
+    fn incremental_backups() {
+        let buffer = new_buffer("tmp/backup_3.db", 3000);
+        let _ = fs::remove_file("tmp/backup_3.delta");
+        assert!(buffer.backup_incremental("tmp/backup_3.delta").is_err());
+        assert!(!Path::new("tmp/backup_3.delta").exists());
+        for key in 0..3000 {
+            buffer.put(key, 1).unwrap();
+        }
+        for path in &["bak", "full", "1.delta", "2.delta"] {
+            let _ = fs::remove_file(format!("tmp/backup_3.{}", path));
+        }
+        buffer.backup("tmp/backup_3.bak").unwrap();
+
+        buffer.put(3, 2).unwrap();
+        buffer.delete(2999).unwrap();
+        let first = buffer.backup_incremental("tmp/backup_3.1.delta").unwrap();
+        assert_eq!((first.seq, first.n_pages), (1, 2));
+
+        buffer.put(300, 2).unwrap();
+        let second = buffer.backup_incremental("tmp/backup_3.2.delta").unwrap();
+        assert_eq!(
+            (second.chain, second.seq, second.n_pages),
+            (first.chain, 2, 1)
+        );
+        assert_eq!(delta_info("tmp/backup_3.2.delta").unwrap(), second);
+
+        // An existing delta is never overwritten
+        let before = fs::read("tmp/backup_3.1.delta").unwrap();
+        assert!(buffer.backup_incremental("tmp/backup_3.1.delta").is_err());
+        assert_eq!(fs::read("tmp/backup_3.1.delta").unwrap(), before);
+
+        // Out of order, or onto a backup of another chain
+        assert!(apply_incremental("tmp/backup_3.2.delta", "tmp/backup_3.bak").is_err());
+        let _ = fs::remove_file("tmp/backup_3.other");
+        buffer.backup("tmp/backup_3.other").unwrap();
+        assert!(apply_incremental("tmp/backup_3.1.delta", "tmp/backup_3.other").is_err());
+
+        // A truncated delta is refused before anything is written
+        let bytes = fs::read("tmp/backup_3.1.delta").unwrap();
+        fs::write("tmp/backup_3.torn.delta", &bytes[..bytes.len() - 8]).unwrap();
+        let before = fs::read("tmp/backup_3.bak").unwrap();
+        assert!(apply_incremental("tmp/backup_3.torn.delta", "tmp/backup_3.bak").is_err());
+        assert_eq!(fs::read("tmp/backup_3.bak").unwrap(), before);
+
+        apply_incremental("tmp/backup_3.1.delta", "tmp/backup_3.bak").unwrap();
+        apply_incremental("tmp/backup_3.2.delta", "tmp/backup_3.bak").unwrap();
+        assert_eq!(
+            backup_info("tmp/backup_3.bak").unwrap(),
+            BackupInfo {
+                chain: first.chain,
+                seq: 2,
+                archive_seq: 0
+            }
+        );
+        buffer.backup("tmp/backup_3.full").unwrap();
+        assert_eq!(
+            check::compare("tmp/backup_3.bak", "tmp/backup_3.full").unwrap(),
+            vec![]
+        );
+        let copy = open("tmp/backup_3.bak");
+        assert_eq!(copy.get(3).unwrap(), Some(2));
+        assert_eq!(copy.get(2999).unwrap(), None);
+    }
+}
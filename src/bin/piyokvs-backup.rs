@@ -0,0 +1,66 @@
+extern crate piyokvs;
+
+use std::env;
+use std::process;
+
+use piyokvs::backup;
+
+const USAGE: &str = "usage: piyokvs-backup info DELTA...
+       piyokvs-backup apply FILE DELTA...
+
+Deltas are written by piyokvs-server --backup-dir. apply writes them to FILE in order, which
+must be the full backup they follow with the earlier deltas of its chain applied, as its header
+records. Deltas must belong to that chain and follow each other. No server may have FILE open.";
+
+fn info(paths: &[String]) -> Result<(), String> {
+    for path in paths {
+        let info = backup::delta_info(path).map_err(|err| format!("{}: {}", path, err))?;
+        println!(
+            "{} chain {} seq {} keys {} pages {}",
+            path, info.chain, info.seq, info.n_keys, info.n_pages
+        );
+    }
+    Ok(())
+}
+
+fn apply(data: &str, paths: &[String]) -> Result<(), String> {
+    // Read every delta whole first, so a bad one leaves FILE untouched
+    let backup = backup::backup_info(data).map_err(|err| format!("{}: {}", data, err))?;
+    let mut seq = backup.seq;
+    for path in paths {
+        let info = backup::check_delta(path).map_err(|err| format!("{}: {}", path, err))?;
+        if info.chain != backup.chain || info.seq != seq + 1 {
+            return Err(format!(
+                "{}: chain {} seq {} does not follow chain {} seq {}",
+                path, info.chain, info.seq, backup.chain, seq
+            ));
+        }
+        seq = info.seq;
+    }
+
+    for path in paths {
+        let info =
+            backup::apply_incremental(path, data).map_err(|err| format!("{}: {}", path, err))?;
+        eprintln!("applied {} ({} pages)", path, info.n_pages);
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let result = match args.split_first() {
+        Some((command, paths)) if command == "info" && !paths.is_empty() => info(paths),
+        Some((command, rest)) if command == "apply" && rest.len() >= 2 => {
+            apply(&rest[0], &rest[1..])
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,138 @@
+extern crate piyokvs;
+
+use std::env;
+use std::process;
+
+use piyokvs::check::{self, Invariants, Problem};
+
+const USAGE: &str =
+    "usage: piyokvs-check [--keys N] [--max-value N] [--repair [--delete-too-large]]
+                     FILE [REFERENCE]
+
+Checks FILE, or compares it with REFERENCE. With --repair, fixes FILE, or copies the differing
+keys from REFERENCE. Values above --max-value are only reported, unless --delete-too-large is
+given too. Never run it on a file a server has open.";
+
+struct Options {
+    invariants: Invariants,
+    repair: bool,
+    delete_too_large: bool,
+    paths: Vec<String>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        invariants: Invariants::default(),
+        repair: false,
+        delete_too_large: false,
+        paths: Vec::new(),
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--repair" => options.repair = true,
+            "--delete-too-large" => options.delete_too_large = true,
+            "--keys" | "--max-value" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("missing value for {}", arg))?;
+                if arg == "--keys" {
+                    let n_keys = value.parse().map_err(|_| "invalid --keys")?;
+                    options.invariants.n_keys = Some(n_keys);
+                } else {
+                    let max_value = value.parse().map_err(|_| "invalid --max-value")?;
+                    options.invariants.max_value = Some(max_value);
+                }
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => options.paths.push(arg),
+        }
+    }
+
+    if options.delete_too_large && !options.repair {
+        return Err("--delete-too-large needs --repair".to_string());
+    }
+    if options.paths.is_empty() || options.paths.len() > 2 {
+        return Err("expected one or two files".to_string());
+    }
+    Ok(options)
+}
+
+/// Returns whether the file is fine, or has been repaired
+fn check_file(path: &str, options: &Options) -> Result<bool, String> {
+    let err = |err: std::io::Error| format!("{}: {}", path, err);
+
+    let report = check::check(path, &options.invariants).map_err(err)?;
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    println!(
+        "{}: {} values, {} expired, {} problems",
+        path,
+        report.n_values,
+        report.n_expired,
+        report.problems.len()
+    );
+
+    if report.problems.is_empty() || !options.repair {
+        return Ok(report.problems.is_empty());
+    }
+    let n_fixed = check::repair(path, &report.problems, options.delete_too_large).map_err(err)?;
+    for problem in &report.problems {
+        if let Problem::TooLarge { key, value } = *problem {
+            if options.delete_too_large {
+                println!("key {}: deleted value {}", key, value);
+            } else {
+                println!("key {}: kept value {}, see --delete-too-large", key, value);
+            }
+        }
+    }
+    println!("{}: fixed {} problems", path, n_fixed);
+    Ok(n_fixed == report.problems.len())
+}
+
+/// Returns whether the files match, or have been made to
+fn compare_files(path: &str, reference: &str, options: &Options) -> Result<bool, String> {
+    let differences = check::compare(path, reference).map_err(|err| err.to_string())?;
+    for difference in &differences {
+        println!(
+            "key {}: {:?} (expires {}) vs {:?} (expires {})",
+            difference.key,
+            difference.left.value,
+            difference.left.expires_at,
+            difference.right.value,
+            difference.right.expires_at
+        );
+    }
+    println!("{} differences", differences.len());
+
+    if differences.is_empty() || !options.repair {
+        return Ok(differences.is_empty());
+    }
+    let n_copied =
+        check::copy_differences(path, &differences).map_err(|err| format!("{}: {}", path, err))?;
+    println!("{}: copied {} keys from {}", path, n_copied, reference);
+    Ok(true)
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|err| {
+        eprintln!("{}\n{}", err, USAGE);
+        process::exit(2);
+    });
+
+    let result = match options.paths.as_slice() {
+        [path] => check_file(path, &options),
+        [path, reference] => compare_files(path, reference, &options),
+        _ => unreachable!(),
+    };
+    match result {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(2);
+        }
+    }
+}
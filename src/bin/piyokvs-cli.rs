@@ -0,0 +1,156 @@
+extern crate piyokvs;
+extern crate rustyline;
+
+use std::env;
+use std::io::{self, BufRead, IsTerminal};
+use std::path::PathBuf;
+use std::process;
+
+use piyokvs::buffer::Buffer;
+use piyokvs::cli::{self, History, Target, HELP, USAGE};
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+
+/// Where commands entered at a terminal are kept between sessions
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".piyokvs_history"))
+}
+
+/// Source of command lines: an editor with the history at a terminal, plain lines otherwise
+enum Input {
+    Terminal(Editor<()>),
+    Lines(io::Stdin),
+}
+
+impl Input {
+    /// Next line, `None` at the end of the input
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        match *self {
+            Input::Terminal(ref mut editor) => match editor.readline("piyokvs> ") {
+                Ok(line) => Ok(Some(line)),
+                Err(ReadlineError::Eof) | Err(ReadlineError::Interrupted) => Ok(None),
+                Err(ReadlineError::Io(err)) => Err(err),
+                Err(err) => Err(io::Error::other(err.to_string())),
+            },
+            Input::Lines(ref stdin) => {
+                let mut line = String::new();
+                match stdin.lock().read_line(&mut line)? {
+                    0 => Ok(None),
+                    _ => Ok(Some(line)),
+                }
+            }
+        }
+    }
+
+    fn add_history_entry(&mut self, line: &str) {
+        if let Input::Terminal(ref mut editor) = *self {
+            editor.add_history_entry(line);
+        }
+    }
+}
+
+/// Read commands from standard input, with line editing and a saved history if it is a
+/// terminal. Returns whether all of them succeeded.
+fn repl(target: &Target) -> bool {
+    let stdin = io::stdin();
+    let (mut input, mut history) = if stdin.is_terminal() {
+        let history = match history_path().map(History::load) {
+            Some(Ok(history)) => history,
+            Some(Err(err)) => {
+                eprintln!("cannot read history: {}", err);
+                History::new()
+            }
+            None => History::new(),
+        };
+        let mut editor = Editor::<()>::new();
+        for line in history.entries() {
+            editor.add_history_entry(line.as_str());
+        }
+        (Input::Terminal(editor), history)
+    } else {
+        (Input::Lines(stdin), History::new())
+    };
+    let mut ok = true;
+
+    loop {
+        let line = match input.read_line() {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(err) => {
+                eprintln!("{}", err);
+                return false;
+            }
+        };
+
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = match history.expand(line) {
+            Ok(expanded) => {
+                if expanded != line {
+                    println!("{}", expanded);
+                }
+                expanded
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                continue;
+            }
+        };
+
+        match line.as_str() {
+            "quit" | "exit" => break,
+            "history" => {
+                for (i, previous) in history.entries().iter().enumerate() {
+                    println!("{:4}  {}", i + 1, previous);
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        let args = line.split_whitespace().collect::<Vec<_>>();
+        if let Err(err) = cli::run(target, &args, &mut io::stdout()) {
+            eprintln!("error: {}", err);
+            ok = false;
+        }
+        input.add_history_entry(&line);
+        if let Err(err) = history.push(line) {
+            eprintln!("cannot save history: {}", err);
+        }
+    }
+    ok
+}
+
+fn main() {
+    let mut args = env::args().skip(1).collect::<Vec<_>>();
+    if args.first().map(String::as_str) == Some("--help") {
+        println!("{}\n\n{}", USAGE, HELP);
+        return;
+    }
+    let target = cli::open(&mut args).unwrap_or_else(|err| {
+        eprintln!("{}\n{}", err, USAGE);
+        process::exit(2);
+    });
+
+    let ok = if args.is_empty() {
+        repl(&target)
+    } else {
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+        cli::run(&target, &args, &mut io::stdout())
+            .map_err(|err| eprintln!("error: {}", err))
+            .is_ok()
+    };
+
+    // Changes made to a local file must not be lost on exit
+    if let Target::Local { ref buffer, .. } = target {
+        if let Err(err) = Buffer::sync(&**buffer) {
+            eprintln!("sync failed: {}", err);
+            process::exit(1);
+        }
+    }
+    if !ok {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,112 @@
+extern crate piyokvs;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::process;
+
+use piyokvs::dump::{self, Format};
+use piyokvs::storage::StorageImpl;
+
+const USAGE: &str = "usage: piyokvs-dump export [--format jsonl|csv] FILE [OUTPUT]
+       piyokvs-dump import [--format jsonl|csv] [--keys N] FILE [INPUT]
+
+Reads and writes the data file directly, so no server may have it open. OUTPUT and INPUT
+default to the standard output and input. Importing grows FILE to N keys if given.";
+
+struct Options {
+    import: bool,
+    format: Format,
+    n_keys: u32,
+    path: String,
+    other: Option<String>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut args = env::args().skip(1);
+    let import = match args.next().as_deref() {
+        Some("export") => false,
+        Some("import") => true,
+        _ => return Err("expected export or import".to_string()),
+    };
+
+    let mut format = Format::JsonLines;
+    let mut n_keys = 1;
+    let mut paths = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" | "--keys" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("missing value for {}", arg))?;
+                if arg == "--format" {
+                    format = value.parse()?;
+                } else {
+                    n_keys = value.parse().map_err(|_| "invalid --keys")?;
+                }
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => paths.push(arg),
+        }
+    }
+
+    if paths.is_empty() || paths.len() > 2 || n_keys == 0 {
+        return Err("expected a data file".to_string());
+    }
+    let other = if paths.len() == 2 { paths.pop() } else { None };
+    Ok(Options {
+        import,
+        format,
+        n_keys,
+        path: paths.pop().unwrap(),
+        other,
+    })
+}
+
+fn run(options: &Options) -> io::Result<u64> {
+    let mut storage = StorageImpl::open(&options.path, options.n_keys)?;
+
+    if options.import {
+        match options.other {
+            Some(ref path) => {
+                let mut input = BufReader::new(File::open(path)?);
+                dump::import(&mut storage, options.format, &mut input)
+            }
+            None => {
+                let stdin = io::stdin();
+                dump::import(&mut storage, options.format, &mut stdin.lock())
+            }
+        }
+    } else {
+        match options.other {
+            Some(ref path) => {
+                let mut out = BufWriter::new(File::create(path)?);
+                dump::export(&mut storage, options.format, &mut out)
+            }
+            None => {
+                let stdout = io::stdout();
+                dump::export(
+                    &mut storage,
+                    options.format,
+                    &mut BufWriter::new(stdout.lock()),
+                )
+            }
+        }
+    }
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|err| {
+        eprintln!("{}\n{}", err, USAGE);
+        process::exit(2);
+    });
+
+    match run(&options) {
+        Ok(n) if options.import => eprintln!("imported {} keys", n),
+        Ok(n) => eprintln!("exported {} keys", n),
+        Err(err) => {
+            eprintln!("{}: {}", options.path, err);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,82 @@
+extern crate piyokvs;
+
+use std::env;
+use std::process;
+
+use piyokvs::archive::{self, Until};
+use piyokvs::storage::StorageImpl;
+
+const USAGE: &str = "usage: piyokvs-restore list ARCHIVE...
+       piyokvs-restore replay [--seq N | --time MILLIS] FILE ARCHIVE...
+
+Archives are written by piyokvs-server --archive-dir. list prints every record as
+SEQ TIME KEY VALUE EXPIRES_AT TXN, VALUE being - for deletes. replay writes the records up to
+sequence number N, or up to time MILLIS since the epoch, to FILE, or all of them. FILE must be a
+copy of a backup taken after the first ARCHIVE was started, and no server may have it open.";
+
+fn list(paths: &[String]) -> Result<(), String> {
+    for path in paths {
+        let records = archive::read(path).map_err(|err| format!("{}: {}", path, err))?;
+        for record in records {
+            let record = record.map_err(|err| format!("{}: {}", path, err))?;
+            let value = record
+                .value
+                .map_or("-".to_string(), |value| value.to_string());
+            println!(
+                "{} {} {} {} {} {}",
+                record.seq, record.time, record.key, value, record.expires_at, record.txn
+            );
+        }
+    }
+    Ok(())
+}
+
+fn replay(args: &[String]) -> Result<(), String> {
+    let mut until = Until::Seq(u64::MAX);
+    let mut args = args;
+    while let Some(arg) = args.first().filter(|arg| arg.starts_with("--")) {
+        let value = args
+            .get(1)
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| format!("invalid value for {}", arg))?;
+        until = match arg.as_str() {
+            "--seq" => Until::Seq(value),
+            "--time" => Until::Time(value),
+            _ => return Err(format!("unknown option {}", arg)),
+        };
+        args = &args[2..];
+    }
+    if args.len() < 2 {
+        return Err(USAGE.to_string());
+    }
+
+    let (data, paths) = (&args[0], &args[1..]);
+    let mut storage =
+        StorageImpl::open(data, 1).map_err(|err| format!("cannot open {}: {}", data, err))?;
+    let restored = archive::restore(&mut storage, paths, until).map_err(|err| err.to_string())?;
+    match restored.last {
+        Some(last) => eprintln!(
+            "replayed {} records up to seq {} at {}, skipped {} of unfinished transactions",
+            restored.n_records, last.seq, last.time, restored.n_skipped
+        ),
+        None => eprintln!("nothing to replay"),
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let result = match args.split_first() {
+        Some((command, paths)) if command == "list" && !paths.is_empty() => list(paths),
+        Some((command, rest)) if command == "replay" => replay(rest),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
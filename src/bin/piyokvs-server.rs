@@ -0,0 +1,246 @@
+extern crate piyokvs;
+
+use std::cmp;
+use std::env;
+use std::fs;
+use std::io;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use piyokvs::archive::Archive;
+use piyokvs::buffer::{Buffer, BufferImpl};
+use piyokvs::cache::LruCache;
+use piyokvs::http;
+use piyokvs::memcached;
+#[cfg(feature = "metrics")]
+use piyokvs::metrics;
+use piyokvs::resp;
+use piyokvs::storage::StorageImpl;
+use piyokvs::ttl::{self, Sweeper};
+use piyokvs::wire;
+
+const USAGE: &str = "usage: piyokvs-server [--addr ADDR] [--memcached ADDR] [--http ADDR] \
+                     [--wire ADDR] [--metrics ADDR] [--data PATH] [--keys N] [--cache N] \
+                     [--backup-dir DIR [--backup-every SECS]] [--archive-dir DIR]
+
+Built with the metrics feature, serves Prometheus metrics at /metrics on a loopback address,
+127.0.0.1:9100 unless --metrics gives another.
+
+With --backup-dir, writes a full backup to DIR on start, then every SECS seconds (3600 by
+default) a delta of the pages changed since, to apply with piyokvs-backup. With --archive-dir,
+archives every change to DIR, starting a new file before each backup, to restore a backup to
+any later point with piyokvs-restore.";
+
+struct Options {
+    addr: String,
+    /// Also serve the memcached text protocol on this address
+    memcached_addr: Option<String>,
+    /// Also serve the JSON API on this address
+    http_addr: Option<String>,
+    /// Also serve the binary protocol on this address
+    wire_addr: Option<String>,
+    /// Serve metrics on this address, which must be a loopback address
+    #[cfg(feature = "metrics")]
+    metrics_addr: String,
+    data: String,
+    n_keys: u32,
+    cache_capacity: usize,
+    backup_dir: Option<String>,
+    backup_interval: Duration,
+    archive_dir: Option<PathBuf>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        addr: "127.0.0.1:6379".to_string(),
+        memcached_addr: None,
+        http_addr: None,
+        wire_addr: None,
+        #[cfg(feature = "metrics")]
+        metrics_addr: metrics::DEFAULT_ADDR.to_string(),
+        data: "piyokvs.db".to_string(),
+        n_keys: 1 << 16,
+        cache_capacity: 1 << 12,
+        backup_dir: None,
+        backup_interval: Duration::from_secs(3600),
+        archive_dir: None,
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+        match arg.as_str() {
+            "--addr" => options.addr = value,
+            "--memcached" => options.memcached_addr = Some(value),
+            "--http" => options.http_addr = Some(value),
+            "--wire" => options.wire_addr = Some(value),
+            #[cfg(feature = "metrics")]
+            "--metrics" => options.metrics_addr = value,
+            "--data" => options.data = value,
+            "--keys" => options.n_keys = value.parse().map_err(|_| "invalid --keys")?,
+            "--cache" => options.cache_capacity = value.parse().map_err(|_| "invalid --cache")?,
+            "--backup-dir" => options.backup_dir = Some(value),
+            "--backup-every" => {
+                let secs = value.parse().map_err(|_| "invalid --backup-every")?;
+                options.backup_interval = Duration::from_secs(secs);
+            }
+            "--archive-dir" => options.archive_dir = Some(PathBuf::from(value)),
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+
+    if options.n_keys == 0 || options.cache_capacity == 0 {
+        return Err("--keys and --cache must be positive".to_string());
+    }
+    Ok(options)
+}
+
+/// Continue the latest archive file in `dir`, or start the first one
+fn open_archive(dir: &Path) -> io::Result<Archive> {
+    fs::create_dir_all(dir)?;
+    let mut latest = None;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "archive") {
+            latest = cmp::max(latest, Some(path));
+        }
+    }
+    let path = latest.unwrap_or_else(|| new_archive_path(dir));
+    Archive::open(path, 1)
+}
+
+/// Named after the time it is started, so that archive files sort in order
+fn new_archive_path(dir: &Path) -> PathBuf {
+    dir.join(format!("{}.archive", ttl::now()))
+}
+
+/// Write a full backup, then deltas forever. A failed full backup is retried at the next
+/// interval, as deltas need one to start from. The archive, if any, moves to a new file just
+/// before each backup, so that the backup and the files from then on can be restored.
+fn back_up(buffer: &BufferImpl, dir: &Path, interval: Duration, archive_dir: Option<&Path>) {
+    let mut started = false;
+    loop {
+        if let Some(archive_dir) = archive_dir {
+            match Archive::open(new_archive_path(archive_dir), 1) {
+                Ok(archive) => {
+                    if let (_, Err(err)) = buffer.set_archive(archive) {
+                        eprintln!("previous archive file is incomplete: {}", err);
+                    }
+                }
+                Err(err) => eprintln!("cannot start a new archive file: {}", err),
+            }
+        }
+
+        let result = if started {
+            let path = dir.join(format!("{}.delta", ttl::now()));
+            buffer.backup_incremental(&path).map(|_| path)
+        } else {
+            let path = dir.join(format!("{}.full.db", ttl::now()));
+            buffer.backup(&path).map(|_| path)
+        };
+        match result {
+            Ok(path) => {
+                started = true;
+                eprintln!("backed up to {}", path.display());
+            }
+            Err(err) => eprintln!("backup failed: {}", err),
+        }
+        thread::sleep(interval);
+    }
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|err| {
+        eprintln!("{}\n{}", err, USAGE);
+        process::exit(2);
+    });
+
+    let storage = StorageImpl::open(&options.data, options.n_keys).unwrap_or_else(|err| {
+        eprintln!("cannot open {}: {}", options.data, err);
+        process::exit(1);
+    });
+    let cache = LruCache::new(options.cache_capacity);
+    let buffer_impl = Arc::new(BufferImpl::new(Box::new(cache), Box::new(storage)));
+    let buffer: Arc<dyn Buffer + Send + Sync> = buffer_impl.clone();
+
+    if let Some(ref dir) = options.archive_dir {
+        let archived = open_archive(dir).map(|archive| buffer_impl.set_archive(archive));
+        if let Err(err) = archived {
+            eprintln!("cannot archive to {}: {}", dir.display(), err);
+            process::exit(1);
+        }
+    }
+
+    let listener = TcpListener::bind(&options.addr).unwrap_or_else(|err| {
+        eprintln!("cannot listen on {}: {}", options.addr, err);
+        process::exit(1);
+    });
+
+    // Dirty entries reach the data file within a second
+    {
+        let buffer = buffer.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            if let Err(err) = buffer.sync() {
+                eprintln!("sync failed: {}", err);
+            }
+        });
+    }
+    let _sweeper = Sweeper::spawn(buffer.clone(), Duration::from_secs(10));
+
+    if let Some(ref dir) = options.backup_dir {
+        if let Err(err) = fs::create_dir_all(dir) {
+            eprintln!("cannot create {}: {}", dir, err);
+            process::exit(1);
+        }
+        let (dir, interval) = (dir.clone(), options.backup_interval);
+        let archive_dir = options.archive_dir.clone();
+        thread::spawn(move || {
+            back_up(
+                &buffer_impl,
+                Path::new(&dir),
+                interval,
+                archive_dir.as_deref(),
+            )
+        });
+    }
+
+    if let Some(ref addr) = options.memcached_addr {
+        let addr = memcached::spawn(addr, buffer.clone()).unwrap_or_else(|err| {
+            eprintln!("cannot listen on {}: {}", addr, err);
+            process::exit(1);
+        });
+        eprintln!("memcached listening on {}", addr);
+    }
+    if let Some(ref addr) = options.http_addr {
+        let addr = http::spawn(addr, buffer.clone()).unwrap_or_else(|err| {
+            eprintln!("cannot listen on {}: {}", addr, err);
+            process::exit(1);
+        });
+        eprintln!("http listening on {}", addr);
+    }
+    if let Some(ref addr) = options.wire_addr {
+        let addr = wire::spawn(addr, buffer.clone()).unwrap_or_else(|err| {
+            eprintln!("cannot listen on {}: {}", addr, err);
+            process::exit(1);
+        });
+        eprintln!("wire protocol listening on {}", addr);
+    }
+    #[cfg(feature = "metrics")]
+    {
+        let addr = metrics::spawn(&options.metrics_addr, buffer.clone()).unwrap_or_else(|err| {
+            eprintln!("cannot serve metrics on {}: {}", options.metrics_addr, err);
+            process::exit(1);
+        });
+        eprintln!("metrics listening on {}", addr);
+    }
+
+    eprintln!("listening on {}", options.addr);
+    resp::serve(&listener, &buffer);
+}
@@ -1,72 +1,1026 @@
-use std::io;
-use std::sync::{Mutex, MutexGuard};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use cache::Cache;
+use archive::Archive;
+use backup::Tracked;
+use cache::{Cache, WriteBack};
+use changes::{ChangeFeed, SlowConsumer, Subscription};
 use entry::{Entry, Lazy, State};
-use storage::Storage;
+use error::{Error, Result};
+use log::Log;
+use scan::{self, Scan};
+use snapshot::{Snapshot, Versions};
+use stats::{Counter, Stats};
+use storage::{Slot, Storage};
+use transaction::{KeyLocks, Transaction};
+use ttl;
+use watch::Watchers;
 
-impl Lazy for u64 {
+impl Lazy for Option<u64> {
     fn init(&mut self) {
-        *self = 0;
+        *self = None;
     }
 }
 
 pub trait Buffer {
-    fn lock(&self, key: u32) -> io::Result<MutexGuard<Entry<u32, u64>>>;
-    fn sync(&self) -> io::Result<()>;
+    fn lock(&self, key: u32) -> Result<Guard<'_>>;
+    fn sync(&self) -> Result<()>;
+
+    /// Change the cache capacity. Dirty entries evicted by shrinking are written back.
+    fn resize(&self, capacity: usize) -> Result<()>;
+
+    fn stats(&self) -> Stats;
+
+    /// Lock `keys` for a transaction. Other transactions and `lock` wait until it ends.
+    fn transaction(&self, keys: &[u32]) -> Transaction<'_>;
+
+    /// Take a consistent point-in-time view for reading without blocking writers
+    fn snapshot(&self) -> Snapshot<'_>;
+
+    /// Iterate over all present keys in ascending order without disturbing the cache
+    fn scan(&self) -> Scan<'_>;
+
+    /// Delete expired values, including those nobody has accessed since they expired. Returns
+    /// how many were deleted.
+    fn sweep(&self) -> Result<usize>;
+
+    /// Wait until the version of `key` is no longer `last_seen_version`, and return the value and
+    /// version as `get_versioned` does. Returns `None` if that does not happen within `timeout`.
+    /// Like `get_versioned`, a reload from storage may change the version as well.
+    fn watch(
+        &self,
+        key: u32,
+        last_seen_version: u64,
+        timeout: Duration,
+    ) -> Result<Option<(Option<u64>, u64)>>;
+
+    /// Read the values of `keys` in the same order. Keys missing from the cache are read from
+    /// storage in ranges of consecutive keys.
+    fn get_many(&self, keys: &[u32]) -> Result<Vec<Option<u64>>>;
+
+    /// Set several values, the last one winning for a repeated key. Unlike a transaction, each key
+    /// is updated on its own.
+    fn put_many(&self, writes: &[(u32, u64)]) -> Result<()>;
+
+    /// Value of `key`, or `None` if it was never set or has been deleted
+    fn get(&self, key: u32) -> Result<Option<u64>> {
+        Ok(*self.lock(key)?.get()?)
+    }
+
+    /// Set `value`, clearing any expiry
+    fn put(&self, key: u32, value: u64) -> Result<()> {
+        let mut entry = self.lock(key)?;
+        *entry.get_mut()? = Some(value);
+        entry.expires_at = 0;
+        Ok(())
+    }
+
+    /// Set `value`, which is deleted once `ttl` has passed
+    fn put_with_ttl(&self, key: u32, value: u64, ttl: Duration) -> Result<()> {
+        let mut entry = self.lock(key)?;
+        *entry.get_mut()? = Some(value);
+        entry.expires_at = ttl::deadline(ttl);
+        Ok(())
+    }
+
+    /// Delete the value of `key` once `ttl` has passed. Returns whether it has a value.
+    fn expire(&self, key: u32, ttl: Duration) -> Result<bool> {
+        let mut entry = self.lock(key)?;
+        if entry.get()?.is_none() {
+            return Ok(false);
+        }
+        // Marked dirty so that the expiry is written back
+        entry.get_mut()?;
+        entry.expires_at = ttl::deadline(ttl);
+        Ok(true)
+    }
+
+    /// Time left until the value of `key` expires, or `None` if it has no value or no expiry
+    fn ttl(&self, key: u32) -> Result<Option<Duration>> {
+        let entry = self.lock(key)?;
+        if entry.get()?.is_none() || entry.expires_at == 0 {
+            return Ok(None);
+        }
+        Ok(Some(ttl::remaining(entry.expires_at)))
+    }
+
+    /// Remove the value of `key`, leaving a tombstone until it is written back. Returns whether it
+    /// had a value.
+    fn delete(&self, key: u32) -> Result<bool> {
+        let mut entry = self.lock(key)?;
+        if entry.get()?.is_none() {
+            return Ok(false);
+        }
+        *entry.get_mut()? = None;
+        entry.expires_at = 0;
+        Ok(true)
+    }
+
+    /// Returns the value and its version. The version changes whenever the value does, and may
+    /// also change when the value is reloaded from storage.
+    fn get_versioned(&self, key: u32) -> Result<(Option<u64>, u64)> {
+        let entry = self.lock(key)?;
+        Ok((*entry.get()?, entry.version))
+    }
+
+    /// Set `value` only if the version is still `expected_version`. Returns the new version, or
+    /// `None` if the key has changed since.
+    fn compare_and_swap(&self, key: u32, expected_version: u64, value: u64) -> Result<Option<u64>> {
+        let mut entry = self.lock(key)?;
+        if entry.version != expected_version {
+            return Ok(None);
+        }
+        *entry.get_mut()? = Some(value);
+        Ok(Some(entry.release()))
+    }
+
+    /// Replace the value with `f` applied to it while the entry is locked. A key without a value
+    /// counts as 0. Returns the previous value.
+    fn fetch_update(&self, key: u32, f: &dyn Fn(u64) -> u64) -> Result<u64> {
+        let mut entry = self.lock(key)?;
+        let value = entry.get_mut()?;
+        let prev = value.unwrap_or(0);
+        *value = Some(f(prev));
+        Ok(prev)
+    }
+
+    /// Add to the value, wrapping around on overflow. Returns the previous value.
+    fn fetch_add(&self, key: u32, delta: u64) -> Result<u64> {
+        self.fetch_update(key, &|value| value.wrapping_add(delta))
+    }
+
+    /// Subtract from the value, wrapping around on overflow. Returns the previous value.
+    fn fetch_sub(&self, key: u32, delta: u64) -> Result<u64> {
+        self.fetch_update(key, &|value| value.wrapping_sub(delta))
+    }
+
+    fn saturating_fetch_add(&self, key: u32, delta: u64) -> Result<u64> {
+        self.fetch_update(key, &|value| value.saturating_add(delta))
+    }
+
+    fn saturating_fetch_sub(&self, key: u32, delta: u64) -> Result<u64> {
+        self.fetch_update(key, &|value| value.saturating_sub(delta))
+    }
+
+    fn fetch_max(&self, key: u32, value: u64) -> Result<u64> {
+        self.fetch_update(key, &|prev| prev.max(value))
+    }
+
+    fn fetch_min(&self, key: u32, value: u64) -> Result<u64> {
+        self.fetch_update(key, &|prev| prev.min(value))
+    }
+}
+
+/// Locked entry. Changes become visible to new snapshots when it is released.
+pub struct Guard<'a> {
+    buffer: &'a BufferImpl,
+    entry: MutexGuard<'a, Entry<u32, Option<u64>>>,
+    before: Option<u64>,
+    expires_before: u64,
+}
+
+impl<'a> Deref for Guard<'a> {
+    type Target = Entry<u32, Option<u64>>;
+
+    fn deref(&self) -> &Entry<u32, Option<u64>> {
+        &self.entry
+    }
+}
+
+impl<'a> DerefMut for Guard<'a> {
+    fn deref_mut(&mut self) -> &mut Entry<u32, Option<u64>> {
+        &mut self.entry
+    }
+}
+
+impl<'a> Guard<'a> {
+    /// Release the entry, returning the version of its value
+    pub fn release(mut self) -> u64 {
+        self.publish();
+        self.entry.version
+    }
+
+    fn publish(&mut self) {
+        if self.entry.value == self.before && self.entry.expires_at == self.expires_before {
+            return;
+        }
+        if let State::Fresh | State::Dirty = self.entry.state() {
+            if self.entry.value != self.before {
+                self.entry.version = self.buffer.versions.commit(self.entry.key, self.before);
+                self.buffer
+                    .changed(self.entry.key, self.before, self.entry.value);
+                self.before = self.entry.value;
+            }
+            // Only the archive keeps expiry times
+            let (key, value, expires_at) =
+                (self.entry.key, self.entry.value, self.entry.expires_at);
+            self.buffer.archive(key, value, expires_at);
+            self.expires_before = expires_at;
+        }
+    }
+}
+
+impl<'a> Drop for Guard<'a> {
+    fn drop(&mut self) {
+        // A panicked thread leaves the entry poisoned instead
+        if !thread::panicking() {
+            self.publish();
+        }
+    }
 }
 
 pub struct BufferImpl {
-    cache: Box<Cache<u32, u64> + Send + Sync>,
-    storage: Mutex<Box<Storage + Send>>,
+    cache: Box<dyn Cache<u32, Option<u64>> + Send + Sync>,
+    /// Only panics inside `Storage` can poison it, and every access seeks first
+    storage: Mutex<Tracked>,
+    counters: BufferCounters,
+    poison_recovery: AtomicBool,
+    key_locks: KeyLocks,
+    versions: Versions,
+    /// Held from appending a commit until it is applied, so that `sync` cannot truncate the log
+    /// in between
+    log: Option<Mutex<Log>>,
+    /// Every change, once set. Locked last.
+    archive: Mutex<Option<Archive>>,
+    /// Dirty values evicted during a batch, written back together when it ends. A key is never
+    /// both here and in the cache, so loads look here before storage.
+    pending: Mutex<BTreeMap<u32, Slot>>,
+    /// Bumped under the storage lock before every write, so that values read ahead by a batch can
+    /// be told apart from newer ones
+    write_epoch: AtomicU64,
+    changes: ChangeFeed,
+    watchers: Watchers,
+}
+
+/// Values of uncached keys read ahead by a batch
+struct Prefetched {
+    epoch: u64,
+    values: HashMap<u32, Slot>,
+}
+
+impl Prefetched {
+    /// The value read ahead, unless storage has been written since
+    fn get(&self, key: u32, epoch: u64) -> Option<Slot> {
+        if epoch == self.epoch {
+            self.values.get(&key).cloned()
+        } else {
+            None
+        }
+    }
+}
+
+/// What a transaction's write overwrote
+struct Applied {
+    key: u32,
+    value: Option<u64>,
+    expires_at: u64,
+    version: u64,
+}
+
+fn slot(entry: &Entry<u32, Option<u64>>) -> Slot {
+    Slot::new(entry.value, entry.expires_at)
+}
+
+/// Split sorted and deduplicated `keys` into runs of consecutive keys as `(first, len)`
+fn ranges(keys: &[u32]) -> Vec<(u32, usize)> {
+    let mut ranges: Vec<(u32, usize)> = Vec::new();
+    for &key in keys {
+        match ranges.last_mut() {
+            Some(&mut (first, ref mut len)) if first.checked_add(*len as u32) == Some(key) => {
+                *len += 1;
+            }
+            _ => ranges.push((key, 1)),
+        }
+    }
+    ranges
+}
+
+#[derive(Default)]
+struct BufferCounters {
+    write_backs: Counter,
+    storage_reads: Counter,
+    storage_writes: Counter,
+    expirations: Counter,
+    syncs: Counter,
+    sync_time: Counter,
+    lock_wait_time: Counter,
 }
 
 impl BufferImpl {
     pub fn new(
-        cache: Box<Cache<u32, u64> + Send + Sync>,
-        storage: Box<Storage + Send>,
+        cache: Box<dyn Cache<u32, Option<u64>> + Send + Sync>,
+        storage: Box<dyn Storage + Send>,
     ) -> BufferImpl {
         BufferImpl {
             cache,
-            storage: Mutex::new(storage),
+            storage: Mutex::new(Tracked::new(storage)),
+            counters: Default::default(),
+            poison_recovery: AtomicBool::new(false),
+            key_locks: Default::default(),
+            versions: Default::default(),
+            log: None,
+            archive: Default::default(),
+            pending: Default::default(),
+            write_epoch: AtomicU64::new(0),
+            changes: Default::default(),
+            watchers: Default::default(),
         }
     }
-}
 
-impl Buffer for BufferImpl {
-    fn lock(&self, key: u32) -> io::Result<MutexGuard<Entry<u32, u64>>> {
-        let mut entry = self.cache.lock(key);
+    /// Replay the commits in `log` left by a previous run into `storage`, then log every commit
+    /// so that a crash while applying it cannot leave it half done.
+    pub fn with_log(
+        cache: Box<dyn Cache<u32, Option<u64>> + Send + Sync>,
+        storage: Box<dyn Storage + Send>,
+        log: Log,
+    ) -> Result<BufferImpl> {
+        BufferImpl::replay(cache, storage, log, None)
+    }
+
+    /// Like `with_log`, recording every change in `archive` as `set_archive` does. The commits
+    /// replayed are archived again, since a crash may have lost their records: archived twice,
+    /// they only rewrite the same values.
+    pub fn with_log_and_archive(
+        cache: Box<dyn Cache<u32, Option<u64>> + Send + Sync>,
+        storage: Box<dyn Storage + Send>,
+        log: Log,
+        archive: Archive,
+    ) -> Result<BufferImpl> {
+        BufferImpl::replay(cache, storage, log, Some(archive))
+    }
 
-        match entry.state {
-            State::Unloaded => {
-                // Read
-                self.storage.lock().unwrap().read(key, entry.as_ptr())?;
+    fn replay(
+        cache: Box<dyn Cache<u32, Option<u64>> + Send + Sync>,
+        mut storage: Box<dyn Storage + Send>,
+        mut log: Log,
+        mut archive: Option<Archive>,
+    ) -> Result<BufferImpl> {
+        let replayed = log.replay(&mut *storage)?;
+        if let Some(ref mut archive) = archive {
+            for writes in &replayed {
+                let mut txn = None;
+                for (i, &(key, value)) in writes.iter().enumerate() {
+                    archive.append_txn(key, value, 0, &mut txn, i + 1 == writes.len());
+                }
             }
+            // The log must outlive the records it would restore
+            archive.flush()?;
+        }
+        log.truncate()?;
 
-            State::Stale(stale_key) => {
-                // Write back and read
-                let ptr = entry.as_ptr();
-                let mut storage = self.storage.lock().unwrap();
-                storage.write(stale_key, ptr)?;
-                storage.read(key, ptr)?;
+        let mut buffer = BufferImpl::new(cache, storage);
+        buffer.log = Some(Mutex::new(log));
+        buffer.archive = Mutex::new(archive);
+        Ok(buffer)
+    }
+
+    /// When enabled, entries poisoned by a panicked thread are reloaded from storage, discarding
+    /// their unsynced changes. Otherwise locking them fails with `Error::Poisoned`.
+    pub fn set_poison_recovery(&self, enabled: bool) {
+        self.poison_recovery.store(enabled, Ordering::Relaxed);
+    }
+
+    pub(crate) fn lock_storage(&self) -> MutexGuard<'_, Tracked> {
+        self.storage.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_log(&self) -> Option<MutexGuard<'_, Log>> {
+        self.log
+            .as_ref()
+            .map(|log| log.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn lock_archive(&self) -> MutexGuard<'_, Option<Archive>> {
+        self.archive.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_pending(&self) -> MutexGuard<'_, BTreeMap<u32, Slot>> {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn key_locks(&self) -> &KeyLocks {
+        &self.key_locks
+    }
+
+    pub(crate) fn versions(&self) -> &Versions {
+        &self.versions
+    }
+
+    /// Receive every change to a value from now on, in order. Up to `capacity` changes are
+    /// queued for the subscriber, and `policy` decides what happens beyond that.
+    pub fn subscribe(&self, capacity: usize, policy: SlowConsumer) -> Subscription {
+        self.changes.subscribe(capacity, policy)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn changes(&self) -> &ChangeFeed {
+        &self.changes
+    }
+
+    #[cfg(test)]
+    pub(crate) fn watchers(&self) -> &Watchers {
+        &self.watchers
+    }
+
+    /// Record every change in `archive` from now on, for point-in-time recovery, with sequence
+    /// numbers following those of the previous archive. Returns the previous archive, and the
+    /// error if its records could not all be written. The new archive is set either way, so that
+    /// a failed write does not stop archiving for good.
+    pub fn set_archive(&self, mut archive: Archive) -> (Option<Archive>, Result<()>) {
+        let mut current = self.lock_archive();
+        let flushed = match *current {
+            Some(ref mut previous) => {
+                archive.continue_from(previous);
+                previous.flush().map_err(Error::from)
+            }
+            None => Ok(()),
+        };
+        (current.replace(archive), flushed)
+    }
+
+    /// Sequence number of the next archive record, 0 without an archive
+    pub(crate) fn archive_seq(&self) -> u64 {
+        self.lock_archive().as_ref().map_or(0, Archive::next_seq)
+    }
+
+    /// Report a change of `key`. Must be called while holding its entry.
+    fn changed(&self, key: u32, old: Option<u64>, new: Option<u64>) {
+        self.changes.publish(key, old, new);
+        self.watchers.notify(key);
+    }
+
+    /// Archive a change of `key` made outside transactions. Must be called while holding its
+    /// entry, so that its changes are archived in order.
+    fn archive(&self, key: u32, value: Option<u64>, expires_at: u64) {
+        if let Some(ref mut archive) = *self.lock_archive() {
+            archive.append(key, value, expires_at);
+        }
+    }
+
+    /// Archive a change of `key` made by the transaction `txn`, as `Archive::append_txn` does
+    fn archive_txn(
+        &self,
+        key: u32,
+        value: Option<u64>,
+        expires_at: u64,
+        txn: &mut Option<u64>,
+        end: bool,
+    ) {
+        if let Some(ref mut archive) = *self.lock_archive() {
+            archive.append_txn(key, value, expires_at, txn, end);
+        }
+    }
+
+    pub(crate) fn n_keys(&self) -> u32 {
+        self.lock_storage().n_keys()
+    }
+
+    /// Values of `len` keys from `first`, bypassing the cache for those not in it. Expired values
+    /// read as `None`, and with `sweep` they are deleted too. Returns the values and how many
+    /// were deleted.
+    pub(crate) fn peek_range(
+        &self,
+        first: u32,
+        len: usize,
+        sweep: bool,
+    ) -> Result<(Vec<Option<u64>>, usize)> {
+        let keys = (first..).take(len).collect::<Vec<_>>();
+        let mut values = vec![None; len];
+        let mut n_expired = 0;
+        let now = ttl::now();
+
+        // Read before locking any entry, and only read again if storage was written meanwhile
+        let mut slots = vec![Slot::default(); len];
+        let epoch = self.read_slots(first, &mut slots, None)?;
+        let mut cached = vec![false; len];
+        self.cache.peek(&keys, &mut |mut entries| {
+            for entry in &mut entries {
+                if let State::Fresh | State::Dirty = entry.state() {
+                    let i = (entry.key - first) as usize;
+                    cached[i] = true;
+                    if !slot(entry).is_expired(now) {
+                        values[i] = entry.value;
+                    } else if sweep {
+                        self.expire_entry(entry);
+                        n_expired += 1;
+                    }
+                }
+            }
+            // Write-backs of keys evicted since may have changed storage
+            self.read_slots(first, &mut slots, Some(epoch))?;
+            Ok(())
+        })?;
+
+        let mut expired = Vec::new();
+        for (i, slot) in slots.iter().enumerate() {
+            if cached[i] {
+                continue;
+            }
+            if !slot.is_expired(now) {
+                values[i] = slot.value;
+            } else if sweep {
+                expired.push(first + i as u32);
+            }
+        }
+        if !expired.is_empty() {
+            n_expired += self.sweep_uncached(&expired, now)?;
+        }
+
+        Ok((values, n_expired))
+    }
+
+    /// Read `slots.len()` slots from `first` with the pending write-backs over them. Storage is
+    /// only read if written since the epoch `since`. Returns the epoch they were read at.
+    fn read_slots(&self, first: u32, slots: &mut [Slot], since: Option<u64>) -> Result<u64> {
+        // Pending values are newer than storage
+        let pending = self.lock_pending();
+        let mut storage = self.lock_storage();
+        let epoch = self.write_epoch.load(Ordering::SeqCst);
+        if since != Some(epoch) {
+            self.counters.storage_reads.incr();
+            storage.read_range(first, slots)?;
+        }
+        for (&key, slot) in pending.range(first..first + slots.len() as u32) {
+            slots[(key - first) as usize] = *slot;
+        }
+        Ok(epoch)
+    }
+
+    /// Delete the values of `keys` that are still expired, unless they have been cached since
+    /// they were read. Returns how many were deleted.
+    fn sweep_uncached(&self, keys: &[u32], now: u64) -> Result<usize> {
+        let mut pending = self.lock_pending();
+        let mut storage = self.lock_storage();
+        let mut n_expired = 0;
+        for &key in keys {
+            // A key loaded from here on reads the deleted value, but one cached already may have
+            // a newer one
+            if self.cache.contains(key) {
+                continue;
+            }
+            let mut slot = match pending.get(&key) {
+                Some(&slot) => slot,
+                None => {
+                    let mut slot = Slot::default();
+                    self.counters.storage_reads.incr();
+                    storage.read(key, NonNull::from(&mut slot))?;
+                    slot
+                }
+            };
+            if !slot.is_expired(now) {
+                continue;
             }
 
-            _ => {}
+            self.versions.commit(key, slot.value);
+            self.changed(key, slot.value, None);
+            self.archive(key, None, 0);
+            pending.remove(&key);
+            slot = Slot::default();
+            self.counters.expirations.incr();
+            self.write_epoch.fetch_add(1, Ordering::SeqCst);
+            storage.write(key, NonNull::from(&mut slot))?;
+            self.counters.storage_writes.incr();
+            n_expired += 1;
+        }
+        Ok(n_expired)
+    }
+
+    /// Lock an entry regardless of transactions
+    pub(crate) fn lock_entry(&self, key: u32) -> Result<MutexGuard<'_, Entry<u32, Option<u64>>>> {
+        self.lock_entry_with(key, None, &mut |entry| self.write_back(entry))
+    }
+
+    fn lock_entry_with(
+        &self,
+        key: u32,
+        prefetched: Option<&Prefetched>,
+        write_back: &mut WriteBack<u32, Option<u64>>,
+    ) -> Result<MutexGuard<'_, Entry<u32, Option<u64>>>> {
+        let start = Instant::now();
+        let mut entry = self.cache.lock(key, write_back)?;
+        self.counters.lock_wait_time.add_duration(start.elapsed());
+
+        if entry.state() == State::Poisoned {
+            if !self.poison_recovery.load(Ordering::Relaxed) {
+                return Err(Error::Poisoned);
+            }
+            entry.set_state(State::Unloaded);
+        }
+
+        if entry.state() == State::Unloaded {
+            self.load(&mut entry, prefetched)?;
+        }
+
+        if entry.expires_at != 0 && slot(&entry).is_expired(ttl::now()) {
+            self.expire_entry(&mut entry);
         }
 
         Ok(entry)
     }
 
-    fn sync(&self) -> io::Result<()> {
-        let dirty_entries = self.cache.dirty_entries();
-        let mut storage = self.storage.lock().unwrap();
-        for mut entry in dirty_entries {
-            storage.write(entry.key, entry.as_ptr())?;
+    fn load(
+        &self,
+        entry: &mut Entry<u32, Option<u64>>,
+        prefetched: Option<&Prefetched>,
+    ) -> Result<()> {
+        let pending = self.lock_pending().remove(&entry.key);
+        let epoch = self.write_epoch.load(Ordering::SeqCst);
+
+        let (slot, state) = if let Some(slot) = pending {
+            // Storage does not have it yet
+            (slot, State::Dirty)
+        } else if let Some(slot) = prefetched.and_then(|p| p.get(entry.key, epoch)) {
+            (slot, State::Fresh)
+        } else {
+            let mut slot = Slot::default();
+            self.counters.storage_reads.incr();
+            self.lock_storage()
+                .read(entry.key, NonNull::from(&mut slot))?;
+            (slot, State::Fresh)
+        };
+
+        entry.value = slot.value;
+        entry.expires_at = slot.expires_at;
+        entry.set_state(state);
+        entry.version = self.versions.now();
+        Ok(())
+    }
+
+    /// Delete the expired value of a locked entry
+    fn expire_entry(&self, entry: &mut Entry<u32, Option<u64>>) {
+        let before = entry.value;
+        entry.value = None;
+        entry.expires_at = 0;
+        entry.set_state(State::Dirty);
+        entry.version = self.versions.commit(entry.key, before);
+        self.changed(entry.key, before, None);
+        self.archive(entry.key, None, 0);
+        self.counters.expirations.incr();
+    }
+
+    /// Lock an entry, waiting while a transaction holds its key
+    fn lock_with(
+        &self,
+        key: u32,
+        prefetched: Option<&Prefetched>,
+        write_back: &mut WriteBack<u32, Option<u64>>,
+    ) -> Result<Guard<'_>> {
+        loop {
+            // Checked while holding the entry, so a transaction locking the key afterwards reads
+            // it only after this guard is released
+            let entry = self.lock_entry_with(key, prefetched, write_back)?;
+            if !self.key_locks.is_locked(key) {
+                let (before, expires_before) = (entry.value, entry.expires_at);
+                return Ok(Guard {
+                    buffer: self,
+                    entry,
+                    before,
+                    expires_before,
+                });
+            }
+            drop(entry);
+            self.key_locks.wait(key);
+        }
+    }
+
+    /// Lock `keys` one at a time in ascending order. Uncached keys are read ahead, and dirty
+    /// entries evicted meanwhile are written back together at the end.
+    fn batch<F>(&self, keys: &[u32], mut f: F) -> Result<()>
+    where
+        F: FnMut(&mut Guard<'_>) -> Result<()>,
+    {
+        let mut keys = keys.to_vec();
+        keys.sort();
+        keys.dedup();
+
+        let prefetched = self.prefetch(&keys)?;
+        let result = keys.iter().try_for_each(|&key| {
+            let mut entry = self.lock_with(key, Some(&prefetched), &mut |entry| {
+                self.defer_write_back(entry)
+            })?;
+            f(&mut entry)
+        });
+
+        let flushed = self.flush_pending();
+        result.and(flushed)
+    }
+
+    fn prefetch(&self, keys: &[u32]) -> Result<Prefetched> {
+        let misses = keys
+            .iter()
+            .cloned()
+            .filter(|&key| !self.cache.contains(key))
+            .collect::<Vec<_>>();
+
+        let mut values = HashMap::with_capacity(misses.len());
+        let mut storage = self.lock_storage();
+        let epoch = self.write_epoch.load(Ordering::SeqCst);
+        for (first, len) in ranges(&misses) {
+            let mut dst = vec![Slot::default(); len];
+            self.counters.storage_reads.incr();
+            storage.read_range(first, &mut dst)?;
+            for (i, slot) in dst.into_iter().enumerate() {
+                values.insert(first + i as u32, slot);
+            }
+        }
+
+        Ok(Prefetched { epoch, values })
+    }
+
+    pub(crate) fn commit(&self, writes: &[(u32, Option<u64>)]) -> Result<()> {
+        let mut log = self.lock_log();
+        if let Some(ref mut log) = log {
+            log.append(writes)?;
         }
-        storage.sync()
+
+        let ts = self.versions.begin();
+        let mut txn = None;
+        let mut applied = Vec::with_capacity(writes.len());
+        let result = self.apply(writes, ts, &mut txn, &mut applied);
+        if result.is_err() {
+            let undone = self.undo(&applied);
+            // The archived writes only count once the transaction ends, so end it with what
+            // they left behind
+            let left = applied.iter().zip(writes).map(|(before, &(key, value))| {
+                match undone.iter().find(|&&(undone_key, _)| undone_key == key) {
+                    Some(_) => (key, before.value, before.expires_at),
+                    None => (key, value, 0),
+                }
+            });
+            let left = left.collect::<Vec<_>>();
+            for (i, &(key, value, expires_at)) in left.iter().enumerate() {
+                self.archive_txn(key, value, expires_at, &mut txn, i + 1 == left.len());
+            }
+            // Already in the log, so it has to be undone there too
+            if let Some(ref mut log) = log {
+                log.append(&undone)?;
+            }
+        }
+        self.versions.end(ts);
+        result
+    }
+
+    /// Apply `writes`, adding what each overwrote to `applied` so that they can be undone if a
+    /// later one fails
+    fn apply(
+        &self,
+        writes: &[(u32, Option<u64>)],
+        ts: u64,
+        txn: &mut Option<u64>,
+        applied: &mut Vec<Applied>,
+    ) -> Result<()> {
+        for (i, &(key, value)) in writes.iter().enumerate() {
+            let mut entry = self.lock_entry(key)?;
+            let before = Applied {
+                key,
+                value: *entry.get()?,
+                expires_at: entry.expires_at,
+                version: entry.version,
+            };
+            // Like `put`, and like replaying the log, writes clear any expiry
+            *entry.get_mut()? = value;
+            entry.expires_at = 0;
+            if before.value != value {
+                self.versions.record(key, ts, before.value);
+                self.changed(key, before.value, value);
+                entry.version = ts;
+            }
+            // Archived even if unchanged, so that the end of the transaction is archived. Until
+            // then restoring skips the transaction.
+            self.archive_txn(key, value, 0, txn, i + 1 == writes.len());
+            applied.push(before);
+        }
+        Ok(())
+    }
+
+    /// Put back what `applied` writes overwrote, in reverse order. Returns the writes undone,
+    /// which leaves out any whose entry cannot be locked anymore.
+    fn undo(&self, applied: &[Applied]) -> Vec<(u32, Option<u64>)> {
+        let mut undone = Vec::with_capacity(applied.len());
+        for before in applied.iter().rev() {
+            let mut entry = match self.lock_entry(before.key) {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            let value = match entry.get_mut() {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            let after = *value;
+            *value = before.value;
+            entry.expires_at = before.expires_at;
+            entry.version = before.version;
+            self.changed(before.key, after, before.value);
+            undone.push((before.key, before.value));
+        }
+        undone
+    }
+
+    fn write_back(&self, entry: &mut Entry<u32, Option<u64>>) -> Result<()> {
+        entry.as_ptr()?;
+        let mut slot = slot(entry);
+        let mut storage = self.lock_storage();
+        self.write_epoch.fetch_add(1, Ordering::SeqCst);
+        if let Err(err) = storage.write(entry.key, NonNull::from(&mut slot)) {
+            // Still not in storage
+            entry.set_state(State::Dirty);
+            return Err(err.into());
+        }
+        self.counters.storage_writes.incr();
+        self.counters.write_backs.incr();
+        Ok(())
+    }
+
+    fn defer_write_back(&self, entry: &mut Entry<u32, Option<u64>>) -> Result<()> {
+        entry.as_ptr()?;
+        self.lock_pending().insert(entry.key, slot(entry));
+        self.counters.write_backs.incr();
+        Ok(())
+    }
+
+    fn flush_pending(&self) -> Result<()> {
+        let mut pending = self.lock_pending();
+        if !pending.is_empty() {
+            self.write_ranges(&mut *self.lock_storage(), &pending)?;
+            pending.clear();
+        }
+        Ok(())
+    }
+
+    /// Write `slots` with one storage write per run of consecutive keys
+    fn write_ranges(
+        &self,
+        storage: &mut (dyn Storage + Send),
+        slots: &BTreeMap<u32, Slot>,
+    ) -> Result<()> {
+        let keys = slots.keys().cloned().collect::<Vec<_>>();
+        for (first, len) in ranges(&keys) {
+            let src = slots
+                .range(first..)
+                .take(len)
+                .map(|(_, &slot)| slot)
+                .collect::<Vec<_>>();
+            self.write_epoch.fetch_add(1, Ordering::SeqCst);
+            storage.write_range(first, &src)?;
+            self.counters.storage_writes.incr();
+        }
+        Ok(())
+    }
+
+    /// Sync, then run `f` on storage before anything else can write to it. Commits wait too when
+    /// there is a log.
+    pub(crate) fn sync_and(&self, f: &mut dyn FnMut(&mut Tracked) -> Result<()>) -> Result<()> {
+        let start = Instant::now();
+
+        let mut log = self.lock_log();
+        let mut dirty_entries = self.cache.dirty_entries();
+        let mut pending = self.lock_pending();
+
+        // Dirty entries stay locked until written, since they must not end up in `pending`
+        let mut slots = pending.clone();
+        for entry in &mut dirty_entries {
+            entry.as_ptr()?;
+            slots.insert(entry.key, slot(entry));
+        }
+
+        let mut storage = self.lock_storage();
+        if let Err(err) = self.write_ranges(&mut *storage, &slots) {
+            // Some may not have been written
+            for entry in &mut dirty_entries {
+                entry.set_state(State::Dirty);
+            }
+            return Err(err);
+        }
+        pending.clear();
+        storage.sync()?;
+        // A failed archive is reported once storage is synced, and until it is replaced, but it
+        // must not keep the log growing
+        let archived = match *self.lock_archive() {
+            Some(ref mut archive) => archive.flush(),
+            None => Ok(()),
+        };
+        if let Some(ref mut log) = log {
+            log.truncate()?;
+        }
+        f(&mut storage)?;
+
+        self.counters.syncs.incr();
+        self.counters.sync_time.add_duration(start.elapsed());
+        archived.map_err(Error::from)
+    }
+}
+
+impl Buffer for BufferImpl {
+    fn lock(&self, key: u32) -> Result<Guard<'_>> {
+        self.lock_with(key, None, &mut |entry| self.write_back(entry))
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.sync_and(&mut |_| Ok(()))
+    }
+
+    fn resize(&self, capacity: usize) -> Result<()> {
+        self.cache
+            .resize(capacity, &mut |entry| self.write_back(entry))
+    }
+
+    fn stats(&self) -> Stats {
+        let cache = self.cache.stats();
+        let counters = &self.counters;
+        Stats {
+            hits: cache.hits,
+            misses: cache.misses,
+            evictions: cache.evictions,
+            write_backs: counters.write_backs.get(),
+            storage_reads: counters.storage_reads.get(),
+            storage_writes: counters.storage_writes.get(),
+            expirations: counters.expirations.get(),
+            syncs: counters.syncs.get(),
+            sync_time: counters.sync_time.get_duration(),
+            lock_wait_time: counters.lock_wait_time.get_duration(),
+            dirty_entries: cache.dirty_entries,
+        }
+    }
+
+    fn transaction(&self, keys: &[u32]) -> Transaction<'_> {
+        Transaction::new(self, keys)
+    }
+
+    fn snapshot(&self) -> Snapshot<'_> {
+        Snapshot::new(self)
+    }
+
+    fn scan(&self) -> Scan<'_> {
+        Scan::new(self)
+    }
+
+    fn watch(
+        &self,
+        key: u32,
+        last_seen_version: u64,
+        timeout: Duration,
+    ) -> Result<Option<(Option<u64>, u64)>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let entry = self.lock(key)?;
+            if entry.version != last_seen_version {
+                return Ok(Some((*entry.get()?, entry.version)));
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            self.watchers.wait(key, entry, deadline - now);
+        }
+    }
+
+    fn sweep(&self) -> Result<usize> {
+        let end = self.n_keys();
+        let mut n_expired = 0;
+        let mut first = 0;
+        while first < end {
+            let len = cmp::min(scan::CHUNK_LEN, end - first);
+            n_expired += self.peek_range(first, len as usize, true)?.1;
+            first += len;
+        }
+        Ok(n_expired)
+    }
+
+    fn get_many(&self, keys: &[u32]) -> Result<Vec<Option<u64>>> {
+        let mut values = HashMap::with_capacity(keys.len());
+        self.batch(keys, |entry| {
+            values.insert(entry.key, *entry.get()?);
+            Ok(())
+        })?;
+        Ok(keys.iter().map(|key| values[key]).collect())
+    }
+
+    fn put_many(&self, writes: &[(u32, u64)]) -> Result<()> {
+        let values = writes.iter().cloned().collect::<HashMap<_, _>>();
+        let keys = values.keys().cloned().collect::<Vec<_>>();
+        self.batch(&keys, |entry| {
+            *entry.get_mut()? = Some(values[&entry.key]);
+            entry.expires_at = 0;
+            Ok(())
+        })
     }
 }
 
+/// Buffer of 10 cached entries over `StorageMock`, for the tests of any module
+#[cfg(test)]
+pub(crate) fn test_buffer() -> BufferImpl {
+    let cache = Box::new(::cache::LruCache::new(10));
+    let storage = Box::new(::storage::StorageMock::new());
+    BufferImpl::new(cache, storage)
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
@@ -79,7 +1033,7 @@ mod tests {
 
     fn assert_data(n_data: u32, buffer: &impl Buffer) {
         for key in 0..n_data {
-            assert_eq!(buffer.lock(key).unwrap().value, key as u64);
+            assert_eq!(buffer.lock(key).unwrap().value, Some(key as u64));
         }
     }
 
@@ -93,7 +1047,7 @@ mod tests {
 
         for key in 0..n_data {
             let mut entry = buffer.lock(key).unwrap();
-            *entry.as_mut() = key as u64;
+            *entry.get_mut().unwrap() = Some(key as u64);
         }
 
         assert_data(n_data, &buffer);
@@ -119,7 +1073,7 @@ mod tests {
             let t = thread::spawn(move || {
                 for key in (start..).take(count) {
                     let mut entry = buffer.lock(key).unwrap();
-                    *entry.as_mut() = key as u64;
+                    *entry.get_mut().unwrap() = Some(key as u64);
                 }
             });
 
@@ -145,7 +1099,7 @@ mod tests {
 
         for key in 0..n_data {
             let mut entry = buffer.lock(key).unwrap();
-            *entry.as_mut() = key as u64;
+            *entry.get_mut().unwrap() = Some(key as u64);
         }
 
         assert_data(n_data, &buffer);
@@ -171,7 +1125,7 @@ mod tests {
             let t = thread::spawn(move || {
                 for key in (start..).take(count) {
                     let mut entry = buffer.lock(key).unwrap();
-                    *entry.as_mut() = key as u64;
+                    *entry.get_mut().unwrap() = Some(key as u64);
                 }
             });
 
@@ -186,4 +1140,274 @@ mod tests {
 
         assert_data(n_data, &*buffer);
     }
+
+    #[test]
+    fn resize_lru_buffer() {
+        let n_data: u32 = 1000;
+
+        let cache = Box::new(LruCache::new(100));
+        let storage = Box::new(StorageMock::new());
+        let buffer = BufferImpl::new(cache, storage);
+
+        for key in 0..n_data {
+            let mut entry = buffer.lock(key).unwrap();
+            *entry.get_mut().unwrap() = Some(key as u64);
+        }
+
+        buffer.resize(10).unwrap();
+        assert_data(n_data, &buffer);
+
+        buffer.resize(200).unwrap();
+        assert_data(n_data, &buffer);
+    }
+
+    #[test]
+    fn single_buffer_stats() {
+        let cache = Box::new(SingleCache::new());
+        let storage = Box::new(StorageMock::new());
+        let buffer = BufferImpl::new(cache, storage);
+
+        *buffer.lock(1).unwrap().get_mut().unwrap() = Some(1);
+        *buffer.lock(1).unwrap().get_mut().unwrap() = Some(2);
+        *buffer.lock(2).unwrap().get_mut().unwrap() = Some(3);
+
+        let stats = buffer.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 2);
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.write_backs, 1);
+        assert_eq!(stats.storage_reads, 2);
+        assert_eq!(stats.storage_writes, 1);
+        assert_eq!(stats.dirty_entries, 1);
+
+        buffer.sync().unwrap();
+
+        let stats = buffer.stats();
+        assert_eq!(stats.storage_writes, 2);
+        assert_eq!(stats.syncs, 1);
+        assert_eq!(stats.dirty_entries, 0);
+    }
+
+    #[test]
+    fn poisoned_lru_buffer() {
+        let cache = Box::new(LruCache::new(10));
+        let storage = Box::new(StorageMock::new());
+        let buffer = Arc::new(BufferImpl::new(cache, storage));
+
+        *buffer.lock(0).unwrap().get_mut().unwrap() = Some(1);
+        buffer.sync().unwrap();
+
+        let t = {
+            let buffer = buffer.clone();
+            thread::spawn(move || {
+                let mut entry = buffer.lock(0).unwrap();
+                *entry.get_mut().unwrap() = Some(2);
+                panic!("client panicked");
+            })
+        };
+        assert!(t.join().is_err());
+
+        match buffer.lock(0) {
+            Err(Error::Poisoned) => {}
+            _ => panic!("poisoned entry must be rejected"),
+        }
+        *buffer.lock(1).unwrap().get_mut().unwrap() = Some(3);
+        buffer.sync().unwrap();
+
+        buffer.set_poison_recovery(true);
+        assert_eq!(buffer.get(0).unwrap(), Some(1));
+        assert_eq!(buffer.get(1).unwrap(), Some(3));
+    }
+
+    #[test]
+    fn compare_and_swap() {
+        let cache = Box::new(LruCache::new(10));
+        let storage = Box::new(StorageMock::new());
+        let buffer = BufferImpl::new(cache, storage);
+
+        let (value, version) = buffer.get_versioned(0).unwrap();
+        assert_eq!(value, None);
+
+        let new_version = buffer.compare_and_swap(0, version, 1).unwrap().unwrap();
+        assert_ne!(new_version, version);
+        assert_eq!(buffer.compare_and_swap(0, version, 2).unwrap(), None);
+        assert_eq!(buffer.get_versioned(0).unwrap(), (Some(1), new_version));
+
+        // Reloading after eviction never reuses an older version
+        for key in 1..100 {
+            buffer.lock(key).unwrap();
+        }
+        let (value, version) = buffer.get_versioned(0).unwrap();
+        assert_eq!(value, Some(1));
+        assert!(version >= new_version);
+        assert!(buffer.compare_and_swap(0, version, 2).unwrap().is_some());
+    }
+
+    #[test]
+    fn fetch_arithmetic() {
+        let cache = Box::new(SingleCache::new());
+        let storage = Box::new(StorageMock::new());
+        let buffer = BufferImpl::new(cache, storage);
+
+        assert_eq!(buffer.fetch_add(0, 5).unwrap(), 0);
+        assert_eq!(buffer.fetch_sub(0, 2).unwrap(), 5);
+        assert_eq!(buffer.fetch_max(0, 10).unwrap(), 3);
+        assert_eq!(buffer.fetch_min(0, 7).unwrap(), 10);
+        assert_eq!(buffer.saturating_fetch_sub(0, 8).unwrap(), 7);
+        assert_eq!(buffer.fetch_sub(0, 1).unwrap(), 0);
+        assert_eq!(buffer.saturating_fetch_add(0, 1).unwrap(), u64::MAX);
+        assert_eq!(buffer.fetch_add(0, 1).unwrap(), u64::MAX);
+        assert_eq!(buffer.get(0).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn key_ranges() {
+        assert_eq!(ranges(&[]), vec![]);
+        assert_eq!(
+            ranges(&[1, 2, 3, 5, 7, 8, u32::MAX]),
+            vec![(1, 3), (5, 1), (7, 2), (u32::MAX, 1)]
+        );
+    }
+
+    #[test]
+    fn batch_get_and_put() {
+        let cache = Box::new(LruCache::new(10));
+        let storage = Box::new(StorageMock::new());
+        let buffer = BufferImpl::new(cache, storage);
+
+        let writes = (0..100)
+            .map(|key| (key, key as u64 * 2))
+            .collect::<Vec<_>>();
+        buffer.put_many(&writes).unwrap();
+
+        let stats = buffer.stats();
+        assert_eq!(stats.storage_reads, 1);
+        assert_eq!(stats.write_backs, 90);
+        assert_eq!(stats.storage_writes, 1);
+
+        let keys = (0..100).rev().chain(vec![3, 3]).collect::<Vec<_>>();
+        let values = buffer.get_many(&keys).unwrap();
+        let expected = keys
+            .iter()
+            .map(|&key| Some(key as u64 * 2))
+            .collect::<Vec<_>>();
+        assert_eq!(values, expected);
+
+        // The dirty entries evicted first are reloaded from the pending write-backs
+        let stats = buffer.stats();
+        assert_eq!(stats.storage_reads, 2);
+        assert_eq!(stats.write_backs, 100);
+        assert_eq!(stats.storage_writes, 1);
+
+        buffer.sync().unwrap();
+        assert_eq!(buffer.stats().storage_writes, 2);
+        for key in 0..100 {
+            assert_eq!(buffer.get(key).unwrap(), Some(key as u64 * 2));
+        }
+    }
+
+    #[test]
+    fn threaded_batches() {
+        let n_data: u32 = 1000;
+        let n_writers: u32 = 10;
+        let n_data_per_writer = n_data / n_writers;
+
+        let cache = Box::new(LruCache::new(50));
+        let storage = Box::new(StorageMock::new());
+        let buffer = Arc::new(BufferImpl::new(cache, storage));
+
+        let mut writers = Vec::with_capacity(n_writers as usize);
+
+        for i in 0..n_writers {
+            let buffer = buffer.clone();
+            let start = i * n_data_per_writer;
+
+            let t = thread::spawn(move || {
+                let keys = (start..start + n_data_per_writer).collect::<Vec<_>>();
+                for _ in 0..10 {
+                    let writes = buffer
+                        .get_many(&keys)
+                        .unwrap()
+                        .into_iter()
+                        .zip(&keys)
+                        .map(|(value, &key)| (key, value.unwrap_or(0) + 1))
+                        .collect::<Vec<_>>();
+                    buffer.put_many(&writes).unwrap();
+                }
+            });
+
+            writers.push(t);
+        }
+
+        for t in writers {
+            t.join().unwrap();
+        }
+
+        let keys = (0..n_data).collect::<Vec<_>>();
+        assert!(buffer
+            .get_many(&keys)
+            .unwrap()
+            .iter()
+            .all(|&value| value == Some(10)));
+    }
+
+    #[test]
+    fn delete_with_tombstones() {
+        let cache = Box::new(LruCache::new(10));
+        let storage = Box::new(StorageMock::new());
+        let buffer = BufferImpl::new(cache, storage);
+
+        assert_eq!(buffer.get(0).unwrap(), None);
+        assert!(!buffer.delete(0).unwrap());
+
+        for key in 0..20 {
+            buffer.put(key, key as u64).unwrap();
+        }
+        buffer.sync().unwrap();
+
+        // Deleted both while cached and after being evicted
+        assert!(buffer.delete(19).unwrap());
+        assert!(buffer.delete(0).unwrap());
+        assert_eq!(buffer.get(19).unwrap(), None);
+
+        for key in 20..40 {
+            buffer.lock(key).unwrap();
+        }
+        buffer.sync().unwrap();
+
+        let values = buffer.get_many(&[0, 1, 18, 19]).unwrap();
+        assert_eq!(values, vec![None, Some(1), Some(18), None]);
+        assert_eq!(buffer.fetch_add(19, 1).unwrap(), 0);
+        assert_eq!(buffer.get(19).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn sweep_rechecks_keys() {
+        let cache = Box::new(LruCache::new(10));
+        let storage = Box::new(StorageMock::new());
+        let buffer = BufferImpl::new(cache, storage);
+
+        for key in 0..2 {
+            buffer
+                .put_with_ttl(key, 1, Duration::from_millis(1))
+                .unwrap();
+        }
+        for key in 10..20 {
+            buffer.lock(key).unwrap();
+        }
+        buffer.sync().unwrap();
+        thread::sleep(Duration::from_millis(5));
+
+        // Both expired when read, then 0 was cached and 1 written back with a new value
+        buffer.put(1, 3).unwrap();
+        buffer.put(0, 2).unwrap();
+        for key in 10..19 {
+            buffer.lock(key).unwrap();
+        }
+        assert!(!buffer.cache.contains(1));
+        let expirations = buffer.stats().expirations;
+        assert_eq!(buffer.sweep_uncached(&[0, 1], ttl::now()).unwrap(), 0);
+        assert_eq!(buffer.stats().expirations, expirations);
+        assert_eq!(buffer.get_many(&[0, 1]).unwrap(), vec![Some(2), Some(3)]);
+    }
 }
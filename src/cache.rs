@@ -1,47 +1,98 @@
 use std::collections::HashMap;
 use std::hash::Hash;
-use std::sync::{Mutex, MutexGuard};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError};
 
 use entry::{Entry, Lazy, State};
+use error::{Error, Result};
+use stats::{CacheCounters, CacheStats, Counter};
+
+/// Writes a dirty entry back under its own key
+pub type WriteBack<'a, K, V> = dyn FnMut(&mut Entry<K, V>) -> Result<()> + 'a;
+
+/// Reads entries passed by `Cache::peek`
+pub type Peek<'a, K, V> = dyn FnMut(Vec<MutexGuard<'_, Entry<K, V>>>) -> Result<()> + 'a;
 
 pub trait Cache<K, V> {
-    fn lock(&self, key: K) -> MutexGuard<Entry<K, V>>;
-    fn dirty_entries(&self) -> Vec<MutexGuard<Entry<K, V>>>;
+    /// Dirty entries evicted to make room for `key` are passed to `write_back` before another
+    /// thread can look their keys up again.
+    fn lock(&self, key: K, write_back: &mut WriteBack<K, V>)
+        -> Result<MutexGuard<'_, Entry<K, V>>>;
+    fn dirty_entries(&self) -> Vec<MutexGuard<'_, Entry<K, V>>>;
+
+    /// Whether `key` is cached, without counting a hit or making it recently used
+    fn contains(&self, key: K) -> bool;
+
+    /// Pass `peek` the locked entries of cached `keys`, without counting hits or making them
+    /// recently used. No entry is loaded or evicted until it returns.
+    fn peek(&self, keys: &[K], peek: &mut Peek<K, V>) -> Result<()>;
+
+    /// Change the number of entries. Dirty entries evicted by shrinking are passed to `write_back`
+    /// before they are dropped.
+    fn resize(&self, capacity: usize, write_back: &mut WriteBack<K, V>) -> Result<()>;
+
+    fn stats(&self) -> CacheStats;
 }
 
-fn prepare_entry<K, V>(entry: &mut MutexGuard<Entry<K, V>>, key: K)
+/// Lock an entry, marking it as poisoned if a thread panicked while holding it
+fn lock_entry<K, V>(mutex: &Mutex<Entry<K, V>>) -> MutexGuard<'_, Entry<K, V>> {
+    mutex.lock().unwrap_or_else(|err| {
+        mutex.clear_poison();
+        let mut entry = err.into_inner();
+        entry.set_state(State::Poisoned);
+        entry
+    })
+}
+
+fn prepare_entry<K, V>(
+    entry: &mut MutexGuard<Entry<K, V>>,
+    key: K,
+    write_back: &mut WriteBack<K, V>,
+    counters: &CacheCounters,
+) -> Result<()>
 where
     K: Copy + PartialEq,
     V: Lazy,
 {
-    match entry.state {
+    match entry.state() {
         State::Uninitialized => {
             entry.init();
             entry.key = key;
+            counters.misses.incr();
+            return Ok(());
         }
 
-        State::Unloaded => panic!("initialized entry must be loaded"),
-
-        State::Fresh => {
-            if entry.key != key {
-                entry.state = State::Unloaded;
-                entry.key = key;
-            }
+        // Loading failed, so there is nothing to evict
+        State::Unloaded => {
+            entry.key = key;
+            counters.misses.incr();
+            return Ok(());
         }
 
-        State::Dirty => {
-            if entry.key != key {
-                entry.state = State::Stale(entry.key);
-                entry.key = key;
-            }
+        // Leave it to the buffer to decide whether to reload
+        State::Poisoned if entry.key == key => return Ok(()),
+
+        _ if entry.key == key => {
+            counters.hits.incr();
+            return Ok(());
         }
 
-        State::Stale(_) => panic!("stale entry must be evicted"),
+        // The half-applied mutation of a poisoned entry is discarded
+        State::Fresh | State::Poisoned => {}
+
+        State::Dirty => write_back(entry)?,
     }
+
+    entry.set_state(State::Unloaded);
+    entry.key = key;
+    counters.misses.incr();
+    counters.evictions.incr();
+
+    Ok(())
 }
 
 pub struct SingleCache<K, V> {
     entry: Mutex<Entry<K, V>>,
+    counters: CacheCounters,
 }
 
 impl<K, V> SingleCache<K, V>
@@ -50,36 +101,184 @@ where
     V: Default,
 {
     pub fn new() -> SingleCache<K, V> {
+        let counters = CacheCounters::default();
         SingleCache {
-            entry: Mutex::new(Default::default()),
+            entry: Mutex::new(Entry::counted(&counters.dirty_entries)),
+            counters,
         }
     }
 }
 
+impl<K, V> Default for SingleCache<K, V>
+where
+    K: Default,
+    V: Default,
+{
+    fn default() -> SingleCache<K, V> {
+        SingleCache::new()
+    }
+}
+
 impl<K, V> Cache<K, V> for SingleCache<K, V>
 where
     K: Copy + PartialEq,
     V: Lazy,
 {
-    fn lock(&self, key: K) -> MutexGuard<Entry<K, V>> {
-        let mut entry = self.entry.lock().unwrap();
-        prepare_entry(&mut entry, key);
-        entry
+    fn lock(
+        &self,
+        key: K,
+        write_back: &mut WriteBack<K, V>,
+    ) -> Result<MutexGuard<'_, Entry<K, V>>> {
+        let mut entry = lock_entry(&self.entry);
+        prepare_entry(&mut entry, key, write_back, &self.counters)?;
+        Ok(entry)
     }
 
-    fn dirty_entries(&self) -> Vec<MutexGuard<Entry<K, V>>> {
-        let entry = self.entry.lock().unwrap();
-        if entry.state == State::Dirty {
+    fn dirty_entries(&self) -> Vec<MutexGuard<'_, Entry<K, V>>> {
+        let entry = lock_entry(&self.entry);
+        if entry.state() == State::Dirty {
             vec![entry]
         } else {
             Vec::new()
         }
     }
+
+    fn contains(&self, key: K) -> bool {
+        let entry = lock_entry(&self.entry);
+        entry.key == key && (entry.state() == State::Fresh || entry.state() == State::Dirty)
+    }
+
+    fn peek(&self, keys: &[K], peek: &mut Peek<K, V>) -> Result<()> {
+        let entry = lock_entry(&self.entry);
+        if entry.state() != State::Uninitialized && keys.contains(&entry.key) {
+            peek(vec![entry])
+        } else {
+            // Still held, so that nothing is loaded meanwhile
+            peek(Vec::new())
+        }
+    }
+
+    /// `SingleCache` always holds exactly one entry.
+    fn resize(&self, _capacity: usize, _write_back: &mut WriteBack<K, V>) -> Result<()> {
+        Ok(())
+    }
+
+    fn stats(&self) -> CacheStats {
+        self.counters.stats()
+    }
 }
 
 pub struct LruCache<K, V> {
-    entries: Vec<Mutex<Entry<K, V>>>,
-    lane: Mutex<LruLane<K>>,
+    /// Only held to update the lane, never while waiting for an entry or running `write_back`,
+    /// `peek` or other code from outside this module. Only a bug here can poison it, so it is
+    /// recovered rather than passed on to every later caller.
+    inner: Mutex<LruInner<K>>,
+    /// Signalled whenever keys leave `LruInner::evicting`
+    evicted: Condvar,
+    frames: Frames<K, V>,
+    counters: CacheCounters,
+}
+
+struct LruInner<K> {
+    lane: LruLane<K>,
+    /// Keys taken out of the lane whose frame may still hold their dirty value, with that frame.
+    /// They are not looked up again until whoever locks the frame next has written it back.
+    evicting: HashMap<K, usize>,
+}
+
+impl<K> LruInner<K>
+where
+    K: Copy + Eq + Hash,
+{
+    /// Forget the keys evicted from frame `i`, which holds none of them anymore. `locked`, the
+    /// key the frame was just prepared for, is kept: another thread may have evicted it from the
+    /// frame since, and it must be waited for until that thread has written it back.
+    fn evicted(&mut self, i: usize, locked: Option<K>) -> bool {
+        let len = self.evicting.len();
+        self.evicting
+            .retain(|&key, &mut frame| frame != i || Some(key) == locked);
+        self.evicting.len() != len
+    }
+}
+
+const FIRST_SEGMENT_LEN: usize = 64;
+const N_SEGMENTS: usize = 32;
+
+type Segment<K, V> = OnceLock<Box<[Mutex<Entry<K, V>>]>>;
+
+/// Entries that never move, so that guards outlive any lock on the lane. Frames are allocated
+/// in segments of doubling length on first use and freed with the cache.
+struct Frames<K, V> {
+    segments: Vec<Segment<K, V>>,
+    dirty_entries: Arc<Counter>,
+}
+
+impl<K, V> Frames<K, V>
+where
+    K: Default,
+    V: Default,
+{
+    fn new(dirty_entries: &Arc<Counter>) -> Frames<K, V> {
+        Frames {
+            segments: (0..N_SEGMENTS).map(|_| OnceLock::new()).collect(),
+            dirty_entries: Arc::clone(dirty_entries),
+        }
+    }
+
+    fn get(&self, i: usize) -> &Mutex<Entry<K, V>> {
+        // Segment `s` holds `FIRST_SEGMENT_LEN << s` frames from `FIRST_SEGMENT_LEN * (2^s - 1)`
+        let n = i / FIRST_SEGMENT_LEN + 1;
+        let s = (usize::BITS - 1 - n.leading_zeros()) as usize;
+        let segment = self.segments[s].get_or_init(|| {
+            (0..FIRST_SEGMENT_LEN << s)
+                .map(|_| Mutex::new(Entry::counted(&self.dirty_entries)))
+                .collect()
+        });
+        &segment[i - FIRST_SEGMENT_LEN * ((1 << s) - 1)]
+    }
+}
+
+/// Puts an evicted key back in the lane unless disarmed, so that a write-back that fails or
+/// panics leaves its dirty value cached under its own key
+struct Eviction<'a, K: 'a + Copy + Eq + Hash> {
+    inner: &'a Mutex<LruInner<K>>,
+    evicted: &'a Condvar,
+    /// Key the frame was handed to
+    key: K,
+    /// Key whose value the frame holds
+    resident: K,
+    i: usize,
+    /// Whether the frame was evicted by shrinking rather than handed to `key`
+    shrinking: bool,
+    done: bool,
+}
+
+impl<'a, K> Drop for Eviction<'a, K>
+where
+    K: Copy + Eq + Hash,
+{
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if self.shrinking {
+            inner.lane.restore(self.i, self.resident);
+        } else if inner.lane.keys.get(&self.key) == Some(&self.i) {
+            inner.lane.rename(self.i, self.resident);
+        } else {
+            // The frame was handed to yet another key, whose eviction writes it back
+            return;
+        }
+        inner.evicted(self.i, None);
+        self.evicted.notify_all();
+    }
+}
+
+impl<K, V> LruCache<K, V> {
+    fn lock_inner(&self) -> MutexGuard<'_, LruInner<K>> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
 }
 
 impl<K, V> LruCache<K, V>
@@ -88,47 +287,179 @@ where
     V: Default,
 {
     pub fn new(capacity: usize) -> LruCache<K, V> {
-        let mut entries = Vec::with_capacity(capacity);
-        for _ in 0..capacity {
-            entries.push(Default::default());
-        }
+        let counters = CacheCounters::default();
         LruCache {
-            entries,
-            lane: Mutex::new(LruLane::new(capacity)),
+            inner: Mutex::new(LruInner {
+                lane: LruLane::new(capacity),
+                evicting: HashMap::new(),
+            }),
+            evicted: Condvar::new(),
+            frames: Frames::new(&counters.dirty_entries),
+            counters,
         }
     }
+
+    /// Frame of `key` in the lane, waiting while the key is being evicted. A key evicted to
+    /// make room is recorded in `evicting`.
+    fn index(&self, key: K) -> usize {
+        let mut inner = self.lock_inner();
+        while inner.evicting.contains_key(&key) {
+            inner = self
+                .evicted
+                .wait(inner)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+
+        let victim = inner.lane.victim(&key);
+        let (i, _) = inner.lane.index(key);
+        if let Some(victim) = victim {
+            inner.evicting.insert(victim, i);
+        }
+        i
+    }
+
+    /// Write back and reset the entries of frames evicted by shrinking. A frame whose
+    /// write-back fails goes back in the lane with its key, and the first error is returned once
+    /// every other frame is freed.
+    fn clear_frames(&self, evicted: &[(usize, K)], write_back: &mut WriteBack<K, V>) -> Result<()> {
+        let mut result = Ok(());
+        for &(i, key) in evicted {
+            let mut entry = lock_entry(self.frames.get(i));
+            let mut eviction = Eviction {
+                inner: &self.inner,
+                evicted: &self.evicted,
+                key,
+                resident: key,
+                i,
+                shrinking: true,
+                done: false,
+            };
+
+            if entry.key == key && entry.state() == State::Dirty {
+                if let Err(err) = write_back(&mut entry) {
+                    if result.is_ok() {
+                        result = Err(err);
+                    }
+                    // Dropping `eviction` puts the key back
+                    continue;
+                }
+            }
+            if entry.key == key && entry.state() != State::Uninitialized {
+                entry.set_state(State::Uninitialized);
+                self.counters.evictions.incr();
+            }
+
+            eviction.done = true;
+            let mut inner = self.lock_inner();
+            inner.lane.free(i);
+            inner.evicted(i, None);
+            drop(inner);
+            self.evicted.notify_all();
+        }
+        result
+    }
 }
 
 impl<K, V> Cache<K, V> for LruCache<K, V>
 where
-    K: Copy + Eq + Hash + PartialEq,
-    V: Lazy,
+    K: Copy + Default + Eq + Hash + PartialEq,
+    V: Default + Lazy,
 {
-    fn lock(&self, key: K) -> MutexGuard<Entry<K, V>> {
-        let (i, uninitialized) = self.lane.lock().unwrap().index(key);
+    fn lock(
+        &self,
+        key: K,
+        write_back: &mut WriteBack<K, V>,
+    ) -> Result<MutexGuard<'_, Entry<K, V>>> {
+        loop {
+            let i = self.index(key);
+            let mut entry = lock_entry(self.frames.get(i));
+            // Another thread may have handed the frame to a different key before it was locked
+            if self.lock_inner().lane.keys.get(&key) != Some(&i) {
+                continue;
+            }
 
-        let mut entry = self.entries[i].lock().unwrap();
-        if uninitialized {
-            entry.state = State::Uninitialized;
+            let replaced = entry.key != key || entry.state() == State::Uninitialized;
+            let mut eviction = Eviction {
+                inner: &self.inner,
+                evicted: &self.evicted,
+                key,
+                resident: entry.key,
+                i,
+                shrinking: false,
+                done: !replaced,
+            };
+            prepare_entry(&mut entry, key, write_back, &self.counters)?;
+
+            if replaced {
+                eviction.done = true;
+                if self.lock_inner().evicted(i, Some(key)) {
+                    self.evicted.notify_all();
+                }
+            }
+            return Ok(entry);
         }
-        prepare_entry(&mut entry, key);
-        entry
     }
 
-    fn dirty_entries(&self) -> Vec<MutexGuard<Entry<K, V>>> {
-        let lane = self.lane.lock().unwrap();
+    /// Frames are locked in order, like `peek` does, so that the two cannot deadlock
+    fn dirty_entries(&self) -> Vec<MutexGuard<'_, Entry<K, V>>> {
+        let n_frames = self.lock_inner().lane.entries.len();
+        (0..n_frames)
+            .map(|i| lock_entry(self.frames.get(i)))
+            .filter(|entry| entry.state() == State::Dirty)
+            .collect()
+    }
 
-        let mut entries = Vec::with_capacity(lane.entries.len());
+    fn contains(&self, key: K) -> bool {
+        let inner = self.lock_inner();
+        inner.lane.keys.contains_key(&key) || inner.evicting.contains_key(&key)
+    }
 
-        let iter = LruLaneIterator::new(&lane);
-        for i in iter {
-            let entry = self.entries[i].lock().unwrap();
-            if entry.state == State::Dirty {
-                entries.push(entry);
-            }
+    fn peek(&self, keys: &[K], peek: &mut Peek<K, V>) -> Result<()> {
+        let mut frames = {
+            let inner = self.lock_inner();
+            keys.iter()
+                .filter_map(|key| {
+                    let i = inner.lane.keys.get(key).or_else(|| inner.evicting.get(key));
+                    i.map(|&i| (i, *key))
+                })
+                .collect::<Vec<_>>()
+        };
+        frames.sort_by_key(|&(i, _)| i);
+
+        let entries = frames
+            .into_iter()
+            .map(|(i, key)| (lock_entry(self.frames.get(i)), key))
+            .filter(|&(ref entry, key)| entry.key == key && entry.state() != State::Uninitialized)
+            .map(|(entry, _)| entry)
+            .collect();
+        peek(entries)
+    }
+
+    fn resize(&self, capacity: usize, write_back: &mut WriteBack<K, V>) -> Result<()> {
+        if capacity == 0 {
+            return Err(Error::InvalidCapacity(capacity));
         }
 
-        entries
+        let freed = {
+            let mut inner = self.lock_inner();
+            inner.lane.capacity = capacity;
+            let freed = inner
+                .lane
+                .evict(capacity)
+                .into_iter()
+                .map(|i| (i, inner.lane.entries[i].key))
+                .collect::<Vec<_>>();
+            for &(i, key) in &freed {
+                inner.evicting.insert(key, i);
+            }
+            freed
+        };
+
+        self.clear_frames(&freed, write_back)
+    }
+
+    fn stats(&self) -> CacheStats {
+        self.counters.stats()
     }
 }
 
@@ -154,6 +485,8 @@ struct LruLane<K> {
     entries: Vec<LruLaneEntry<K>>,
     head: Option<usize>,
     tail: Option<usize>,
+    /// Indices freed by shrinking
+    free: Vec<usize>,
 }
 
 impl<K> LruLane<K>
@@ -167,22 +500,26 @@ where
             entries: Vec::with_capacity(capacity),
             head: None,
             tail: None,
+            free: Vec::new(),
         }
     }
 
     fn index(&mut self, key: K) -> (usize, bool) {
-        if let Some(i) = self.keys.get(&key).map(|i| *i) {
+        if let Some(i) = self.keys.get(&key).copied() {
             self.touch(i);
             return (i, false);
         }
 
         let mut uninitialized = false;
 
-        let new_head_i = if self.entries.len() == self.capacity {
+        let new_head_i = if self.keys.len() >= self.capacity {
             let i = self.pop_back();
             self.keys.remove(&self.entries[i].key).unwrap();
             self.entries[i].key = key;
             i
+        } else if let Some(i) = self.free.pop() {
+            self.entries[i].key = key;
+            i
         } else {
             uninitialized = true;
             self.entries.push(LruLaneEntry::new(key));
@@ -195,6 +532,50 @@ where
         (new_head_i, uninitialized)
     }
 
+    /// The key that looking `key` up would evict
+    fn victim(&self, key: &K) -> Option<K> {
+        if self.keys.contains_key(key) || self.keys.len() < self.capacity {
+            return None;
+        }
+        self.tail.map(|i| self.entries[i].key)
+    }
+
+    /// Hand index `i` back to the key it was taken from
+    fn rename(&mut self, i: usize, key: K) {
+        self.keys.remove(&self.entries[i].key);
+        self.entries[i].key = key;
+        self.keys.insert(key, i);
+    }
+
+    /// Evict least recently used keys until at most `capacity` remain. Returns the evicted
+    /// indices, which are not reused until passed to `free` or `restore`.
+    fn evict(&mut self, capacity: usize) -> Vec<usize> {
+        let mut evicted = Vec::new();
+        while self.keys.len() > capacity {
+            let i = self.pop_back();
+            self.keys.remove(&self.entries[i].key).unwrap();
+            evicted.push(i);
+        }
+        evicted
+    }
+
+    fn free(&mut self, i: usize) {
+        self.free.push(i);
+    }
+
+    /// Put `key` back at evicted index `i` as the least recently used key
+    fn restore(&mut self, i: usize, key: K) {
+        self.entries[i].key = key;
+        self.entries[i].next = None;
+        self.entries[i].prev = self.tail;
+        match self.tail {
+            Some(tail_i) => self.entries[tail_i].next = Some(i),
+            None => self.head = Some(i),
+        }
+        self.tail = Some(i);
+        self.keys.insert(key, i);
+    }
+
     fn pop_back(&mut self) -> usize {
         let old_tail_i = self.tail.unwrap();
         self.tail = self.entries[old_tail_i].prev;
@@ -240,11 +621,13 @@ where
     }
 }
 
+#[cfg(test)]
 struct LruLaneIterator<'a, K: 'a> {
     lru_lane: &'a LruLane<K>,
     pos: Option<usize>,
 }
 
+#[cfg(test)]
 impl<'a, K> LruLaneIterator<'a, K> {
     fn new(lru_lane: &'a LruLane<K>) -> LruLaneIterator<'a, K> {
         LruLaneIterator {
@@ -254,6 +637,7 @@ impl<'a, K> LruLaneIterator<'a, K> {
     }
 }
 
+#[cfg(test)]
 impl<'a, K> Iterator for LruLaneIterator<'a, K> {
     type Item = usize;
 
@@ -269,6 +653,13 @@ impl<'a, K> Iterator for LruLaneIterator<'a, K> {
 
 #[cfg(test)]
 mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use error::Error;
+
     use super::*;
 
     impl Lazy for i32 {
@@ -277,25 +668,75 @@ mod tests {
         }
     }
 
+    fn no_write_back(_entry: &mut Entry<i32, i32>) -> Result<()> {
+        panic!("unexpected write back");
+    }
+
     #[test]
     fn single_cache_state_changes() {
         let cache: SingleCache<i32, i32> = SingleCache::new();
 
         {
-            let mut guard = cache.lock(1);
-            assert_eq!(guard.state, State::Unloaded);
-            guard.state = State::Fresh;
+            let mut guard = cache.lock(1, &mut no_write_back).unwrap();
+            assert_eq!(guard.state(), State::Unloaded);
+            guard.set_state(State::Fresh);
         }
-        assert_eq!(cache.lock(1).state, State::Fresh);
+        assert_eq!(
+            cache.lock(1, &mut no_write_back).unwrap().state(),
+            State::Fresh
+        );
 
         {
-            let mut guard = cache.lock(2);
-            assert_eq!(guard.state, State::Unloaded);
-            guard.state = State::Dirty;
+            let mut guard = cache.lock(2, &mut no_write_back).unwrap();
+            assert_eq!(guard.state(), State::Unloaded);
+            guard.set_state(State::Dirty);
         }
-        assert_eq!(cache.lock(2).state, State::Dirty);
+        assert_eq!(
+            cache.lock(2, &mut no_write_back).unwrap().state(),
+            State::Dirty
+        );
 
-        assert_eq!(cache.lock(3).state, State::Stale(2));
+        let mut written = Vec::new();
+        {
+            let guard = cache
+                .lock(3, &mut |entry| {
+                    written.push(entry.key);
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(guard.state(), State::Unloaded);
+        }
+        assert_eq!(written, [2]);
+    }
+
+    #[test]
+    fn single_cache_invalid_state() {
+        let cache: SingleCache<i32, i32> = SingleCache::new();
+
+        {
+            let mut guard = cache.lock(1, &mut no_write_back).unwrap();
+            match guard.get() {
+                Err(Error::InvalidState(State::Unloaded)) => {}
+                _ => panic!("unloaded entry must be rejected"),
+            }
+            assert!(guard.get_mut().is_err());
+        }
+
+        {
+            let mut guard = cache.lock(1, &mut no_write_back).unwrap();
+            guard.set_state(State::Fresh);
+            *guard.get_mut().unwrap() = 3;
+            assert_eq!(*guard.get().unwrap(), 3);
+            assert_eq!(guard.state(), State::Dirty);
+            guard.set_state(State::Unloaded);
+            assert!(guard.get().is_err());
+        }
+
+        // An entry left unloaded is loaded again by the next lock
+        assert_eq!(
+            cache.lock(2, &mut no_write_back).unwrap().state(),
+            State::Unloaded
+        );
     }
 
     #[test]
@@ -317,7 +758,7 @@ mod tests {
             assert_eq!(lane.index(input_keys[i]), expected[i]);
         }
 
-        let mut keys = lane.keys.keys().map(|key| *key).collect::<Vec<_>>();
+        let mut keys = lane.keys.keys().copied().collect::<Vec<_>>();
         keys.sort();
         assert_eq!(keys, [4, 5, 6]);
 
@@ -345,7 +786,7 @@ mod tests {
             assert_eq!(lane.index(input_keys[i]), expected[i]);
         }
 
-        let mut keys = lane.keys.keys().map(|key| *key).collect::<Vec<_>>();
+        let mut keys = lane.keys.keys().copied().collect::<Vec<_>>();
         keys.sort();
         assert_eq!(keys, [4, 5, 6]);
 
@@ -373,7 +814,7 @@ mod tests {
             assert_eq!(lane.index(input_keys[i]), expected[i]);
         }
 
-        let mut keys = lane.keys.keys().map(|key| *key).collect::<Vec<_>>();
+        let mut keys = lane.keys.keys().copied().collect::<Vec<_>>();
         keys.sort();
         assert_eq!(keys, [4, 5, 6]);
 
@@ -401,11 +842,207 @@ mod tests {
             assert_eq!(lane.index(input_keys[i]), expected[i]);
         }
 
-        let mut keys = lane.keys.keys().map(|key| *key).collect::<Vec<_>>();
+        let mut keys = lane.keys.keys().copied().collect::<Vec<_>>();
         keys.sort();
         assert_eq!(keys, [4, 5, 6]);
 
         let indices = LruLaneIterator::new(&lane).collect::<Vec<_>>();
         assert_eq!(indices, [2, 0, 1]);
     }
+
+    #[test]
+    fn lru_cache_lane_shrink() {
+        let mut lane: LruLane<i32> = LruLane::new(4);
+
+        for key in [0, 1, 2, 3, 0, 2].iter() {
+            lane.index(*key);
+        }
+
+        let mut evicted = lane.evict(2);
+        evicted.sort();
+        assert_eq!(evicted, [1, 3]);
+
+        lane.capacity = 2;
+
+        let mut keys = lane.keys.iter().map(|(k, i)| (*k, *i)).collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, [(0, 0), (2, 2)]);
+
+        let indices = LruLaneIterator::new(&lane).collect::<Vec<_>>();
+        assert_eq!(indices, [0, 2]);
+
+        // Freed indices are reused once the lane has room again
+        lane.free(1);
+        lane.capacity = 3;
+        assert_eq!(lane.index(4), (1, false));
+        assert_eq!(lane.index(5), (0, false));
+
+        // A key whose write-back failed goes back as least recently used
+        let evicted = lane.evict(2);
+        assert_eq!(evicted, [2]);
+        lane.restore(2, 2);
+        assert_eq!(lane.victim(&6), Some(2));
+    }
+
+    #[test]
+    fn lru_cache_lock_while_evicting() {
+        let cache: Arc<LruCache<i32, i32>> = Arc::new(LruCache::new(1));
+        cache
+            .lock(1, &mut no_write_back)
+            .unwrap()
+            .set_state(State::Dirty);
+
+        // 2 evicts 1, whose write-back fails, so 1 stays cached and dirty
+        let result = cache.lock(2, &mut |_| Err(Error::Poisoned));
+        assert!(result.is_err());
+        assert!(cache.contains(1));
+        assert!(!cache.contains(2));
+
+        // Holding an entry does not keep others from being locked
+        let entry = cache.lock(1, &mut no_write_back).unwrap();
+        assert_eq!(entry.state(), State::Dirty);
+        let other = Arc::clone(&cache);
+        let handle = thread::spawn(move || {
+            let mut written = Vec::new();
+            other
+                .lock(3, &mut |entry| {
+                    written.push(entry.key);
+                    entry.set_state(State::Fresh);
+                    Ok(())
+                })
+                .unwrap()
+                .set_state(State::Fresh);
+            written
+        });
+        thread::sleep(Duration::from_millis(50));
+        drop(entry);
+        assert_eq!(handle.join().unwrap(), [1]);
+        assert!(cache.contains(3));
+        assert_eq!(cache.stats().evictions, 1);
+        assert_eq!(cache.stats().dirty_entries, 0);
+    }
+
+    #[test]
+    fn lru_cache_threaded_increments() {
+        let n_threads = 8;
+        let n_increments = 1000;
+        let cache: Arc<LruCache<i32, i32>> = Arc::new(LruCache::new(4));
+        let storage = Arc::new(Mutex::new(HashMap::new()));
+
+        let mut handles = Vec::with_capacity(n_threads);
+        for t in 0..n_threads {
+            let (cache, storage) = (Arc::clone(&cache), Arc::clone(&storage));
+            handles.push(thread::spawn(move || {
+                for j in 0..n_increments {
+                    let key = ((t + j) % 16) as i32;
+                    let mut entry = cache
+                        .lock(key, &mut |entry| {
+                            storage.lock().unwrap().insert(entry.key, entry.value);
+                            entry.set_state(State::Fresh);
+                            Ok(())
+                        })
+                        .unwrap();
+                    if entry.state() == State::Unloaded {
+                        entry.value = *storage.lock().unwrap().get(&key).unwrap_or(&0);
+                        entry.set_state(State::Fresh);
+                    }
+                    *entry.get_mut().unwrap() += 1;
+                }
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        for entry in cache.dirty_entries() {
+            storage.lock().unwrap().insert(entry.key, entry.value);
+        }
+        let sum: i32 = storage.lock().unwrap().values().sum();
+        assert_eq!(sum, (n_threads * n_increments) as i32);
+    }
+
+    #[test]
+    fn lru_cache_write_back_panics() {
+        let cache: LruCache<i32, i32> = LruCache::new(1);
+        cache
+            .lock(1, &mut no_write_back)
+            .unwrap()
+            .set_state(State::Dirty);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            drop(cache.lock(2, &mut |_| panic!("write back")));
+        }));
+        assert!(result.is_err());
+
+        // 1 is still cached and poisoned, and the cache still works
+        assert!(cache.contains(1));
+        assert_eq!(
+            cache.lock(1, &mut no_write_back).unwrap().state(),
+            State::Poisoned
+        );
+        cache
+            .lock(2, &mut no_write_back)
+            .unwrap()
+            .set_state(State::Fresh);
+        assert!(!cache.contains(1));
+    }
+
+    #[test]
+    fn lru_cache_resize() {
+        let cache: LruCache<i32, i32> = LruCache::new(3);
+        for key in 0..3 {
+            cache
+                .lock(key, &mut no_write_back)
+                .unwrap()
+                .set_state(State::Dirty);
+        }
+
+        match cache.resize(0, &mut no_write_back) {
+            Err(Error::InvalidCapacity(0)) => {}
+            result => panic!("unexpected {:?}", result.map(|_| ())),
+        }
+
+        let mut written = Vec::new();
+        cache
+            .resize(1, &mut |entry| {
+                written.push(entry.key);
+                Ok(())
+            })
+            .unwrap();
+        written.sort();
+        assert_eq!(written, [0, 1]);
+        assert!(cache.contains(2));
+        assert!(!cache.contains(0));
+        assert_eq!(cache.dirty_entries().len(), 1);
+        assert_eq!(cache.stats().dirty_entries, 1);
+    }
+
+    #[test]
+    fn lru_cache_peek() {
+        let cache: LruCache<i32, i32> = LruCache::new(2);
+        for key in 1..3 {
+            cache
+                .lock(key, &mut no_write_back)
+                .unwrap()
+                .set_state(State::Fresh);
+        }
+
+        let mut peeked = Vec::new();
+        cache
+            .peek(&[1, 3], &mut |entries| {
+                peeked.extend(entries.iter().map(|entry| entry.key));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(peeked, [1]);
+        assert_eq!(cache.stats().hits, 0);
+
+        // Peeking did not make 1 recently used
+        cache
+            .lock(3, &mut no_write_back)
+            .unwrap()
+            .set_state(State::Fresh);
+        assert!(!cache.contains(1));
+        assert!(cache.contains(2));
+    }
 }
@@ -0,0 +1,292 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, Weak};
+use std::time::{Duration, Instant};
+
+/// A value change. `None` means the key had no value or was deleted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Change {
+    /// Increases by one with every change, so gaps show where changes were dropped
+    pub seq: u64,
+    pub key: u32,
+    pub old: Option<u64>,
+    pub new: Option<u64>,
+}
+
+/// What to do when a subscriber's queue is full
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SlowConsumer {
+    /// Drop the oldest queued change to make room
+    DropOldest,
+    /// Stop delivering. The subscriber drains what is queued and then sees the end of the stream.
+    Disconnect,
+}
+
+/// Fans changes out to subscribers in the order they happen
+#[derive(Default)]
+pub struct ChangeFeed {
+    inner: Mutex<FeedInner>,
+}
+
+#[derive(Default)]
+struct FeedInner {
+    seq: u64,
+    subscribers: Vec<Weak<Queue>>,
+}
+
+impl ChangeFeed {
+    fn inner(&self) -> MutexGuard<'_, FeedInner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn subscribe(&self, capacity: usize, policy: SlowConsumer) -> Subscription {
+        assert!(capacity > 0);
+
+        let queue = Arc::new(Queue {
+            inner: Mutex::new(QueueInner {
+                changes: VecDeque::with_capacity(capacity),
+                dropped: 0,
+                disconnected: false,
+            }),
+            ready: Condvar::new(),
+            capacity,
+            policy,
+        });
+        self.inner().subscribers.push(Arc::downgrade(&queue));
+        Subscription { queue }
+    }
+
+    /// Must be called while holding the entry of `key`, so that its changes are published in
+    /// order.
+    pub fn publish(&self, key: u32, old: Option<u64>, new: Option<u64>) {
+        let mut inner = self.inner();
+        if inner.subscribers.is_empty() {
+            return;
+        }
+
+        inner.seq += 1;
+        let change = Change {
+            seq: inner.seq,
+            key,
+            old,
+            new,
+        };
+        inner.subscribers.retain(|queue| match queue.upgrade() {
+            Some(queue) => queue.push(change),
+            None => false,
+        });
+    }
+}
+
+struct Queue {
+    inner: Mutex<QueueInner>,
+    ready: Condvar,
+    capacity: usize,
+    policy: SlowConsumer,
+}
+
+struct QueueInner {
+    changes: VecDeque<Change>,
+    dropped: u64,
+    disconnected: bool,
+}
+
+impl Queue {
+    fn inner(&self) -> MutexGuard<'_, QueueInner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns whether to keep delivering to this queue
+    fn push(&self, change: Change) -> bool {
+        let mut inner = self.inner();
+        if inner.changes.len() == self.capacity {
+            inner.dropped += 1;
+            match self.policy {
+                SlowConsumer::DropOldest => {
+                    inner.changes.pop_front();
+                }
+                SlowConsumer::Disconnect => {
+                    inner.disconnected = true;
+                    self.ready.notify_all();
+                    return false;
+                }
+            }
+        }
+        inner.changes.push_back(change);
+        self.ready.notify_all();
+        true
+    }
+}
+
+/// Receiving end of a change stream. Dropping it unsubscribes.
+pub struct Subscription {
+    queue: Arc<Queue>,
+}
+
+impl Subscription {
+    /// Wait for the next change. Returns `None` once disconnected and drained.
+    pub fn recv(&self) -> Option<Change> {
+        let mut inner = self.queue.inner();
+        loop {
+            if let Some(change) = inner.changes.pop_front() {
+                return Some(change);
+            }
+            if inner.disconnected {
+                return None;
+            }
+            inner = self
+                .queue
+                .ready
+                .wait(inner)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Like `recv`, but gives up after `timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Change> {
+        let deadline = Instant::now() + timeout;
+        let mut inner = self.queue.inner();
+        loop {
+            if let Some(change) = inner.changes.pop_front() {
+                return Some(change);
+            }
+            let now = Instant::now();
+            if inner.disconnected || now >= deadline {
+                return None;
+            }
+            inner = self
+                .queue
+                .ready
+                .wait_timeout(inner, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<Change> {
+        self.queue.inner().changes.pop_front()
+    }
+
+    /// Number of changes lost because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.queue.inner().dropped
+    }
+
+    /// Whether it was disconnected for falling behind
+    pub fn is_disconnected(&self) -> bool {
+        self.queue.inner().disconnected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use buffer::{test_buffer, Buffer};
+
+    use super::*;
+
+    fn change(seq: u64, key: u32, old: Option<u64>, new: Option<u64>) -> Change {
+        Change { seq, key, old, new }
+    }
+
+    #[test]
+    fn changes_in_order() {
+        let buffer = test_buffer();
+        let subscription = buffer.subscribe(16, SlowConsumer::DropOldest);
+
+        buffer.put(0, 1).unwrap();
+        buffer.fetch_add(0, 2).unwrap();
+        // Unchanged values are not reported
+        buffer.put(0, 3).unwrap();
+        buffer.lock(1).unwrap();
+        buffer.delete(0).unwrap();
+
+        let mut tx = buffer.transaction(&[1, 2]);
+        tx.put(1, 10).unwrap();
+        tx.put(2, 20).unwrap();
+        tx.commit().unwrap();
+
+        let changes = (0..5)
+            .map(|_| subscription.try_recv().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                change(1, 0, None, Some(1)),
+                change(2, 0, Some(1), Some(3)),
+                change(3, 0, Some(3), None),
+                change(4, 1, None, Some(10)),
+                change(5, 2, None, Some(20)),
+            ]
+        );
+        assert_eq!(subscription.try_recv(), None);
+    }
+
+    #[test]
+    fn slow_consumers() {
+        let buffer = test_buffer();
+        let dropping = buffer.subscribe(2, SlowConsumer::DropOldest);
+        let disconnecting = buffer.subscribe(2, SlowConsumer::Disconnect);
+
+        for value in 1..5 {
+            buffer.put(0, value).unwrap();
+        }
+
+        assert_eq!(dropping.dropped(), 2);
+        assert_eq!(dropping.recv().unwrap().seq, 3);
+        assert_eq!(dropping.recv().unwrap().seq, 4);
+
+        assert!(disconnecting.is_disconnected());
+        assert_eq!(disconnecting.recv().unwrap().seq, 1);
+        assert_eq!(disconnecting.recv().unwrap().seq, 2);
+        assert_eq!(disconnecting.recv(), None);
+
+        // Dropped subscriptions are forgotten
+        drop(dropping);
+        buffer.put(0, 5).unwrap();
+        assert_eq!(buffer.changes().inner().subscribers.len(), 0);
+    }
+
+    #[test]
+    fn threaded_changes() {
+        let n_writers: u32 = 4;
+        let n_increments: u64 = 500;
+
+        let n_changes = n_writers as u64 * n_increments;
+
+        let buffer = Arc::new(test_buffer());
+        let subscription = buffer.subscribe(n_changes as usize, SlowConsumer::Disconnect);
+
+        let reader = thread::spawn(move || {
+            let mut last_values = vec![None; n_writers as usize];
+            for seq in 1..=n_changes {
+                let change = subscription.recv_timeout(Duration::from_secs(5)).unwrap();
+                assert_eq!(change.seq, seq);
+
+                // Per key, each change follows the previous one
+                let last = &mut last_values[change.key as usize];
+                assert_eq!(change.old, *last);
+                assert_eq!(change.new, Some(last.unwrap_or(0) + 1));
+                *last = change.new;
+            }
+            assert_eq!(subscription.dropped(), 0);
+        });
+
+        let writers = (0..n_writers)
+            .map(|key| {
+                let buffer = buffer.clone();
+                thread::spawn(move || {
+                    for _ in 0..n_increments {
+                        buffer.fetch_add(key, 1).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for t in writers {
+            t.join().unwrap();
+        }
+        reader.join().unwrap();
+    }
+}
@@ -0,0 +1,306 @@
+use std::cmp;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, SeekFrom};
+use std::path::Path;
+
+use scan::CHUNK_LEN;
+use storage::{self, Slot, SLOT_SIZE, SLOT_WORDS};
+use ttl;
+
+/// What a data file is checked against
+#[derive(Clone, Debug, Default)]
+pub struct Invariants {
+    /// Number of keys the file should have room for
+    pub n_keys: Option<u32>,
+    /// Largest value allowed
+    pub max_value: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    /// The file ends inside a slot
+    TrailingBytes(u64),
+    /// The file has room for `found` keys instead of `expected`
+    Capacity { found: u64, expected: u32 },
+    /// An absent slot that is not zeroed
+    Malformed(u32),
+    /// A value above `Invariants::max_value`
+    TooLarge { key: u32, value: u64 },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::TrailingBytes(n) => write!(f, "{} trailing bytes after the last slot", n),
+            Problem::Capacity { found, expected } => {
+                write!(f, "room for {} keys instead of {}", found, expected)
+            }
+            Problem::Malformed(key) => write!(f, "key {}: malformed slot", key),
+            Problem::TooLarge { key, value } => write!(f, "key {}: value {} too large", key, value),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+    /// Keys with a value, expired or not
+    pub n_values: u64,
+    pub n_expired: u64,
+}
+
+/// A key whose slot differs between two files
+#[derive(Clone, Debug, PartialEq)]
+pub struct Difference {
+    pub key: u32,
+    pub left: Slot,
+    pub right: Slot,
+}
+
+/// Check the data file at `path` without modifying it
+pub fn check<P: AsRef<Path>>(path: P, invariants: &Invariants) -> io::Result<Report> {
+    let mut file = open(path, false)?;
+    let (n_slots, n_trailing) = storage::n_slots(file.metadata()?.len());
+
+    let mut report = Report::default();
+    if n_trailing != 0 {
+        report.problems.push(Problem::TrailingBytes(n_trailing));
+    }
+    if let Some(expected) = invariants.n_keys {
+        if n_slots != u64::from(expected) {
+            report.problems.push(Problem::Capacity {
+                found: n_slots,
+                expected,
+            });
+        }
+    }
+
+    let now = ttl::now();
+    for_each_chunk(&mut file, n_slots, &mut |first, words| {
+        for (i, words) in words.chunks(SLOT_WORDS).enumerate() {
+            let key = first + i as u32;
+            if storage::is_malformed(words) {
+                report.problems.push(Problem::Malformed(key));
+                continue;
+            }
+
+            let slot = storage::decode(words);
+            if let Some(value) = slot.value {
+                report.n_values += 1;
+                if slot.is_expired(now) {
+                    report.n_expired += 1;
+                }
+                if invariants.max_value.is_some_and(|max| value > max) {
+                    report.problems.push(Problem::TooLarge { key, value });
+                }
+            }
+        }
+    })?;
+
+    Ok(report)
+}
+
+/// Fix the `problems` found in the data file at `path`: trailing bytes are cut, a file too
+/// small is extended and malformed slots are cleared. Values above `Invariants::max_value` are
+/// only deleted with `delete_too_large`, and a file too large is left alone, since either
+/// would lose data. Returns the number of problems fixed.
+pub fn repair<P: AsRef<Path>>(
+    path: P,
+    problems: &[Problem],
+    delete_too_large: bool,
+) -> io::Result<usize> {
+    let mut file = open(path, true)?;
+
+    let mut n_fixed = 0;
+    for problem in problems {
+        let len = file.metadata()?.len();
+        match *problem {
+            Problem::TrailingBytes(_) => file.set_len(len - storage::n_slots(len).1)?,
+            Problem::Capacity { found, expected } if found < u64::from(expected) => {
+                file.seek(SeekFrom::Start(storage::slot_pos(found as u32)))?;
+                storage::write_zeros(&mut file, (u64::from(expected) - found) * SLOT_SIZE)?;
+            }
+            Problem::Capacity { .. } => continue,
+            Problem::TooLarge { .. } if !delete_too_large => continue,
+            Problem::Malformed(key) | Problem::TooLarge { key, .. } => {
+                write_slot(&mut file, key, Slot::default())?;
+            }
+        }
+        n_fixed += 1;
+    }
+
+    file.sync_data()?;
+    Ok(n_fixed)
+}
+
+/// Keys whose slots differ between the data files at `left` and `right`. Keys beyond the end
+/// of a file count as absent.
+pub fn compare<P, Q>(left: P, right: Q) -> io::Result<Vec<Difference>>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let mut left = open(left, false)?;
+    let mut right = open(right, false)?;
+    let (n_left, _) = storage::n_slots(left.metadata()?.len());
+    let (n_right, _) = storage::n_slots(right.metadata()?.len());
+
+    let mut differences = Vec::new();
+    let mut first = 0;
+    while first < cmp::max(n_left, n_right) {
+        let len = cmp::min(u64::from(CHUNK_LEN), cmp::max(n_left, n_right) - first);
+        let left_slots = read_slots(&mut left, first, len, n_left)?;
+        let right_slots = read_slots(&mut right, first, len, n_right)?;
+
+        for (i, (&left, &right)) in left_slots.iter().zip(&right_slots).enumerate() {
+            if left != right {
+                differences.push(Difference {
+                    key: (first + i as u64) as u32,
+                    left,
+                    right,
+                });
+            }
+        }
+        first += len;
+    }
+
+    Ok(differences)
+}
+
+/// Make the data file at `path` match the right side of `differences`. Returns the number of
+/// slots written.
+pub fn copy_differences<P: AsRef<Path>>(path: P, differences: &[Difference]) -> io::Result<usize> {
+    let mut file = open(path, true)?;
+    for difference in differences {
+        write_slot(&mut file, difference.key, difference.right)?;
+    }
+    file.sync_data()?;
+    Ok(differences.len())
+}
+
+/// Call `f` with the first key and the words of every chunk of the first `n_slots` slots
+fn for_each_chunk(file: &mut File, n_slots: u64, f: &mut dyn FnMut(u32, &[u64])) -> io::Result<()> {
+    let mut words = Vec::new();
+    let mut first = 0;
+    while first < n_slots {
+        let len = cmp::min(u64::from(CHUNK_LEN), n_slots - first);
+        words.resize(len as usize * SLOT_WORDS, 0);
+        storage::read_at(file, storage::slot_pos(first as u32), &mut words)?;
+        f(first as u32, &words);
+        first += len;
+    }
+    Ok(())
+}
+
+/// Read `len` slots from `first`, those at or beyond `n_slots` being absent
+fn read_slots(file: &mut File, first: u64, len: u64, n_slots: u64) -> io::Result<Vec<Slot>> {
+    let mut slots = vec![Slot::default(); len as usize];
+    let n_present = cmp::min(len, n_slots.saturating_sub(first)) as usize;
+
+    let mut words = vec![0; n_present * SLOT_WORDS];
+    storage::read_at(file, storage::slot_pos(first as u32), &mut words)?;
+    for (slot, words) in slots.iter_mut().zip(words.chunks(SLOT_WORDS)) {
+        *slot = storage::decode(words);
+    }
+    Ok(slots)
+}
+
+fn write_slot(file: &mut File, key: u32, slot: Slot) -> io::Result<()> {
+    let mut words = [0; SLOT_WORDS];
+    storage::encode(slot, &mut words);
+    storage::write_at(file, storage::slot_pos(key), &words)
+}
+
+/// Open a data file, failing unless it has the header of the current format
+fn open<P: AsRef<Path>>(path: P, write: bool) -> io::Result<File> {
+    let mut file = OpenOptions::new().read(true).write(write).open(path)?;
+    storage::check_header(&mut file)?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::ptr::NonNull;
+
+    use storage::{Storage, StorageImpl};
+
+    use super::*;
+
+    fn create(path: &str, values: &[(u32, u64)]) {
+        let mut storage = StorageImpl::new(path, 10).unwrap();
+        for &(key, value) in values {
+            let mut slot = Slot::new(Some(value), 0);
+            storage.write(key, NonNull::from(&mut slot)).unwrap();
+        }
+    }
+
+    #[test]
+    fn check_and_repair() {
+        let path = "tmp/check_1.db";
+        create(path, &[(1, 10), (2, 1000)]);
+
+        // Garbage in the absent slot of key 3 and a torn slot at the end
+        let mut bytes = fs::read(path).unwrap();
+        bytes[storage::slot_pos(3) as usize + 8] = 1;
+        bytes.extend_from_slice(&[1, 2, 3]);
+        fs::write(path, bytes).unwrap();
+
+        let invariants = Invariants {
+            n_keys: Some(12),
+            max_value: Some(100),
+        };
+        let report = check(path, &invariants).unwrap();
+        assert_eq!(
+            report.problems,
+            vec![
+                Problem::TrailingBytes(3),
+                Problem::Capacity {
+                    found: 10,
+                    expected: 12
+                },
+                Problem::TooLarge {
+                    key: 2,
+                    value: 1000
+                },
+                Problem::Malformed(3),
+            ]
+        );
+        assert_eq!(report.n_values, 2);
+
+        // Values are kept unless deleting them is asked for
+        assert_eq!(repair(path, &report.problems, false).unwrap(), 3);
+        let report = check(path, &invariants).unwrap();
+        assert_eq!(
+            report.problems,
+            vec![Problem::TooLarge {
+                key: 2,
+                value: 1000
+            }]
+        );
+        assert_eq!(report.n_values, 2);
+
+        assert_eq!(repair(path, &report.problems, true).unwrap(), 1);
+        let report = check(path, &invariants).unwrap();
+        assert_eq!(report.problems, vec![]);
+        assert_eq!(report.n_values, 1);
+    }
+
+    #[test]
+    fn compare_files() {
+        let (left, right) = ("tmp/check_2.db", "tmp/check_3.db");
+        create(left, &[(1, 10), (2, 20)]);
+        create(right, &[(2, 21), (3, 30)]);
+
+        let differences = compare(left, right).unwrap();
+        let keys = differences.iter().map(|d| d.key).collect::<Vec<_>>();
+        assert_eq!(keys, vec![1, 2, 3]);
+        assert_eq!(differences[1].left, Slot::new(Some(20), 0));
+        assert_eq!(differences[1].right, Slot::new(Some(21), 0));
+
+        assert_eq!(copy_differences(left, &differences).unwrap(), 3);
+        assert_eq!(compare(left, right).unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,392 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use buffer::{Buffer, BufferImpl};
+use cache::LruCache;
+use error::Error;
+use remote::RemoteClient;
+use stats::Stats;
+use storage::{Storage, StorageImpl};
+use store::Store;
+use wire;
+
+pub const USAGE: &str = "usage: piyokvs-cli [--data PATH [--keys N] [--cache N] | --connect ADDR] \
+                         [COMMAND ARGS...]
+
+Runs COMMAND and exits, or reads commands from standard input, one per line.";
+
+pub const HELP: &str = "get KEY                  print the value of KEY
+set KEY VALUE [TTL]      set KEY, deleted after TTL seconds if given
+incr KEY [DELTA]         add DELTA, 1 by default, and print the new value
+del KEY                  delete KEY
+scan [LIMIT]             print every key and value in key order
+sync                     write dirty entries to the data file
+stats                    print the buffer statistics
+info                     print what is open
+history                  list previous commands, rerun one with !N
+quit                     leave";
+
+/// What the commands run against: a data file opened in this process, or a server
+pub enum Target {
+    Local {
+        buffer: Box<BufferImpl>,
+        path: String,
+        n_keys: u32,
+        cache_capacity: usize,
+    },
+    Remote {
+        client: RemoteClient,
+        addr: String,
+    },
+}
+
+impl Target {
+    fn store(&self) -> &dyn Store {
+        match *self {
+            Target::Local { ref buffer, .. } => &**buffer,
+            Target::Remote { ref client, .. } => client,
+        }
+    }
+
+    fn stats(&self) -> Result<Stats, Error> {
+        match *self {
+            Target::Local { ref buffer, .. } => Ok(buffer.stats()),
+            Target::Remote { ref client, .. } => client.stats(),
+        }
+    }
+
+    /// Write up to `limit` present keys and their values to `out`
+    fn scan(&self, limit: u64, out: &mut dyn Write) -> Result<(), String> {
+        let err = |err: Error| err.to_string();
+        let line = |out: &mut dyn Write, (key, value)| {
+            writeln!(out, "{} {}", key, value).map_err(|err| err.to_string())
+        };
+
+        match *self {
+            Target::Local { ref buffer, .. } => {
+                for item in buffer.scan().take(limit as usize) {
+                    line(out, item.map_err(err)?)?;
+                }
+            }
+            Target::Remote { ref client, .. } => {
+                let mut first = 0;
+                let mut left = limit;
+                while left > 0 {
+                    let asked = left.min(u64::from(wire::MAX_SCAN_LEN)) as u32;
+                    let pairs = client.scan(first, asked).map_err(err)?;
+                    let last = pairs.last().map(|&(key, _)| key);
+                    left -= pairs.len() as u64;
+                    for pair in pairs.iter().cloned() {
+                        line(out, pair)?;
+                    }
+                    match last {
+                        Some(key) if pairs.len() as u32 == asked && key < u32::MAX => {
+                            first = key + 1
+                        }
+                        _ => break,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Open what the leading options of `args` name, removing them
+pub fn open(args: &mut Vec<String>) -> Result<Target, String> {
+    let mut data = "piyokvs.db".to_string();
+    let mut connect = None;
+    let mut n_keys = 1;
+    let mut cache_capacity = 1 << 12;
+
+    while args.first().is_some_and(|arg| arg.starts_with("--")) {
+        let arg = args.remove(0);
+        if args.is_empty() {
+            return Err(format!("missing value for {}", arg));
+        }
+        let value = args.remove(0);
+        match arg.as_str() {
+            "--data" => data = value,
+            "--connect" => connect = Some(value),
+            "--keys" => n_keys = value.parse().map_err(|_| "invalid --keys")?,
+            "--cache" => cache_capacity = value.parse().map_err(|_| "invalid --cache")?,
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    if n_keys == 0 || cache_capacity == 0 {
+        return Err("--keys and --cache must be positive".to_string());
+    }
+
+    if let Some(addr) = connect {
+        let client = RemoteClient::connect(&addr)
+            .map_err(|err| format!("cannot connect to {}: {}", addr, err))?;
+        return Ok(Target::Remote { client, addr });
+    }
+
+    let storage =
+        StorageImpl::open(&data, n_keys).map_err(|err| format!("cannot open {}: {}", data, err))?;
+    let n_keys = storage.n_keys();
+    let cache = LruCache::new(cache_capacity);
+    Ok(Target::Local {
+        buffer: Box::new(BufferImpl::new(Box::new(cache), Box::new(storage))),
+        path: data,
+        n_keys,
+        cache_capacity,
+    })
+}
+
+/// Run one command against `target`, writing what it prints to `out`
+pub fn run(target: &Target, args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+    let store = target.store();
+    let err = |err: Error| err.to_string();
+
+    let text = match *args {
+        ["get", key] => match store.get(key_arg(key)?).map_err(err)? {
+            Some(value) => format!("{}\n", value),
+            None => "(none)\n".to_string(),
+        },
+        ["set", key, value] => {
+            store.put(key_arg(key)?, int_arg(value)?).map_err(err)?;
+            String::new()
+        }
+        ["set", key, value, ttl] => {
+            let ttl = Duration::from_secs(int_arg(ttl)?);
+            store
+                .put_with_ttl(key_arg(key)?, int_arg(value)?, ttl)
+                .map_err(err)?;
+            String::new()
+        }
+        ["incr", key] | ["incr", key, _] => {
+            let delta = args.get(2).map_or(Ok(1), |delta| int_arg(delta))?;
+            let prev = store.fetch_add(key_arg(key)?, delta).map_err(err)?;
+            format!("{}\n", prev.wrapping_add(delta))
+        }
+        ["del", key] => {
+            let deleted = store.delete(key_arg(key)?).map_err(err)?;
+            format!("{}\n", if deleted { "deleted" } else { "(none)" })
+        }
+        ["scan"] | ["scan", _] => {
+            let limit = args.get(1).map_or(Ok(u64::MAX), |limit| int_arg(limit))?;
+            return target.scan(limit, out);
+        }
+        ["sync"] => {
+            store.sync().map_err(err)?;
+            String::new()
+        }
+        ["stats"] => {
+            let stats = target.stats().map_err(err)?;
+            format!(
+                "hits {}\nmisses {}\nhit_ratio {:.3}\nevictions {}\nwrite_backs {}\n\
+                 storage_reads {}\nstorage_writes {}\nexpirations {}\nsyncs {}\n\
+                 dirty_entries {}\n",
+                stats.hits,
+                stats.misses,
+                stats.hit_ratio(),
+                stats.evictions,
+                stats.write_backs,
+                stats.storage_reads,
+                stats.storage_writes,
+                stats.expirations,
+                stats.syncs,
+                stats.dirty_entries
+            )
+        }
+        ["info"] => match *target {
+            Target::Local {
+                ref path,
+                n_keys,
+                cache_capacity,
+                ..
+            } => {
+                let len = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+                format!(
+                    "data {}\nfile_size {}\nkeys {}\ncache_capacity {}\n",
+                    path, len, n_keys, cache_capacity
+                )
+            }
+            Target::Remote { ref addr, .. } => format!("server {}\n", addr),
+        },
+        ["help"] => format!("{}\n", HELP),
+        [] => String::new(),
+        _ => {
+            return Err(format!(
+                "unknown command or wrong arguments: {}",
+                args.join(" ")
+            ))
+        }
+    };
+    out.write_all(text.as_bytes())
+        .map_err(|err| err.to_string())
+}
+
+fn key_arg(arg: &str) -> Result<u32, String> {
+    arg.parse().map_err(|_| format!("invalid key: {}", arg))
+}
+
+fn int_arg(arg: &str) -> Result<u64, String> {
+    arg.parse().map_err(|_| format!("invalid number: {}", arg))
+}
+
+/// Commands entered so far, kept in a file if given one so that they outlive the session
+pub struct History {
+    path: Option<PathBuf>,
+    entries: Vec<String>,
+}
+
+impl History {
+    /// History of this session only
+    pub fn new() -> History {
+        History {
+            path: None,
+            entries: Vec::new(),
+        }
+    }
+
+    /// History continuing the one saved in `path`, which need not exist yet
+    pub fn load(path: PathBuf) -> io::Result<History> {
+        let entries = match File::open(&path) {
+            Ok(file) => BufReader::new(file).lines().collect::<io::Result<_>>()?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(History {
+            path: Some(path),
+            entries,
+        })
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    /// Replace a `!N` reference by the Nth command
+    pub fn expand(&self, line: &str) -> Result<String, String> {
+        if !line.starts_with('!') {
+            return Ok(line.to_string());
+        }
+        line[1..]
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| self.entries.get(n.wrapping_sub(1)))
+            .cloned()
+            .ok_or_else(|| "no such command in history".to_string())
+    }
+
+    /// Add `line`, appending it to the file right away so that a crash does not lose it
+    pub fn push(&mut self, line: String) -> io::Result<()> {
+        if let Some(ref path) = self.path {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", line)?;
+        }
+        self.entries.push(line);
+        Ok(())
+    }
+}
+
+impl Default for History {
+    fn default() -> History {
+        History::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use buffer::test_buffer;
+
+    use super::*;
+
+    fn args(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    fn output(target: &Target, command: &str) -> Result<String, String> {
+        let mut out = Vec::new();
+        run(
+            target,
+            &command.split_whitespace().collect::<Vec<_>>(),
+            &mut out,
+        )?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn parse_options() {
+        let mut rest = args("--data tmp/cli_1.db --keys 100 --cache 8 get 1");
+        match open(&mut rest).unwrap() {
+            Target::Local {
+                n_keys,
+                cache_capacity,
+                ..
+            } => assert_eq!((n_keys, cache_capacity), (100, 8)),
+            Target::Remote { .. } => panic!("expected a local target"),
+        }
+        assert_eq!(rest, args("get 1"));
+
+        assert!(open(&mut args("--keys")).is_err());
+        assert!(open(&mut args("--keys x")).is_err());
+        assert!(open(&mut args("--cache 0")).is_err());
+        assert!(open(&mut args("--verbose 1")).is_err());
+    }
+
+    #[test]
+    fn run_local() {
+        let _ = fs::remove_file("tmp/cli_2.db");
+        let target = open(&mut args("--data tmp/cli_2.db --keys 10")).unwrap();
+
+        assert_eq!(output(&target, "get 1").unwrap(), "(none)\n");
+        assert_eq!(output(&target, "set 1 41").unwrap(), "");
+        assert_eq!(output(&target, "incr 1").unwrap(), "42\n");
+        assert_eq!(output(&target, "set 3 7 60").unwrap(), "");
+        assert_eq!(output(&target, "scan").unwrap(), "1 42\n3 7\n");
+        assert_eq!(output(&target, "scan 1").unwrap(), "1 42\n");
+        assert_eq!(output(&target, "del 3").unwrap(), "deleted\n");
+        assert_eq!(output(&target, "sync").unwrap(), "");
+        assert!(output(&target, "stats").unwrap().contains("syncs 1\n"));
+        assert!(output(&target, "info").unwrap().contains("keys 10\n"));
+
+        assert!(output(&target, "get x").is_err());
+        assert!(output(&target, "frobnicate").is_err());
+    }
+
+    #[test]
+    fn run_remote() {
+        let buffer = Arc::new(test_buffer());
+        let addr = wire::spawn("127.0.0.1:0", buffer).unwrap();
+        let target = open(&mut vec!["--connect".to_string(), addr.to_string()]).unwrap();
+
+        assert_eq!(output(&target, "set 2 5").unwrap(), "");
+        assert_eq!(output(&target, "incr 2 3").unwrap(), "8\n");
+        assert_eq!(output(&target, "set 4 1").unwrap(), "");
+        // The mock storage of the server only knows keys up to the highest one written back
+        assert_eq!(output(&target, "sync").unwrap(), "");
+        assert_eq!(output(&target, "scan").unwrap(), "2 8\n4 1\n");
+        assert_eq!(output(&target, "scan 1").unwrap(), "2 8\n");
+        assert!(output(&target, "stats").unwrap().contains("syncs 1\n"));
+        assert_eq!(
+            output(&target, "info").unwrap(),
+            format!("server {}\n", addr)
+        );
+    }
+
+    #[test]
+    fn persist_history() {
+        let path = PathBuf::from("tmp/cli_3.history");
+        let _ = fs::remove_file(&path);
+
+        let mut history = History::load(path.clone()).unwrap();
+        history.push("set 1 2".to_string()).unwrap();
+        history.push("get 1".to_string()).unwrap();
+
+        let history = History::load(path).unwrap();
+        assert_eq!(history.entries(), ["set 1 2", "get 1"]);
+        assert_eq!(history.expand("!2").unwrap(), "get 1");
+        assert_eq!(history.expand("get 2").unwrap(), "get 2");
+        assert!(history.expand("!3").is_err());
+        assert!(history.expand("!0").is_err());
+    }
+}
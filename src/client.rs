@@ -3,23 +3,23 @@ use std::sync::Arc;
 use rand::distributions::Uniform;
 use rand::{thread_rng, Rng};
 
-use buffer::Buffer;
+use store::Store;
 
+/// Drives random increments against a local buffer or a server
 pub struct Client {
-    buffer: Arc<Buffer>,
+    store: Arc<dyn Store>,
 }
 
 impl Client {
-    pub fn new(buffer: Arc<Buffer>) -> Client {
-        Client { buffer }
+    pub fn new(store: Arc<dyn Store>) -> Client {
+        Client { store }
     }
 
     pub fn start(&self, n_data: u32, n_increments: usize) {
         let u = Uniform::new(0, n_data);
 
         for key in thread_rng().sample_iter(&u).take(n_increments) {
-            let mut entry = self.buffer.lock(key).unwrap();
-            *entry.as_mut() += 1;
+            self.store.fetch_add(key, 1).unwrap();
         }
     }
 }
@@ -0,0 +1,259 @@
+use std::cmp;
+use std::io;
+use std::io::prelude::*;
+use std::str::FromStr;
+
+use scan::CHUNK_LEN;
+use storage::{Slot, Storage, MAX_EXPIRES_AT};
+use ttl;
+
+const CSV_HEADER: &str = "key,value,expires_at";
+
+/// Text format of a dump, with one key per line
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// `{"key":1,"value":2,"expires_at":0}`
+    JsonLines,
+    /// `key,value,expires_at` after a header line
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "jsonl" | "json" => Ok(Format::JsonLines),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format {}", s)),
+        }
+    }
+}
+
+/// Write every key with a value in `storage` to `out`, in key order. Expired values are
+/// skipped. Returns the number of keys written.
+pub fn export(storage: &mut dyn Storage, format: Format, out: &mut dyn Write) -> io::Result<u64> {
+    if format == Format::Csv {
+        writeln!(out, "{}", CSV_HEADER)?;
+    }
+
+    let now = ttl::now();
+    let n_keys = storage.n_keys();
+    let mut slots = Vec::new();
+    let mut n_written = 0;
+
+    let mut first = 0;
+    while first < n_keys {
+        let len = cmp::min(CHUNK_LEN, n_keys - first);
+        slots.resize(len as usize, Slot::default());
+        storage.read_range(first, &mut slots)?;
+
+        for (i, slot) in slots.iter().enumerate() {
+            let value = match slot.value {
+                Some(value) if !slot.is_expired(now) => value,
+                _ => continue,
+            };
+            let key = first + i as u32;
+            match format {
+                Format::JsonLines => writeln!(
+                    out,
+                    "{{\"key\":{},\"value\":{},\"expires_at\":{}}}",
+                    key, value, slot.expires_at
+                )?,
+                Format::Csv => writeln!(out, "{},{},{}", key, value, slot.expires_at)?,
+            }
+            n_written += 1;
+        }
+        first += len;
+    }
+
+    out.flush()?;
+    Ok(n_written)
+}
+
+/// Write the keys read from `input` to `storage`, then sync it. Runs of consecutive keys are
+/// written together. Returns the number of keys read.
+pub fn import(
+    storage: &mut dyn Storage,
+    format: Format,
+    input: &mut dyn BufRead,
+) -> io::Result<u64> {
+    let n_keys = storage.n_keys();
+    let mut run: Vec<Slot> = Vec::new();
+    let mut run_first = 0;
+    let mut n_read = 0;
+
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || (format == Format::Csv && line == CSV_HEADER) {
+            continue;
+        }
+
+        let (key, slot) = match format {
+            Format::JsonLines => parse_json(line),
+            Format::Csv => parse_csv(line),
+        }
+        .ok_or_else(|| invalid(format!("line {}: cannot parse {:?}", i + 1, line)))?;
+        if key >= n_keys {
+            return Err(invalid(format!(
+                "line {}: key {} beyond the {} keys of storage",
+                i + 1,
+                key,
+                n_keys
+            )));
+        }
+
+        let contiguous = run_first + run.len() as u32 == key;
+        if !contiguous || run.len() == CHUNK_LEN as usize {
+            storage.write_range(run_first, &run)?;
+            run.clear();
+            run_first = key;
+        }
+        run.push(slot);
+        n_read += 1;
+    }
+
+    storage.write_range(run_first, &run)?;
+    storage.sync()?;
+    Ok(n_read)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn parse_csv(line: &str) -> Option<(u32, Slot)> {
+    let mut fields = line.split(',').map(str::trim);
+    let key = fields.next()?.parse().ok()?;
+    let value = fields.next()?.parse().ok()?;
+    let expires_at = match fields.next() {
+        Some(field) => parse_expires_at(field)?,
+        None => 0,
+    };
+    if fields.next().is_some() {
+        return None;
+    }
+    Some((key, Slot::new(Some(value), expires_at)))
+}
+
+/// Parses the flat objects written by `export`, with the fields in any order
+fn parse_json(line: &str) -> Option<(u32, Slot)> {
+    let body = line.strip_prefix('{')?.strip_suffix('}')?;
+
+    let (mut key, mut value, mut expires_at) = (None, None, 0);
+    for field in body.split(',') {
+        let mut parts = field.splitn(2, ':');
+        let name = parts.next()?.trim();
+        let number = parts.next()?.trim();
+        match name {
+            "\"key\"" => key = Some(number.parse().ok()?),
+            "\"value\"" => value = Some(number.parse().ok()?),
+            "\"expires_at\"" => expires_at = parse_expires_at(number)?,
+            _ => return None,
+        }
+    }
+    Some((key?, Slot::new(Some(value?), expires_at)))
+}
+
+/// Expiry times beyond `MAX_EXPIRES_AT` would be cut short in storage
+fn parse_expires_at(number: &str) -> Option<u64> {
+    number
+        .parse()
+        .ok()
+        .filter(|&expires_at| expires_at <= MAX_EXPIRES_AT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::ptr::NonNull;
+
+    use storage::StorageImpl;
+
+    use super::*;
+
+    fn storage(path: &str, values: &[(u32, Slot)]) -> StorageImpl {
+        let mut storage = StorageImpl::new(path, 2000).unwrap();
+        for &(key, mut slot) in values {
+            storage.write(key, NonNull::from(&mut slot)).unwrap();
+        }
+        storage
+    }
+
+    #[test]
+    fn export_formats() {
+        let mut storage = storage(
+            "tmp/dump_1.db",
+            &[
+                (1, Slot::new(Some(10), 0)),
+                (1500, Slot::new(Some(20), u64::MAX >> 1)),
+                (7, Slot::new(Some(30), 1)),
+            ],
+        );
+
+        let mut out = Vec::new();
+        assert_eq!(export(&mut storage, Format::Csv, &mut out).unwrap(), 2);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("key,value,expires_at\n1,10,0\n1500,20,{}\n", u64::MAX >> 1)
+        );
+
+        let mut out = Vec::new();
+        export(&mut storage, Format::JsonLines, &mut out).unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .starts_with("{\"key\":1,\"value\":10,\"expires_at\":0}\n"));
+    }
+
+    #[test]
+    fn round_trip() {
+        let values = (0..1200)
+            .chain(1500..1510)
+            .map(|key| (key, Slot::new(Some(u64::from(key) * 3), 0)))
+            .collect::<Vec<_>>();
+        let mut source = storage("tmp/dump_2.db", &values);
+
+        for &format in &[Format::JsonLines, Format::Csv] {
+            let mut out = Vec::new();
+            export(&mut source, format, &mut out).unwrap();
+
+            let mut target = StorageImpl::new("tmp/dump_3.db", 2000).unwrap();
+            assert_eq!(
+                import(&mut target, format, &mut Cursor::new(out)).unwrap(),
+                1210
+            );
+
+            let mut slots = vec![Slot::default(); 2000];
+            target.read_range(0, &mut slots).unwrap();
+            for &(key, slot) in &values {
+                assert_eq!(slots[key as usize], slot);
+            }
+            assert_eq!(
+                slots.iter().filter(|slot| slot.value.is_some()).count(),
+                1210
+            );
+        }
+    }
+
+    #[test]
+    fn import_errors() {
+        let mut target = StorageImpl::new("tmp/dump_4.db", 10).unwrap();
+
+        let mut input = Cursor::new("1,2\n10,1\n");
+        let err = import(&mut target, Format::Csv, &mut input).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2: key 10 beyond the 10 keys of storage"
+        );
+
+        let mut input = Cursor::new("{\"key\":1,\"size\":2}\n");
+        assert!(import(&mut target, Format::JsonLines, &mut input).is_err());
+
+        let line = format!("1,2,{}", MAX_EXPIRES_AT + 1);
+        let err = import(&mut target, Format::Csv, &mut Cursor::new(&line)).unwrap_err();
+        assert_eq!(err.to_string(), format!("line 1: cannot parse {:?}", line));
+        let line = format!("{{\"key\":1,\"value\":2,\"expires_at\":{}}}", u64::MAX);
+        assert!(import(&mut target, Format::JsonLines, &mut Cursor::new(line)).is_err());
+    }
+}
@@ -1,54 +1,78 @@
 use std::ptr::NonNull;
+use std::sync::Arc;
+
+use error::{Error, Result};
+use stats::Counter;
 
 pub trait Lazy {
     fn init(&mut self);
 }
 
-#[derive(Debug, PartialEq)]
-pub enum State<K> {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
     Uninitialized,
     Unloaded,
     Fresh,
     Dirty,
-    Stale(K),
+    /// A thread panicked while holding the entry, so its value cannot be trusted
+    Poisoned,
 }
 
 #[derive(Debug)]
 pub struct Entry<K, V> {
     pub key: K,
     pub value: V,
-    pub state: State<K>,
+    /// Only changed through `set_state`, which keeps `dirty_entries` in step
+    state: State,
+    /// Timestamp of the commit that produced `value`, or a later one if it was loaded from storage
+    pub version: u64,
+    /// Expiry time as returned by `ttl::deadline`, or 0 if it never expires
+    pub expires_at: u64,
+    /// Counts the entries sharing it that are dirty, as long as their state is only changed
+    /// through `set_state`
+    dirty_entries: Option<Arc<Counter>>,
 }
 
 impl<K, V> Entry<K, V> {
-    pub fn as_ptr(&mut self) -> NonNull<V> {
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: State) {
+        if let Some(ref dirty_entries) = self.dirty_entries {
+            match (self.state == State::Dirty, state == State::Dirty) {
+                (false, true) => dirty_entries.incr(),
+                (true, false) => dirty_entries.decr(),
+                _ => {}
+            }
+        }
+        self.state = state;
+    }
+
+    /// Pointer for storage I/O. Marks the entry as in sync with storage.
+    pub fn as_ptr(&mut self) -> Result<NonNull<V>> {
         match self.state {
-            State::Unloaded | State::Dirty | State::Stale(_) => {}
-            _ => panic!("invalid state"),
+            State::Unloaded | State::Dirty => {}
+            state => return Err(Error::InvalidState(state)),
         }
-        self.state = State::Fresh;
-        unsafe { NonNull::new_unchecked(&mut self.value) }
+        self.set_state(State::Fresh);
+        Ok(unsafe { NonNull::new_unchecked(&mut self.value) })
     }
-}
 
-impl<K, V> AsRef<V> for Entry<K, V> {
-    fn as_ref(&self) -> &V {
+    pub fn get(&self) -> Result<&V> {
         match self.state {
-            State::Fresh | State::Dirty => {}
-            _ => panic!("invalid state"),
+            State::Fresh | State::Dirty => Ok(&self.value),
+            state => Err(Error::InvalidState(state)),
         }
-        &self.value
     }
-}
 
-impl<K, V> AsMut<V> for Entry<K, V> {
-    fn as_mut(&mut self) -> &mut V {
+    pub fn get_mut(&mut self) -> Result<&mut V> {
         match self.state {
             State::Fresh | State::Dirty => {}
-            _ => panic!("invalid state"),
+            state => return Err(Error::InvalidState(state)),
         }
-        self.state = State::Dirty;
-        &mut self.value
+        self.set_state(State::Dirty);
+        Ok(&mut self.value)
     }
 }
 
@@ -62,6 +86,23 @@ where
             key: Default::default(),
             value: Default::default(),
             state: State::Uninitialized,
+            version: 0,
+            expires_at: 0,
+            dirty_entries: None,
+        }
+    }
+}
+
+impl<K, V> Entry<K, V>
+where
+    K: Default,
+    V: Default,
+{
+    /// Uninitialized entry counted in `dirty_entries` while dirty
+    pub fn counted(dirty_entries: &Arc<Counter>) -> Entry<K, V> {
+        Entry {
+            dirty_entries: Some(Arc::clone(dirty_entries)),
+            ..Default::default()
         }
     }
 }
@@ -72,6 +113,6 @@ where
 {
     fn init(&mut self) {
         self.value.init();
-        self.state = State::Unloaded;
+        self.set_state(State::Unloaded);
     }
 }
@@ -0,0 +1,55 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+use entry::State;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// An entry was used in a state that does not allow the operation
+    InvalidState(State),
+    /// A thread panicked while holding the entry
+    Poisoned,
+    /// The key was not locked when the transaction began
+    NotInTransaction(u32),
+    /// A server failed the request with this message
+    Remote(String),
+    /// A cache cannot hold this many entries
+    InvalidCapacity(usize),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "I/O error: {}", err),
+            Error::InvalidState(state) => write!(f, "invalid entry state: {:?}", state),
+            Error::Poisoned => write!(f, "entry poisoned by a panicked thread"),
+            Error::NotInTransaction(key) => write!(f, "key {} is not in the transaction", key),
+            Error::Remote(ref message) => write!(f, "server error: {}", message),
+            Error::InvalidCapacity(capacity) => write!(f, "invalid cache capacity {}", capacity),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            Error::InvalidState(_)
+            | Error::Poisoned
+            | Error::NotInTransaction(_)
+            | Error::Remote(_)
+            | Error::InvalidCapacity(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
@@ -0,0 +1,363 @@
+use std::fmt::Write as FmtWrite;
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::str;
+use std::sync::Arc;
+use std::time::Duration;
+
+use buffer::Buffer;
+use error::Result;
+use server;
+use stats::Stats;
+
+/// Largest request body accepted
+const MAX_BODY_LEN: u64 = 1 << 16;
+/// Largest request line and headers accepted, together
+const MAX_HEAD_LEN: u64 = 1 << 14;
+
+/// A parsed HTTP request
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    /// Path without the query string
+    pub path: String,
+    pub query: String,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Value of `name` in the query string
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .split('&')
+            .filter_map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                Some((parts.next()?, parts.next().unwrap_or("")))
+            })
+            .find(|&(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: &'static str, content_type: &'static str, body: String) -> Response {
+        Response {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    fn json(status: &'static str, body: String) -> Response {
+        Response::new(status, "application/json", body)
+    }
+
+    fn error(status: &'static str, message: &str) -> Response {
+        let message = message.replace('\\', "\\\\").replace('"', "\\\"");
+        Response::json(status, format!("{{\"error\":\"{}\"}}", message))
+    }
+
+    /// Write the response, closing the connection after it
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write!(
+            out,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            self.content_type,
+            self.body.len(),
+            self.body
+        )
+    }
+}
+
+/// Read a request, its headers and a body of up to `MAX_BODY_LEN` bytes
+pub fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Request> {
+    let mut head = reader.by_ref().take(MAX_HEAD_LEN);
+    let mut request_line = String::new();
+    head.read_line(&mut request_line)?;
+
+    let mut content_length = 0;
+    let mut line = String::new();
+    while head.read_line(&mut line)? > 2 {
+        let mut header = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (header.next(), header.next()) {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid("invalid Content-Length"))?;
+            }
+        }
+        line.clear();
+    }
+    if head.limit() == 0 {
+        return Err(invalid("request head too large"));
+    }
+    if content_length > MAX_BODY_LEN {
+        return Err(invalid("request body too large"));
+    }
+
+    let mut body = Vec::new();
+    reader.take(content_length).read_to_end(&mut body)?;
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let mut target = parts.next().unwrap_or("").splitn(2, '?');
+    let path = target.next().unwrap_or("").to_string();
+    let query = target.next().unwrap_or("").to_string();
+
+    Ok(Request {
+        method,
+        path,
+        query,
+        body,
+    })
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Serve the JSON API for `buffer` on `addr` from background threads, one per connection.
+/// Returns the bound address.
+///
+/// - `GET /keys/{k}` reads a value
+/// - `PUT /keys/{k}` sets the value in the body, deleted after `?ttl=SECONDS` if given
+/// - `POST /keys/{k}/incr` adds the signed delta in the body, or 1 if empty
+/// - `DELETE /keys/{k}` deletes a value
+/// - `POST /sync` writes dirty entries to storage
+/// - `GET /stats` returns the buffer statistics
+pub fn spawn<A>(addr: A, buffer: Arc<dyn Buffer + Send + Sync>) -> io::Result<SocketAddr>
+where
+    A: ToSocketAddrs,
+{
+    server::spawn(addr, buffer, handle)
+}
+
+/// Accept connections on `listener` like `server::serve`
+pub fn serve(listener: &TcpListener, buffer: &Arc<dyn Buffer + Send + Sync>) {
+    server::serve(listener, buffer, handle)
+}
+
+fn handle(stream: TcpStream, buffer: &dyn Buffer) -> io::Result<()> {
+    let response = match read_request(&mut BufReader::new(stream.try_clone()?)) {
+        Ok(request) => route(buffer, &request),
+        Err(err) => Response::error("400 Bad Request", &err.to_string()),
+    };
+    let mut stream = stream;
+    response.write_to(&mut stream)
+}
+
+/// Answer `request` from `buffer`
+pub fn route(buffer: &dyn Buffer, request: &Request) -> Response {
+    let segments = request.path.split('/').skip(1).collect::<Vec<_>>();
+    let method = request.method.as_str();
+
+    let response = match (method, segments.as_slice()) {
+        ("POST", ["sync"]) => buffer
+            .sync()
+            .map(|()| Response::json("200 OK", "{}".into())),
+        ("GET", ["stats"]) => Ok(Response::json("200 OK", stats_json(&buffer.stats()))),
+        (_, ["keys", key]) | (_, ["keys", key, "incr"]) => {
+            let key = match key.parse::<u32>() {
+                Ok(key) => key,
+                Err(_) => return Response::error("400 Bad Request", "invalid key"),
+            };
+            match (method, segments.len()) {
+                ("GET", 2) => buffer.get(key).map(|value| match value {
+                    Some(value) => value_json(key, value),
+                    None => Response::error("404 Not Found", "not found"),
+                }),
+                ("PUT", 2) => put(buffer, key, request),
+                ("DELETE", 2) => buffer.delete(key).map(|deleted| {
+                    if deleted {
+                        Response::json("200 OK", "{\"deleted\":true}".into())
+                    } else {
+                        Response::error("404 Not Found", "not found")
+                    }
+                }),
+                ("POST", 3) => incr(buffer, key, request),
+                _ => Ok(Response::error(
+                    "405 Method Not Allowed",
+                    "method not allowed",
+                )),
+            }
+        }
+        _ => Ok(Response::error("404 Not Found", "not found")),
+    };
+
+    response.unwrap_or_else(|err| Response::error("500 Internal Server Error", &err.to_string()))
+}
+
+fn put(buffer: &dyn Buffer, key: u32, request: &Request) -> Result<Response> {
+    let value = match parse_body::<u64>(&request.body) {
+        Some(value) => value,
+        None => return Ok(Response::error("400 Bad Request", "invalid value")),
+    };
+
+    match request.param("ttl").map(str::parse::<u64>) {
+        None => buffer.put(key, value)?,
+        Some(Ok(ttl)) if ttl > 0 => buffer.put_with_ttl(key, value, Duration::from_secs(ttl))?,
+        Some(_) => return Ok(Response::error("400 Bad Request", "invalid ttl")),
+    }
+    Ok(value_json(key, value))
+}
+
+/// Adds under the entry lock, refusing to leave the `u64` range
+fn incr(buffer: &dyn Buffer, key: u32, request: &Request) -> Result<Response> {
+    let delta = if request.body.iter().all(u8::is_ascii_whitespace) {
+        1
+    } else {
+        match parse_body::<i64>(&request.body) {
+            Some(delta) => delta,
+            None => return Ok(Response::error("400 Bad Request", "invalid delta")),
+        }
+    };
+
+    let mut entry = buffer.lock(key)?;
+    let value = entry.get_mut()?;
+    let prev = value.unwrap_or(0);
+    let next = if delta >= 0 {
+        prev.checked_add(delta as u64)
+    } else {
+        prev.checked_sub(delta.unsigned_abs())
+    };
+    match next {
+        Some(next) => {
+            *value = Some(next);
+            Ok(value_json(key, next))
+        }
+        None => Ok(Response::error("409 Conflict", "overflow")),
+    }
+}
+
+fn parse_body<T: str::FromStr>(body: &[u8]) -> Option<T> {
+    str::from_utf8(body).ok()?.trim().parse().ok()
+}
+
+fn value_json(key: u32, value: u64) -> Response {
+    Response::json("200 OK", format!("{{\"key\":{},\"value\":{}}}", key, value))
+}
+
+fn stats_json(stats: &Stats) -> String {
+    let mut out = String::from("{");
+    for &(name, value) in &[
+        ("hits", stats.hits),
+        ("misses", stats.misses),
+        ("evictions", stats.evictions),
+        ("write_backs", stats.write_backs),
+        ("storage_reads", stats.storage_reads),
+        ("storage_writes", stats.storage_writes),
+        ("expirations", stats.expirations),
+        ("syncs", stats.syncs),
+        ("dirty_entries", stats.dirty_entries),
+    ] {
+        write!(out, "\"{}\":{},", name, value).unwrap();
+    }
+    write!(out, "\"hit_ratio\":{}}}", stats.hit_ratio()).unwrap();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use buffer::test_buffer;
+
+    use super::*;
+
+    fn request(method: &str, target: &str, body: &str) -> Request {
+        let text = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\ncontent-length: {}\r\n\r\n{}",
+            method,
+            target,
+            body.len(),
+            body
+        );
+        read_request(&mut Cursor::new(text)).unwrap()
+    }
+
+    #[test]
+    fn parse_request() {
+        let request = request("PUT", "/keys/1?ttl=5&x", "42");
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path, "/keys/1");
+        assert_eq!(request.param("ttl"), Some("5"));
+        assert_eq!(request.param("x"), Some(""));
+        assert_eq!(request.param("y"), None);
+        assert_eq!(request.body, b"42");
+
+        let long = format!(
+            "GET /{} HTTP/1.1\r\n\r\n",
+            "x".repeat(MAX_HEAD_LEN as usize)
+        );
+        let err = read_request(&mut Cursor::new(long)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn route_requests() {
+        let buffer = Arc::new(test_buffer());
+        let run = |method: &str, target: &str, body: &str| {
+            let response = route(&*buffer, &request(method, target, body));
+            (response.status, response.body)
+        };
+
+        assert_eq!(
+            run("GET", "/keys/1", ""),
+            ("404 Not Found", "{\"error\":\"not found\"}".to_string())
+        );
+        assert_eq!(
+            run("PUT", "/keys/1", "41\n"),
+            ("200 OK", "{\"key\":1,\"value\":41}".to_string())
+        );
+        assert_eq!(
+            run("POST", "/keys/1/incr", ""),
+            ("200 OK", "{\"key\":1,\"value\":42}".to_string())
+        );
+        assert_eq!(run("POST", "/keys/1/incr", "-43").0, "409 Conflict");
+        assert_eq!(
+            run("GET", "/keys/1", ""),
+            ("200 OK", "{\"key\":1,\"value\":42}".to_string())
+        );
+        assert_eq!(run("DELETE", "/keys/1", "").0, "200 OK");
+        assert_eq!(run("DELETE", "/keys/1", "").0, "404 Not Found");
+
+        assert_eq!(run("PUT", "/keys/2?ttl=60", "1").0, "200 OK");
+        assert!(buffer.ttl(2).unwrap().is_some());
+        assert_eq!(run("PUT", "/keys/2", "x").0, "400 Bad Request");
+        assert_eq!(run("GET", "/keys/x", "").0, "400 Bad Request");
+        assert_eq!(run("PATCH", "/keys/2", "").0, "405 Method Not Allowed");
+
+        assert_eq!(run("POST", "/sync", ""), ("200 OK", "{}".to_string()));
+        let (status, body) = run("GET", "/stats", "");
+        assert_eq!(status, "200 OK");
+        assert!(body.starts_with("{\"hits\":"));
+        assert!(body.contains("\"syncs\":1,"));
+    }
+
+    #[test]
+    fn serve_requests() {
+        let buffer = Arc::new(test_buffer());
+        let addr = spawn("127.0.0.1:0", buffer.clone()).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"PUT /keys/7 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\n\r\n42")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n{\"key\":7,\"value\":42}"));
+        assert_eq!(buffer.get(7).unwrap(), Some(42));
+    }
+}
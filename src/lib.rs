@@ -1,7 +1,30 @@
 extern crate rand;
 
+pub mod archive;
+pub mod backup;
 pub mod buffer;
 pub mod cache;
+pub mod changes;
+pub mod check;
+pub mod cli;
 pub mod client;
+pub mod dump;
 pub mod entry;
+pub mod error;
+pub mod http;
+pub mod log;
+pub mod memcached;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod remote;
+pub mod resp;
+pub mod scan;
+pub mod server;
+pub mod snapshot;
+pub mod stats;
 pub mod storage;
+pub mod store;
+pub mod transaction;
+pub mod ttl;
+pub mod watch;
+pub mod wire;
@@ -0,0 +1,169 @@
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, SeekFrom};
+use std::path::Path;
+use std::ptr::NonNull;
+
+use storage::{Slot, Storage};
+
+const HEADER_SIZE: usize = 4;
+const WRITE_SIZE: usize = 13;
+const CHECKSUM_SIZE: usize = 8;
+
+/// Keys and their new values, `None` for deletes
+type Writes = Vec<(u32, Option<u64>)>;
+
+/// Redo log of committed transactions. Each record is the number of writes, the writes as
+/// `(key, tag, value)` with a tag of 0 for deletes, and a checksum, so that a record torn by a
+/// crash is ignored on replay.
+pub struct Log {
+    file: File,
+}
+
+impl Log {
+    /// Open the log at `path`, keeping records left by a previous run
+    pub fn open<P>(path: P) -> io::Result<Log>
+    where
+        P: AsRef<Path>,
+    {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.as_ref())?;
+        Ok(Log { file })
+    }
+
+    /// Durably append a record. Returns once it is on disk.
+    pub fn append(&mut self, writes: &[(u32, Option<u64>)]) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE + writes.len() * WRITE_SIZE + CHECKSUM_SIZE);
+        buf.extend_from_slice(&(writes.len() as u32).to_le_bytes());
+        for &(key, value) in writes {
+            buf.extend_from_slice(&key.to_le_bytes());
+            buf.push(value.is_some() as u8);
+            buf.extend_from_slice(&value.unwrap_or(0).to_le_bytes());
+        }
+        let checksum = checksum(&buf);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&buf)?;
+        self.file.sync_data()
+    }
+
+    /// Apply every complete record to `storage` in order. Returns the writes of each record
+    /// applied.
+    pub fn replay(&mut self, storage: &mut dyn Storage) -> io::Result<Vec<Writes>> {
+        let mut buf = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut buf)?;
+
+        let mut records = Vec::new();
+        let mut pos = 0;
+        while let Some((writes, len)) = parse_record(&buf[pos..]) {
+            for &(key, value) in &writes {
+                storage.write(key, NonNull::from(&mut Slot::new(value, 0)))?;
+            }
+            records.push(writes);
+            pos += len;
+        }
+
+        storage.sync()?;
+        Ok(records)
+    }
+
+    /// Drop all records, once their writes have been synced to storage
+    pub fn truncate(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()
+    }
+}
+
+/// Returns the writes and the length of the record at the start of `buf`, if it is complete
+fn parse_record(buf: &[u8]) -> Option<(Writes, usize)> {
+    if buf.len() < HEADER_SIZE {
+        return None;
+    }
+    let n_writes = u32_at(buf, 0) as usize;
+
+    let checksum_pos = HEADER_SIZE + n_writes * WRITE_SIZE;
+    let len = checksum_pos + CHECKSUM_SIZE;
+    if buf.len() < len || u64_at(buf, checksum_pos) != checksum(&buf[..checksum_pos]) {
+        return None;
+    }
+
+    let writes = (0..n_writes)
+        .map(|i| {
+            let pos = HEADER_SIZE + i * WRITE_SIZE;
+            let value = match buf[pos + 4] {
+                0 => None,
+                _ => Some(u64_at(buf, pos + 5)),
+            };
+            (u32_at(buf, pos), value)
+        })
+        .collect();
+
+    Some((writes, len))
+}
+
+fn u32_at(buf: &[u8], pos: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[pos..pos + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn u64_at(buf: &[u8], pos: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[pos..pos + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// FNV-1a
+pub(crate) fn checksum(buf: &[u8]) -> u64 {
+    buf.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use storage::StorageMock;
+
+    use super::*;
+
+    fn read(storage: &mut impl Storage, key: u32) -> Option<u64> {
+        let mut slot = Slot::default();
+        storage.read(key, NonNull::from(&mut slot)).unwrap();
+        slot.value
+    }
+
+    #[test]
+    fn replay_complete_records() {
+        let path = "tmp/log_1.log";
+        let _ = fs::remove_file(path);
+
+        let mut log = Log::open(path).unwrap();
+        log.append(&[(0, Some(10)), (1, Some(20)), (3, Some(30))])
+            .unwrap();
+        log.append(&[(0, Some(30)), (3, None)]).unwrap();
+
+        // Tear the last record as if crashed while appending
+        log.append(&[(1, Some(40)), (2, Some(50))]).unwrap();
+        let len = fs::metadata(path).unwrap().len();
+        log.file.set_len(len - 1).unwrap();
+
+        let mut storage = StorageMock::new();
+        let mut log = Log::open(path).unwrap();
+        assert_eq!(log.replay(&mut storage).unwrap().len(), 2);
+        assert_eq!(read(&mut storage, 0), Some(30));
+        assert_eq!(read(&mut storage, 1), Some(20));
+        assert_eq!(read(&mut storage, 2), None);
+        assert_eq!(read(&mut storage, 3), None);
+
+        log.truncate().unwrap();
+        assert_eq!(log.replay(&mut storage).unwrap().len(), 0);
+    }
+}
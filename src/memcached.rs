@@ -0,0 +1,332 @@
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::str;
+use std::sync::Arc;
+use std::time::Duration;
+
+use buffer::Buffer;
+use error::Result;
+use server;
+use ttl;
+
+/// Longest command line or data block a client may send
+const MAX_LEN: usize = 1 << 20;
+
+/// Expiry times above 30 days are Unix timestamps rather than relative seconds
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+/// Serve the memcached text protocol for `buffer` on `addr` from background threads, one per
+/// connection. Returns the bound address.
+pub fn spawn<A>(addr: A, buffer: Arc<dyn Buffer + Send + Sync>) -> io::Result<SocketAddr>
+where
+    A: ToSocketAddrs,
+{
+    server::spawn(addr, buffer, handle)
+}
+
+/// Accept connections on `listener` like `server::serve`
+pub fn serve(listener: &TcpListener, buffer: &Arc<dyn Buffer + Send + Sync>) {
+    server::serve(listener, buffer, handle)
+}
+
+fn handle(stream: TcpStream, buffer: &dyn Buffer) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let mut line = Vec::new();
+        if reader
+            .by_ref()
+            .take(MAX_LEN as u64)
+            .read_until(b'\n', &mut line)?
+            == 0
+        {
+            return Ok(());
+        }
+        if !line.ends_with(b"\r\n") {
+            writer.write_all(b"CLIENT_ERROR line too long or not terminated by CRLF\r\n")?;
+            return writer.flush();
+        }
+        line.truncate(line.len() - 2);
+
+        let line = String::from_utf8_lossy(&line);
+        let args = line.split_whitespace().collect::<Vec<_>>();
+        if args.first() == Some(&"quit") {
+            return writer.flush();
+        }
+
+        let reply = match execute(buffer, &args, &mut reader) {
+            Ok(Reply::Text(reply)) => reply,
+            Ok(Reply::Close(reply)) => {
+                writer.write_all(reply.as_bytes())?;
+                return writer.flush();
+            }
+            Err(err) => format!("SERVER_ERROR {}\r\n", err),
+        };
+        writer.write_all(reply.as_bytes())?;
+
+        // Pipelined commands are answered together
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+/// Reply to a command
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    /// Sent before reading the next command. Empty for `noreply`.
+    Text(String),
+    /// Sent before closing the connection, as the next command cannot be found in it
+    Close(String),
+}
+
+/// Run one command line against `buffer`, reading the data block of `set` from `reader`
+pub fn execute<R: BufRead>(buffer: &dyn Buffer, args: &[&str], reader: &mut R) -> Result<Reply> {
+    let (command, args) = match args.split_first() {
+        Some((command, args)) => (*command, args),
+        None => return Ok(Reply::Text("ERROR\r\n".to_string())),
+    };
+    let noreply = args.last() == Some(&"noreply");
+    let args = if noreply {
+        &args[..args.len() - 1]
+    } else {
+        args
+    };
+
+    let reply = match (command, args.len()) {
+        ("get", n) | ("gets", n) if n > 0 => get(buffer, args)?,
+        ("set", 4) => {
+            let bytes = match args[3].parse::<u64>() {
+                Ok(bytes) if bytes <= MAX_LEN as u64 => bytes as usize,
+                // Skipped like memcached does, so that the next command is found
+                Ok(bytes) => {
+                    io::copy(&mut reader.take(bytes + 2), &mut io::sink())?;
+                    return Ok(Reply::Text(
+                        "SERVER_ERROR object too large for cache\r\n".to_string(),
+                    ));
+                }
+                Err(_) => return Ok(Reply::Close(client_error("bad data chunk"))),
+            };
+            let mut data = vec![0; bytes + 2];
+            reader.read_exact(&mut data)?;
+            if !data.ends_with(b"\r\n") {
+                return Ok(Reply::Text(client_error("bad data chunk")));
+            }
+            data.truncate(bytes);
+
+            match (key(args[0]), args[2].parse(), parse::<u64>(&data)) {
+                (Some(key), Ok(exptime), Some(value)) => set(buffer, key, value, exptime)?,
+                (None, _, _) => client_error("key is not an integer or out of range"),
+                (_, Err(_), _) => client_error("bad command line format"),
+                (_, _, None) => client_error("value is not an integer or out of range"),
+            }
+        }
+        ("incr", 2) | ("decr", 2) => match (key(args[0]), args[1].parse::<u64>()) {
+            (Some(key), Ok(delta)) => incr(buffer, key, delta, command == "decr")?,
+            (None, _) => client_error("key is not an integer or out of range"),
+            (_, Err(_)) => client_error("invalid numeric delta argument"),
+        },
+        ("delete", 1) => match key(args[0]) {
+            Some(key) if buffer.delete(key)? => "DELETED\r\n".to_string(),
+            Some(_) => "NOT_FOUND\r\n".to_string(),
+            None => client_error("key is not an integer or out of range"),
+        },
+        ("stats", 0) => stats(buffer),
+        // Without its length the data block cannot be skipped
+        ("set", _) => return Ok(Reply::Close(client_error("bad command line format"))),
+        _ => "ERROR\r\n".to_string(),
+    };
+
+    Ok(Reply::Text(if noreply { String::new() } else { reply }))
+}
+
+fn get(buffer: &dyn Buffer, args: &[&str]) -> Result<String> {
+    let keys = match args.iter().map(|arg| key(arg)).collect::<Option<Vec<_>>>() {
+        Some(keys) => keys,
+        None => return Ok(client_error("key is not an integer or out of range")),
+    };
+
+    let mut reply = String::new();
+    for (key, value) in keys.iter().zip(buffer.get_many(&keys)?) {
+        if let Some(value) = value {
+            let value = value.to_string();
+            reply += &format!("VALUE {} 0 {}\r\n{}\r\n", key, value.len(), value);
+        }
+    }
+    reply += "END\r\n";
+    Ok(reply)
+}
+
+fn set(buffer: &dyn Buffer, key: u32, value: u64, exptime: i64) -> Result<String> {
+    if exptime == 0 {
+        buffer.put(key, value)?;
+    } else {
+        let ttl = if exptime > MAX_RELATIVE_EXPTIME {
+            exptime.saturating_mul(1000) - ttl::now() as i64
+        } else {
+            exptime.saturating_mul(1000)
+        };
+        // Already expired, as memcached does with a negative or past expiry time
+        if ttl <= 0 {
+            buffer.delete(key)?;
+        } else {
+            buffer.put_with_ttl(key, value, Duration::from_millis(ttl as u64))?;
+        }
+    }
+    Ok("STORED\r\n".to_string())
+}
+
+/// Like memcached, `incr` wraps around at 64 bits and `decr` stops at 0. The expiry is kept.
+fn incr(buffer: &dyn Buffer, key: u32, delta: u64, decr: bool) -> Result<String> {
+    let mut entry = buffer.lock(key)?;
+    let prev = match *entry.get()? {
+        Some(prev) => prev,
+        None => return Ok("NOT_FOUND\r\n".to_string()),
+    };
+
+    let next = if decr {
+        prev.saturating_sub(delta)
+    } else {
+        prev.wrapping_add(delta)
+    };
+    *entry.get_mut()? = Some(next);
+    Ok(format!("{}\r\n", next))
+}
+
+/// Hits and misses are those of the cache, not of `get` commands
+fn stats(buffer: &dyn Buffer) -> String {
+    let stats = buffer.stats();
+    let mut reply = String::new();
+    for &(name, value) in &[
+        ("get_hits", stats.hits),
+        ("get_misses", stats.misses),
+        ("evictions", stats.evictions),
+        ("expired_unfetched", stats.expirations),
+        ("dirty_items", stats.dirty_entries),
+    ] {
+        reply += &format!("STAT {} {}\r\n", name, value);
+    }
+    reply += "END\r\n";
+    reply
+}
+
+fn key(arg: &str) -> Option<u32> {
+    arg.parse().ok()
+}
+
+fn parse<T: str::FromStr>(bytes: &[u8]) -> Option<T> {
+    str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+fn client_error(message: &str) -> String {
+    format!("CLIENT_ERROR {}\r\n", message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor};
+
+    use buffer::test_buffer;
+
+    use super::*;
+
+    #[test]
+    fn execute_commands() {
+        let buffer = Arc::new(test_buffer());
+        let run = |line: &str, data: &[u8]| {
+            let args = line.split(' ').collect::<Vec<_>>();
+            match execute(&*buffer, &args, &mut Cursor::new(data)).unwrap() {
+                Reply::Text(reply) => reply,
+                Reply::Close(reply) => panic!("closed after {}", reply),
+            }
+        };
+
+        assert_eq!(run("get 1", b""), "END\r\n");
+        assert_eq!(run("set 1 0 0 2", b"10\r\n"), "STORED\r\n");
+        assert_eq!(run("set 2 0 60 1 noreply", b"5\r\n"), "");
+        assert!(buffer.ttl(2).unwrap().is_some());
+        assert_eq!(
+            run("get 1 2 3", b""),
+            "VALUE 1 0 2\r\n10\r\nVALUE 2 0 1\r\n5\r\nEND\r\n"
+        );
+
+        assert_eq!(run("incr 1 5", b""), "15\r\n");
+        assert_eq!(run("decr 1 20", b""), "0\r\n");
+        assert_eq!(run("incr 3 1", b""), "NOT_FOUND\r\n");
+        assert_eq!(run("delete 1", b""), "DELETED\r\n");
+        assert_eq!(run("delete 1", b""), "NOT_FOUND\r\n");
+
+        assert_eq!(
+            run("set 1 0 0 1", b"x\r\n"),
+            "CLIENT_ERROR value is not an integer or out of range\r\n"
+        );
+        assert_eq!(
+            run("set 1 0 0 1", b"1xx"),
+            "CLIENT_ERROR bad data chunk\r\n"
+        );
+        assert_eq!(run("set 1 0 -1 1", b"1\r\n"), "STORED\r\n");
+        assert_eq!(buffer.get(1).unwrap(), None);
+        assert_eq!(run("flush_all", b""), "ERROR\r\n");
+    }
+
+    #[test]
+    fn stats_from_cache() {
+        let buffer = Arc::new(test_buffer());
+        buffer.put(1, 1).unwrap();
+        buffer.get(1).unwrap();
+
+        let args = ["stats"];
+        let reply = match execute(&*buffer, &args, &mut io::empty()).unwrap() {
+            Reply::Text(reply) => reply,
+            Reply::Close(reply) => panic!("closed after {}", reply),
+        };
+        assert!(reply.contains("STAT get_hits 1\r\nSTAT get_misses 1\r\n"));
+        assert!(reply.ends_with("END\r\n"));
+    }
+
+    #[test]
+    fn serve_clients() {
+        let buffer = Arc::new(test_buffer());
+        let addr = spawn("127.0.0.1:0", buffer.clone()).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"set 7 0 0 2\r\n42\r\nincr 7 1\r\nget 7\r\nquit\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert_eq!(response, "STORED\r\n43\r\nVALUE 7 0 2\r\n43\r\nEND\r\n");
+    }
+
+    #[test]
+    fn skip_rejected_data_blocks() {
+        let buffer = Arc::new(test_buffer());
+        let addr = spawn("127.0.0.1:0", buffer.clone()).unwrap();
+
+        // The value of a set too large is skipped, not run as commands
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut request = format!("set 7 0 0 {}\r\n", MAX_LEN + 1).into_bytes();
+        request.extend(b"get 7\r\n".iter().cycle().take(MAX_LEN + 1));
+        request.extend_from_slice(b"\r\nget 7\r\nquit\r\n");
+        stream.write_all(&request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(
+            response,
+            "SERVER_ERROR object too large for cache\r\nEND\r\n"
+        );
+
+        // Without a length there is no telling where the value ends
+        for request in &["set 7 0 0 x\r\nget 7\r\n", "set 7 0 0\r\nget 7\r\n"] {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("CLIENT_ERROR"), "{}", response);
+            assert!(!response.contains("END"), "{}", response);
+        }
+    }
+}
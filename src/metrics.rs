@@ -0,0 +1,197 @@
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufReader};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use buffer::Buffer;
+use http::{self, Response};
+use server;
+use stats::Stats;
+
+/// Render `stats` in the Prometheus text exposition format
+pub fn render(stats: &Stats) -> String {
+    let mut out = String::new();
+
+    counter(
+        &mut out,
+        "cache_hits_total",
+        "Cache lookups that found the key",
+        stats.hits,
+    );
+    counter(
+        &mut out,
+        "cache_misses_total",
+        "Cache lookups that loaded the key",
+        stats.misses,
+    );
+    counter(
+        &mut out,
+        "cache_evictions_total",
+        "Entries evicted from the cache",
+        stats.evictions,
+    );
+    gauge(
+        &mut out,
+        "cache_hit_ratio",
+        "Ratio of hits to lookups",
+        stats.hit_ratio(),
+    );
+    gauge(
+        &mut out,
+        "cache_dirty_entries",
+        "Entries not yet written to storage",
+        stats.dirty_entries as f64,
+    );
+    counter(
+        &mut out,
+        "buffer_write_backs_total",
+        "Dirty entries written back on eviction",
+        stats.write_backs,
+    );
+    counter(
+        &mut out,
+        "storage_reads_total",
+        "Values read from storage",
+        stats.storage_reads,
+    );
+    counter(
+        &mut out,
+        "storage_writes_total",
+        "Values written to storage",
+        stats.storage_writes,
+    );
+    counter(
+        &mut out,
+        "buffer_expirations_total",
+        "Values deleted because they expired",
+        stats.expirations,
+    );
+    counter(
+        &mut out,
+        "buffer_lock_wait_seconds_total",
+        "Time spent waiting for cache entries",
+        seconds(stats.lock_wait_time),
+    );
+
+    let name = "piyokvs_buffer_sync_duration_seconds";
+    writeln!(out, "# HELP {} Time spent in sync", name).unwrap();
+    writeln!(out, "# TYPE {} summary", name).unwrap();
+    writeln!(out, "{}_sum {}", name, seconds(stats.sync_time)).unwrap();
+    writeln!(out, "{}_count {}", name, stats.syncs).unwrap();
+
+    out
+}
+
+fn counter<T: ToString>(out: &mut String, name: &str, help: &str, value: T) {
+    metric(out, name, "counter", help, value.to_string());
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: f64) {
+    metric(out, name, "gauge", help, value.to_string());
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: String) {
+    writeln!(out, "# HELP piyokvs_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE piyokvs_{} {}", name, kind).unwrap();
+    writeln!(out, "piyokvs_{} {}", name, value).unwrap();
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+/// Where the server serves metrics unless told otherwise
+pub const DEFAULT_ADDR: &str = "127.0.0.1:9100";
+
+/// Serve `GET /metrics` for `buffer` on `addr` from background threads, one per connection.
+/// Returns the bound address. Metrics are for the host only, so `addr` must be a loopback
+/// address.
+pub fn spawn<A>(addr: A, buffer: Arc<dyn Buffer + Send + Sync>) -> io::Result<SocketAddr>
+where
+    A: ToSocketAddrs,
+{
+    let addrs = addr.to_socket_addrs()?.collect::<Vec<_>>();
+    if let Some(addr) = addrs.iter().find(|addr| !addr.ip().is_loopback()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a loopback address", addr),
+        ));
+    }
+    server::spawn(&addrs[..], buffer, handle)
+}
+
+fn handle(stream: TcpStream, buffer: &dyn Buffer) -> io::Result<()> {
+    let (status, body) = match http::read_request(&mut BufReader::new(stream.try_clone()?)) {
+        Ok(request) => match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/metrics") => ("200 OK", render(&buffer.stats())),
+            ("GET", _) => ("404 Not Found", String::new()),
+            _ => ("405 Method Not Allowed", String::new()),
+        },
+        Err(err) => ("400 Bad Request", err.to_string()),
+    };
+
+    let mut stream = stream;
+    Response::new(status, "text/plain; version=0.0.4", body).write_to(&mut stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use buffer::BufferImpl;
+    use cache::SingleCache;
+    use storage::StorageMock;
+
+    use super::*;
+
+    #[test]
+    fn render_stats() {
+        let stats = Stats {
+            hits: 3,
+            misses: 1,
+            syncs: 2,
+            sync_time: Duration::from_millis(500),
+            ..Default::default()
+        };
+
+        let text = render(&stats);
+        assert!(
+            text.contains("# TYPE piyokvs_cache_hits_total counter\npiyokvs_cache_hits_total 3\n")
+        );
+        assert!(text.contains("piyokvs_cache_hit_ratio 0.75\n"));
+        assert!(text.contains("piyokvs_buffer_sync_duration_seconds_sum 0.5\n"));
+        assert!(text.contains("piyokvs_buffer_sync_duration_seconds_count 2\n"));
+    }
+
+    #[test]
+    fn serve_metrics() {
+        let cache = Box::new(SingleCache::new());
+        let storage = Box::new(StorageMock::new());
+        let buffer = Arc::new(BufferImpl::new(cache, storage));
+        *buffer.lock(1).unwrap().get_mut().unwrap() = Some(1);
+
+        let err = spawn("0.0.0.0:0", buffer.clone()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let addr = spawn("127.0.0.1:0", buffer).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("piyokvs_cache_misses_total 1\n"));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nContent-Length: x\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+}
@@ -0,0 +1,349 @@
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use error::Result;
+use stats::Stats;
+use store::Store;
+use wire::{self, Request, Response};
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl Connection {
+    fn open(addrs: &[SocketAddr]) -> io::Result<Connection> {
+        let stream = TcpStream::connect(addrs)?;
+        stream.set_nodelay(true)?;
+        Ok(Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    fn exchange(&mut self, requests: &[Request]) -> Result<Vec<Response>> {
+        let mut buf = Vec::new();
+        for request in requests {
+            buf.clear();
+            request.encode(&mut buf);
+            wire::write_frame(&mut self.writer, &buf)?;
+        }
+        self.writer.flush()?;
+
+        let mut responses = Vec::with_capacity(requests.len());
+        for _ in requests {
+            let payload = wire::read_frame(&mut self.reader)?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by server")
+            })?;
+            responses.push(Response::decode(&payload)?);
+        }
+        Ok(responses)
+    }
+}
+
+/// Client of a server speaking the binary protocol of `wire`. Calls from several threads share
+/// the connection one at a time.
+pub struct RemoteClient {
+    addrs: Vec<SocketAddr>,
+    /// `None` once an exchange failed partway, since unread responses would otherwise be taken
+    /// for the answers to later requests. The next call connects again.
+    conn: Mutex<Option<Connection>>,
+}
+
+impl RemoteClient {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<RemoteClient> {
+        let addrs = addr.to_socket_addrs()?.collect::<Vec<_>>();
+        let conn = Connection::open(&addrs)?;
+        Ok(RemoteClient {
+            addrs,
+            conn: Mutex::new(Some(conn)),
+        })
+    }
+
+    /// Send all `requests` before reading any response, saving a round trip per request.
+    /// Failed requests come back as `Response::Error`.
+    pub fn pipeline(&self, requests: &[Request]) -> Result<Vec<Response>> {
+        // A panic mid-exchange leaves the connection in an unknown state, like an error does
+        let mut guard = self.conn.lock().unwrap_or_else(|err| {
+            let mut guard = err.into_inner();
+            *guard = None;
+            guard
+        });
+        let mut conn = match guard.take() {
+            Some(conn) => conn,
+            None => Connection::open(&self.addrs)?,
+        };
+        let responses = conn.exchange(requests)?;
+        *guard = Some(conn);
+        Ok(responses)
+    }
+
+    /// Present keys from `first` on and their values, at most `limit` of them and no more than
+    /// `wire::MAX_SCAN_LEN`. Fewer than asked for means the scan reached the last key.
+    pub fn scan(&self, first: u32, limit: u32) -> Result<Vec<(u32, u64)>> {
+        match self.call(Request::Scan(first, limit))? {
+            Response::Pairs(pairs) => Ok(pairs),
+            response => Err(unexpected(response).into()),
+        }
+    }
+
+    pub fn stats(&self) -> Result<Stats> {
+        match self.call(Request::Stats)? {
+            Response::Stats(stats) => Ok(stats),
+            response => Err(unexpected(response).into()),
+        }
+    }
+
+    fn call(&self, request: Request) -> Result<Response> {
+        let response = self.pipeline(&[request])?.pop().unwrap();
+        wire::check(response)
+    }
+
+    /// Run one of the fetch operations, which return the previous value
+    fn fetch(&self, request: Request) -> Result<u64> {
+        match self.call(request)? {
+            Response::Integer(prev) => Ok(prev),
+            response => Err(unexpected(response).into()),
+        }
+    }
+}
+
+fn unexpected(response: Response) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected response {:?}", response),
+    )
+}
+
+impl Store for RemoteClient {
+    fn get(&self, key: u32) -> Result<Option<u64>> {
+        match self.call(Request::Get(key))? {
+            Response::Value(value) => Ok(value),
+            response => Err(unexpected(response).into()),
+        }
+    }
+
+    fn put(&self, key: u32, value: u64) -> Result<()> {
+        match self.call(Request::Put(key, value))? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response).into()),
+        }
+    }
+
+    fn put_with_ttl(&self, key: u32, value: u64, ttl: Duration) -> Result<()> {
+        match self.call(Request::PutWithTtl(key, value, ttl))? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response).into()),
+        }
+    }
+
+    fn expire(&self, key: u32, ttl: Duration) -> Result<bool> {
+        match self.call(Request::Expire(key, ttl))? {
+            Response::Bool(found) => Ok(found),
+            response => Err(unexpected(response).into()),
+        }
+    }
+
+    fn ttl(&self, key: u32) -> Result<Option<Duration>> {
+        match self.call(Request::Ttl(key))? {
+            Response::Ttl(ttl) => Ok(ttl),
+            response => Err(unexpected(response).into()),
+        }
+    }
+
+    fn delete(&self, key: u32) -> Result<bool> {
+        match self.call(Request::Delete(key))? {
+            Response::Bool(deleted) => Ok(deleted),
+            response => Err(unexpected(response).into()),
+        }
+    }
+
+    fn get_versioned(&self, key: u32) -> Result<(Option<u64>, u64)> {
+        match self.call(Request::GetVersioned(key))? {
+            Response::Versioned(value, version) => Ok((value, version)),
+            response => Err(unexpected(response).into()),
+        }
+    }
+
+    fn compare_and_swap(&self, key: u32, expected_version: u64, value: u64) -> Result<Option<u64>> {
+        match self.call(Request::CompareAndSwap(key, expected_version, value))? {
+            Response::Value(version) => Ok(version),
+            response => Err(unexpected(response).into()),
+        }
+    }
+
+    /// Split into requests of at most `wire::MAX_GET_MANY_LEN` keys, sent together
+    fn get_many(&self, keys: &[u32]) -> Result<Vec<Option<u64>>> {
+        let requests = keys
+            .chunks(wire::MAX_GET_MANY_LEN)
+            .map(|keys| Request::GetMany(keys.to_vec()))
+            .collect::<Vec<_>>();
+        let mut values = Vec::with_capacity(keys.len());
+        for response in self.pipeline(&requests)? {
+            match wire::check(response)? {
+                Response::Values(chunk) => values.extend(chunk),
+                response => return Err(unexpected(response).into()),
+            }
+        }
+        Ok(values)
+    }
+
+    fn put_many(&self, writes: &[(u32, u64)]) -> Result<()> {
+        match self.call(Request::PutMany(writes.to_vec()))? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response).into()),
+        }
+    }
+
+    fn fetch_add(&self, key: u32, delta: u64) -> Result<u64> {
+        self.fetch(Request::FetchAdd(key, delta))
+    }
+
+    fn fetch_sub(&self, key: u32, delta: u64) -> Result<u64> {
+        self.fetch(Request::FetchSub(key, delta))
+    }
+
+    fn saturating_fetch_add(&self, key: u32, delta: u64) -> Result<u64> {
+        self.fetch(Request::SaturatingFetchAdd(key, delta))
+    }
+
+    fn saturating_fetch_sub(&self, key: u32, delta: u64) -> Result<u64> {
+        self.fetch(Request::SaturatingFetchSub(key, delta))
+    }
+
+    fn fetch_max(&self, key: u32, value: u64) -> Result<u64> {
+        self.fetch(Request::FetchMax(key, value))
+    }
+
+    fn fetch_min(&self, key: u32, value: u64) -> Result<u64> {
+        self.fetch(Request::FetchMin(key, value))
+    }
+
+    fn sync(&self) -> Result<()> {
+        match self.call(Request::Sync)? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    use buffer::{test_buffer, Buffer, BufferImpl};
+    use client::Client;
+
+    use super::*;
+
+    fn connect() -> (Arc<BufferImpl>, RemoteClient) {
+        let buffer = Arc::new(test_buffer());
+        let addr = wire::spawn("127.0.0.1:0", buffer.clone()).unwrap();
+        (buffer, RemoteClient::connect(addr).unwrap())
+    }
+
+    #[test]
+    fn remote_operations() {
+        let (buffer, client) = connect();
+
+        client.put(1, 10).unwrap();
+        assert_eq!(client.fetch_add(1, 5).unwrap(), 10);
+        assert_eq!(client.get(1).unwrap(), Some(15));
+        client.put_with_ttl(2, 20, Duration::from_secs(60)).unwrap();
+        client.put_many(&[(3, 30), (4, 40)]).unwrap();
+        assert_eq!(
+            client.get_many(&[1, 2, 3, 5]).unwrap(),
+            vec![Some(15), Some(20), Some(30), None]
+        );
+        assert!(client.delete(4).unwrap());
+        assert!(!client.delete(4).unwrap());
+        client.sync().unwrap();
+        assert_eq!(client.scan(2, 10).unwrap(), vec![(2, 20), (3, 30)]);
+        assert_eq!(client.scan(0, 1).unwrap(), vec![(1, 15)]);
+
+        assert!(Buffer::ttl(&*buffer, 2).unwrap().is_some());
+        assert!(client.ttl(2).unwrap().is_some());
+        assert!(client.expire(3, Duration::from_secs(60)).unwrap());
+        assert!(!client.expire(4, Duration::from_secs(60)).unwrap());
+        assert_eq!(client.ttl(1).unwrap(), None);
+
+        let (value, version) = client.get_versioned(1).unwrap();
+        assert_eq!(value, Some(15));
+        let swapped = client.compare_and_swap(1, version, 16).unwrap();
+        assert!(swapped.is_some());
+        assert_eq!(client.compare_and_swap(1, version, 17).unwrap(), None);
+        assert_eq!(client.fetch_sub(1, 6).unwrap(), 16);
+        assert_eq!(client.saturating_fetch_sub(1, 100).unwrap(), 10);
+        assert_eq!(client.saturating_fetch_add(1, u64::MAX).unwrap(), 0);
+        assert_eq!(client.fetch_min(1, 3).unwrap(), u64::MAX);
+        assert_eq!(client.fetch_max(1, 7).unwrap(), 3);
+        assert_eq!(client.get(1).unwrap(), Some(7));
+        assert_eq!(
+            client
+                .get_many(&vec![1; wire::MAX_GET_MANY_LEN + 1])
+                .unwrap()
+                .len(),
+            wire::MAX_GET_MANY_LEN + 1
+        );
+        assert_eq!(Buffer::stats(&*buffer).syncs, 1);
+        assert_eq!(client.stats().unwrap().syncs, 1);
+    }
+
+    #[test]
+    fn pipelined_requests() {
+        let (_, client) = connect();
+
+        let requests = (0..100)
+            .map(|i| Request::FetchAdd(i % 10, 1))
+            .collect::<Vec<_>>();
+        let responses = client.pipeline(&requests).unwrap();
+        assert_eq!(responses[0], Response::Integer(0));
+        assert_eq!(responses[99], Response::Integer(9));
+    }
+
+    #[test]
+    fn shared_client() {
+        let (buffer, client) = connect();
+        let client: Arc<dyn Store + Send + Sync> = Arc::new(client);
+
+        let threads = (0..4)
+            .map(|_| {
+                let client = client.clone();
+                thread::spawn(move || Client::new(client).start(10, 100))
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let values = Buffer::get_many(&*buffer, &(0..10).collect::<Vec<_>>()).unwrap();
+        assert_eq!(values.into_iter().map(|v| v.unwrap_or(0)).sum::<u64>(), 400);
+    }
+
+    #[test]
+    fn reconnect_after_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = RemoteClient::connect(listener.local_addr().unwrap()).unwrap();
+
+        // The first connection answers with an undecodable frame followed by a stray response
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut stray = Vec::new();
+        Response::Value(Some(99)).encode(&mut stray);
+        thread::spawn(move || {
+            wire::read_frame(&mut stream).unwrap();
+            wire::write_frame(&mut stream, &[0xff]).unwrap();
+            wire::write_frame(&mut stream, &stray).unwrap();
+            let buffer: Arc<dyn Buffer + Send + Sync> = Arc::new(test_buffer());
+            buffer.put(1, 10).unwrap();
+            wire::serve(&listener, &buffer);
+        });
+
+        assert!(client.get(1).is_err());
+        assert_eq!(client.get(1).unwrap(), Some(10));
+    }
+}
@@ -0,0 +1,410 @@
+use std::cmp;
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::str;
+use std::sync::Arc;
+use std::time::Duration;
+
+use buffer::Buffer;
+use server;
+
+/// Longest bulk string or array a client may send, so that a bad length cannot exhaust memory
+const MAX_LEN: usize = 1 << 20;
+
+/// Most arguments reserved for before they are read
+const MAX_PREALLOCATED_ARGS: usize = 64;
+
+/// A reply in the Redis serialization protocol
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    /// `None` is the null bulk string
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn error(message: &str) -> Reply {
+        Reply::Error(format!("ERR {}", message))
+    }
+
+    fn value(value: Option<u64>) -> Reply {
+        Reply::Bulk(value.map(|value| value.to_string().into_bytes()))
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match *self {
+            Reply::Simple(ref s) => write!(out, "+{}\r\n", s),
+            Reply::Error(ref s) => write!(out, "-{}\r\n", s),
+            Reply::Integer(n) => write!(out, ":{}\r\n", n),
+            Reply::Bulk(None) => write!(out, "$-1\r\n"),
+            Reply::Bulk(Some(ref bytes)) => {
+                write!(out, "${}\r\n", bytes.len())?;
+                out.write_all(bytes)?;
+                out.write_all(b"\r\n")
+            }
+            Reply::Array(ref items) => {
+                write!(out, "*{}\r\n", items.len())?;
+                for item in items {
+                    item.write_to(out)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Read the next command, either an array of bulk strings or an inline command. Returns `None`
+/// at the end of the stream.
+pub fn read_command<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+
+        if line.first() != Some(&b'*') {
+            let args = line
+                .split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| arg.to_vec())
+                .collect::<Vec<_>>();
+            // Blank lines are skipped like Redis does
+            if args.is_empty() {
+                continue;
+            }
+            return Ok(Some(args));
+        }
+
+        let n_args = parse_len(&line[1..])?;
+        // Like a blank line
+        if n_args == 0 {
+            continue;
+        }
+        // The length is not trusted until the arguments arrive
+        let mut args = Vec::with_capacity(cmp::min(n_args, MAX_PREALLOCATED_ARGS));
+        for _ in 0..n_args {
+            let header = read_line(reader)?.ok_or_else(eof)?;
+            if header.first() != Some(&b'$') {
+                return Err(invalid("expected a bulk string"));
+            }
+            let len = parse_len(&header[1..])?;
+
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg)?;
+            if !arg.ends_with(b"\r\n") {
+                return Err(invalid("bulk string not terminated by CRLF"));
+            }
+            arg.truncate(len);
+            args.push(arg);
+        }
+        return Ok(Some(args));
+    }
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader.take(MAX_LEN as u64).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(invalid("line too long"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(bytes: &[u8]) -> io::Result<usize> {
+    match parse::<usize>(bytes) {
+        Some(len) if len <= MAX_LEN => Ok(len),
+        _ => Err(invalid("invalid length")),
+    }
+}
+
+fn parse<T: str::FromStr>(bytes: &[u8]) -> Option<T> {
+    str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn eof() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "connection closed inside a command",
+    )
+}
+
+/// Run one command against `buffer`. Keys are `u32` and values `u64`, both written in decimal.
+pub fn execute(buffer: &dyn Buffer, args: &[Vec<u8>]) -> Reply {
+    let (name, args) = match args.split_first() {
+        Some((name, args)) => (String::from_utf8_lossy(name).to_ascii_uppercase(), args),
+        None => return Reply::error("empty command"),
+    };
+
+    let arity = match name.as_str() {
+        "PING" => args.len() <= 1,
+        "GET" | "INCR" => args.len() == 1,
+        "SET" => args.len() == 2 || args.len() == 4,
+        "INCRBY" => args.len() == 2,
+        "DEL" | "MGET" => !args.is_empty(),
+        _ => return Reply::error(&format!("unknown command '{}'", name)),
+    };
+    if !arity {
+        return Reply::error(&format!(
+            "wrong number of arguments for '{}' command",
+            name.to_ascii_lowercase()
+        ));
+    }
+
+    match run(buffer, &name, args) {
+        Ok(reply) => reply,
+        Err(reply) => reply,
+    }
+}
+
+fn run(buffer: &dyn Buffer, name: &str, args: &[Vec<u8>]) -> Result<Reply, Reply> {
+    let reply = match name {
+        "PING" => match args.first() {
+            Some(message) => Reply::Bulk(Some(message.clone())),
+            None => Reply::Simple("PONG".to_string()),
+        },
+        "GET" => Reply::value(buffer.get(key(&args[0])?).map_err(failed)?),
+        "SET" => {
+            let (key, value) = (key(&args[0])?, integer::<u64>(&args[1])?);
+            if args.len() == 4 {
+                let ttl = integer::<u64>(&args[3])?;
+                if ttl == 0 {
+                    return Err(Reply::error("invalid expire time in 'set' command"));
+                }
+                let unit = String::from_utf8_lossy(&args[2]).to_ascii_uppercase();
+                let ttl = match unit.as_str() {
+                    "EX" => Duration::from_secs(ttl),
+                    "PX" => Duration::from_millis(ttl),
+                    _ => return Err(Reply::error("syntax error")),
+                };
+                buffer.put_with_ttl(key, value, ttl).map_err(failed)?;
+            } else {
+                buffer.put(key, value).map_err(failed)?;
+            }
+            Reply::Simple("OK".to_string())
+        }
+        "INCR" => incr_by(buffer, key(&args[0])?, 1)?,
+        "INCRBY" => incr_by(buffer, key(&args[0])?, integer::<i64>(&args[1])?)?,
+        "DEL" => {
+            let mut n_deleted = 0;
+            for arg in args {
+                if buffer.delete(key(arg)?).map_err(failed)? {
+                    n_deleted += 1;
+                }
+            }
+            Reply::Integer(n_deleted)
+        }
+        "MGET" => {
+            let keys = args
+                .iter()
+                .map(|arg| key(arg))
+                .collect::<Result<Vec<_>, _>>()?;
+            let values = buffer.get_many(&keys).map_err(failed)?;
+            Reply::Array(values.into_iter().map(Reply::value).collect())
+        }
+        _ => unreachable!(),
+    };
+    Ok(reply)
+}
+
+/// Add `delta`, refusing to leave the `u64` range like Redis refuses to leave `i64`. On
+/// overflow nothing is written, so a key without a value stays without one.
+fn incr_by(buffer: &dyn Buffer, key: u32, delta: i64) -> Result<Reply, Reply> {
+    let mut entry = buffer.lock(key).map_err(failed)?;
+    let prev = entry.get().map_err(failed)?.unwrap_or(0);
+    let next = if delta >= 0 {
+        prev.checked_add(delta as u64)
+    } else {
+        prev.checked_sub(delta.unsigned_abs())
+    };
+    let next = next.ok_or_else(|| Reply::error("increment or decrement would overflow"))?;
+    *entry.get_mut().map_err(failed)? = Some(next);
+
+    // Replies are signed, so larger values are sent as bulk strings
+    Ok(if next <= i64::MAX as u64 {
+        Reply::Integer(next as i64)
+    } else {
+        Reply::value(Some(next))
+    })
+}
+
+fn key(arg: &[u8]) -> Result<u32, Reply> {
+    parse(arg).ok_or_else(|| Reply::error("key is not an integer or out of range"))
+}
+
+fn integer<T: str::FromStr>(arg: &[u8]) -> Result<T, Reply> {
+    parse(arg).ok_or_else(|| Reply::error("value is not an integer or out of range"))
+}
+
+fn failed<E: ToString>(err: E) -> Reply {
+    Reply::error(&err.to_string())
+}
+
+/// Serve RESP clients for `buffer` on `addr` from background threads, one per connection.
+/// Returns the bound address.
+pub fn spawn<A>(addr: A, buffer: Arc<dyn Buffer + Send + Sync>) -> io::Result<SocketAddr>
+where
+    A: ToSocketAddrs,
+{
+    server::spawn(addr, buffer, handle)
+}
+
+/// Accept connections on `listener` like `server::serve`
+pub fn serve(listener: &TcpListener, buffer: &Arc<dyn Buffer + Send + Sync>) {
+    server::serve(listener, buffer, handle)
+}
+
+fn handle(stream: TcpStream, buffer: &dyn Buffer) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                // Redis also closes the connection on a protocol error
+                Reply::error(&format!("Protocol error: {}", err)).write_to(&mut writer)?;
+                return writer.flush();
+            }
+        };
+        execute(buffer, &args).write_to(&mut writer)?;
+
+        // Pipelined commands are answered together
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use buffer::test_buffer;
+
+    use super::*;
+
+    fn command(line: &str) -> Vec<Vec<u8>> {
+        line.split(' ').map(|arg| arg.as_bytes().to_vec()).collect()
+    }
+
+    fn encode(reply: &Reply) -> String {
+        let mut out = Vec::new();
+        reply.write_to(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn parse_commands() {
+        let mut input =
+            Cursor::new(&b"*2\r\n$3\r\nGET\r\n$2\r\n42\r\n\r\nset  1 2\r\n*1\r\n$4\r\nPI"[..]);
+        assert_eq!(read_command(&mut input).unwrap(), Some(command("GET 42")));
+        assert_eq!(read_command(&mut input).unwrap(), Some(command("set 1 2")));
+        assert!(read_command(&mut input).is_err());
+        assert_eq!(read_command(&mut input).unwrap(), None);
+
+        let mut input = Cursor::new(&b"*1\r\n$-1\r\n"[..]);
+        assert!(read_command(&mut input).is_err());
+
+        // Empty arrays are skipped like blank lines
+        let mut input = Cursor::new(&b"*0\r\n*1\r\n$4\r\nPING\r\n*0\r\n"[..]);
+        assert_eq!(read_command(&mut input).unwrap(), Some(command("PING")));
+        assert_eq!(read_command(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn encode_replies() {
+        let reply = Reply::Array(vec![
+            Reply::Bulk(Some(b"1".to_vec())),
+            Reply::Bulk(None),
+            Reply::Integer(-3),
+            Reply::Simple("OK".to_string()),
+            Reply::error("oops"),
+        ]);
+        assert_eq!(
+            encode(&reply),
+            "*5\r\n$1\r\n1\r\n$-1\r\n:-3\r\n+OK\r\n-ERR oops\r\n"
+        );
+    }
+
+    #[test]
+    fn execute_commands() {
+        let buffer = Arc::new(test_buffer());
+        let run = |line: &str| execute(&*buffer, &command(line));
+
+        assert_eq!(run("PING"), Reply::Simple("PONG".to_string()));
+        assert_eq!(run("get 1"), Reply::Bulk(None));
+        assert_eq!(run("SET 1 10"), Reply::Simple("OK".to_string()));
+        assert_eq!(run("GET 1"), Reply::Bulk(Some(b"10".to_vec())));
+        assert_eq!(run("INCR 1"), Reply::Integer(11));
+        assert_eq!(run("INCRBY 1 -11"), Reply::Integer(0));
+        assert_eq!(
+            run("INCRBY 1 -1"),
+            Reply::error("increment or decrement would overflow")
+        );
+        assert_eq!(
+            run("INCRBY 2 -5"),
+            Reply::error("increment or decrement would overflow")
+        );
+        assert_eq!(run("GET 2"), Reply::Bulk(None));
+        assert_eq!(run("INCRBY 2 5"), Reply::Integer(5));
+        assert_eq!(
+            run("MGET 1 2 3"),
+            Reply::Array(vec![
+                Reply::Bulk(Some(b"0".to_vec())),
+                Reply::Bulk(Some(b"5".to_vec())),
+                Reply::Bulk(None),
+            ])
+        );
+        assert_eq!(run("DEL 1 2 3"), Reply::Integer(2));
+        assert_eq!(run("GET 2"), Reply::Bulk(None));
+
+        assert_eq!(run("SET 3 1 PX 60000"), Reply::Simple("OK".to_string()));
+        assert!(buffer.ttl(3).unwrap().is_some());
+
+        assert_eq!(
+            run("SET 1 x"),
+            Reply::error("value is not an integer or out of range")
+        );
+        assert_eq!(
+            run("GET 1 2"),
+            Reply::error("wrong number of arguments for 'get' command")
+        );
+        assert_eq!(run("FLUSHALL"), Reply::error("unknown command 'FLUSHALL'"));
+        assert_eq!(execute(&*buffer, &[]), Reply::error("empty command"));
+    }
+
+    #[test]
+    fn serve_clients() {
+        let buffer = Arc::new(test_buffer());
+        let addr = spawn("127.0.0.1:0", buffer.clone()).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"*3\r\n$3\r\nSET\r\n$1\r\n7\r\n$2\r\n42\r\nINCR 7\r\nGET 7\r\n")
+            .unwrap();
+
+        let mut reader = BufReader::new(stream);
+        let mut lines = Vec::new();
+        for _ in 0..4 {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            lines.push(line);
+        }
+        assert_eq!(lines, vec!["+OK\r\n", ":43\r\n", "$2\r\n", "43\r\n"]);
+        assert_eq!(buffer.get(7).unwrap(), Some(43));
+    }
+}
@@ -0,0 +1,112 @@
+use std::cmp;
+use std::vec;
+
+use buffer::BufferImpl;
+use error::Result;
+
+/// Keys read from storage at a time
+pub(crate) const CHUNK_LEN: u32 = 1024;
+
+/// Iterator over present keys and their values in ascending order, leaving out expired ones.
+/// Values are read through the cache without loading into it, so a scan does not evict the
+/// working set. It is not a snapshot: each value is the one current when its chunk was read.
+pub struct Scan<'a> {
+    buffer: &'a BufferImpl,
+    next: u32,
+    end: u32,
+    chunk: vec::IntoIter<(u32, u64)>,
+}
+
+impl<'a> Scan<'a> {
+    pub fn new(buffer: &'a BufferImpl) -> Scan<'a> {
+        Scan {
+            buffer,
+            next: 0,
+            end: buffer.n_keys(),
+            chunk: Vec::new().into_iter(),
+        }
+    }
+
+    /// Skip the keys before `first`
+    pub fn starting_at(mut self, first: u32) -> Scan<'a> {
+        self.chunk = Vec::new().into_iter();
+        self.next = cmp::min(first, self.end);
+        self
+    }
+
+    fn read_chunk(&mut self) -> Result<()> {
+        let first = self.next;
+        let len = cmp::min(CHUNK_LEN, self.end - first);
+        let (values, _) = self.buffer.peek_range(first, len as usize, false)?;
+        self.next += len;
+
+        self.chunk = values
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, value)| value.map(|value| (first + i as u32, value)))
+            .collect::<Vec<_>>()
+            .into_iter();
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for Scan<'a> {
+    type Item = Result<(u32, u64)>;
+
+    fn next(&mut self) -> Option<Result<(u32, u64)>> {
+        loop {
+            if let Some(item) = self.chunk.next() {
+                return Some(Ok(item));
+            }
+            if self.next == self.end {
+                return None;
+            }
+            if let Err(err) = self.read_chunk() {
+                self.next = self.end;
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use buffer::{test_buffer, Buffer};
+
+    use super::*;
+
+    #[test]
+    fn scan_merges_cache_and_storage() {
+        let buffer = test_buffer();
+
+        for key in (0..3000).filter(|key| key % 3 == 0) {
+            buffer.put(key, key as u64).unwrap();
+        }
+        buffer.sync().unwrap();
+
+        // Changes still only in the cache
+        buffer.put(1, 1).unwrap();
+        buffer.delete(0).unwrap();
+        buffer.put(2997, 0).unwrap();
+
+        let stats = buffer.stats();
+        let items = buffer.scan().collect::<Result<Vec<_>>>().unwrap();
+
+        let mut expected = (3..2997)
+            .filter(|key| key % 3 == 0)
+            .map(|key| (key, key as u64))
+            .collect::<Vec<_>>();
+        expected.insert(0, (1, 1));
+        expected.push((2997, 0));
+        assert_eq!(items, expected);
+
+        // Nothing was loaded into the cache
+        let after = buffer.stats();
+        assert_eq!(after.hits, stats.hits);
+        assert_eq!(after.misses, stats.misses);
+        assert_eq!(after.storage_reads, stats.storage_reads + 3);
+
+        let items = buffer.scan().starting_at(2994).collect::<Result<Vec<_>>>();
+        assert_eq!(items.unwrap(), vec![(2994, 2994), (2997, 0)]);
+    }
+}
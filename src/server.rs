@@ -0,0 +1,133 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use buffer::Buffer;
+
+/// Most connections served at once by a listener. Others wait to be accepted.
+pub const MAX_CONNECTIONS: usize = 1024;
+
+/// How long a connection may go without a request or stall a reply before it is closed
+pub const TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Pause after failing to accept a connection, which usually fails again right away when it is
+/// for lack of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Serves one connection until it is closed
+pub type Handler = fn(TcpStream, &dyn Buffer) -> io::Result<()>;
+
+/// Serve `buffer` on `addr` with `handle` from background threads, one per connection. Returns
+/// the bound address.
+pub fn spawn<A>(
+    addr: A,
+    buffer: Arc<dyn Buffer + Send + Sync>,
+    handle: Handler,
+) -> io::Result<SocketAddr>
+where
+    A: ToSocketAddrs,
+{
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    thread::spawn(move || serve(&listener, &buffer, handle));
+    Ok(local_addr)
+}
+
+/// Accept connections on `listener` forever, handling each in its own thread with `TIMEOUT` for
+/// reads and writes, at most `MAX_CONNECTIONS` at a time
+pub fn serve(listener: &TcpListener, buffer: &Arc<dyn Buffer + Send + Sync>, handle: Handler) {
+    let connections = Arc::new(Connections::default());
+    loop {
+        connections.wait_for_room();
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(_) => {
+                thread::sleep(ACCEPT_BACKOFF);
+                continue;
+            }
+        };
+        if stream.set_read_timeout(Some(TIMEOUT)).is_err()
+            || stream.set_write_timeout(Some(TIMEOUT)).is_err()
+        {
+            continue;
+        }
+
+        let connection = Connection::open(&connections);
+        let buffer = buffer.clone();
+        thread::spawn(move || {
+            // A broken connection must not take the listener down
+            let _ = handle(stream, &*buffer);
+            drop(connection);
+        });
+    }
+}
+
+#[derive(Default)]
+struct Connections {
+    n_open: Mutex<usize>,
+    closed: Condvar,
+}
+
+impl Connections {
+    fn wait_for_room(&self) {
+        let mut n_open = self.n_open.lock().unwrap_or_else(PoisonError::into_inner);
+        while *n_open >= MAX_CONNECTIONS {
+            n_open = self
+                .closed
+                .wait(n_open)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+/// Counts as open until dropped, even if its thread panics
+struct Connection(Arc<Connections>);
+
+impl Connection {
+    fn open(connections: &Arc<Connections>) -> Connection {
+        *connections
+            .n_open
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) += 1;
+        Connection(connections.clone())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        *self.0.n_open.lock().unwrap_or_else(PoisonError::into_inner) -= 1;
+        self.0.closed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn limit_connections() {
+        let connections = Arc::new(Connections::default());
+        let mut open = (0..MAX_CONNECTIONS)
+            .map(|_| Connection::open(&connections))
+            .collect::<Vec<_>>();
+
+        let room = Arc::new(AtomicBool::new(false));
+        let waiter = {
+            let (connections, room) = (connections.clone(), room.clone());
+            thread::spawn(move || {
+                connections.wait_for_room();
+                room.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!room.load(Ordering::SeqCst));
+
+        open.pop();
+        waiter.join().unwrap();
+        assert!(room.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use buffer::BufferImpl;
+use error::Result;
+
+/// Commit timestamps and the old values that running snapshots may still read
+#[derive(Default)]
+pub struct Versions {
+    inner: Mutex<VersionsInner>,
+}
+
+#[derive(Default)]
+struct VersionsInner {
+    clock: u64,
+    /// Number of snapshots per timestamp
+    snapshots: BTreeMap<u64, usize>,
+    /// Transactions being applied, which snapshots must not see yet
+    pending: BTreeSet<u64>,
+    /// Per key, `(until, value)` pairs in ascending order: `value` was current before `until`
+    history: HashMap<u32, Vec<(u64, Option<u64>)>>,
+}
+
+impl VersionsInner {
+    /// Drop history that neither a snapshot nor a pending transaction needs
+    fn collect_garbage(&mut self) {
+        let oldest = self
+            .snapshots
+            .keys()
+            .next()
+            .cloned()
+            .into_iter()
+            .chain(self.pending.iter().map(|ts| ts - 1))
+            .min();
+
+        match oldest {
+            Some(oldest) => self.history.retain(|_, versions| {
+                versions.retain(|&(until, _)| until > oldest);
+                !versions.is_empty()
+            }),
+            None => self.history.clear(),
+        }
+    }
+}
+
+impl Versions {
+    fn inner(&self) -> MutexGuard<'_, VersionsInner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Timestamp of the latest commit
+    pub fn now(&self) -> u64 {
+        self.inner().clock
+    }
+
+    /// Record that `key` changed from `before`. Must be called while holding its entry. Returns
+    /// the commit timestamp.
+    pub fn commit(&self, key: u32, before: Option<u64>) -> u64 {
+        let mut inner = self.inner();
+        inner.clock += 1;
+        let ts = inner.clock;
+        if !inner.snapshots.is_empty() {
+            inner.history.entry(key).or_default().push((ts, before));
+        }
+        ts
+    }
+
+    /// Start applying a transaction, hiding it from snapshots until `end`
+    pub fn begin(&self) -> u64 {
+        let mut inner = self.inner();
+        inner.clock += 1;
+        let ts = inner.clock;
+        inner.pending.insert(ts);
+        ts
+    }
+
+    /// Record that `key` changed from `before` in the transaction `ts`
+    pub fn record(&self, key: u32, ts: u64, before: Option<u64>) {
+        self.inner()
+            .history
+            .entry(key)
+            .or_default()
+            .push((ts, before));
+    }
+
+    pub fn end(&self, ts: u64) {
+        let mut inner = self.inner();
+        inner.pending.remove(&ts);
+        inner.collect_garbage();
+    }
+
+    fn acquire(&self) -> u64 {
+        let mut inner = self.inner();
+        let ts = match inner.pending.iter().next() {
+            Some(pending) => pending - 1,
+            None => inner.clock,
+        };
+        *inner.snapshots.entry(ts).or_insert(0) += 1;
+        ts
+    }
+
+    fn release(&self, ts: u64) {
+        let mut inner = self.inner();
+        let remove = {
+            let count = inner.snapshots.get_mut(&ts).unwrap();
+            *count -= 1;
+            *count == 0
+        };
+        if remove {
+            inner.snapshots.remove(&ts);
+        }
+        inner.collect_garbage();
+    }
+
+    /// Value of `key` as of `ts`, if it changed since then
+    fn value_at(&self, key: u32, ts: u64) -> Option<Option<u64>> {
+        self.inner().history.get(&key).and_then(|versions| {
+            versions
+                .iter()
+                .find(|&&(until, _)| until > ts)
+                .map(|&(_, value)| value)
+        })
+    }
+}
+
+/// Consistent point-in-time view of the buffer. Reads only see commits made before it was taken.
+pub struct Snapshot<'a> {
+    buffer: &'a BufferImpl,
+    ts: u64,
+}
+
+impl<'a> Snapshot<'a> {
+    pub fn new(buffer: &'a BufferImpl) -> Snapshot<'a> {
+        Snapshot {
+            buffer,
+            ts: buffer.versions().acquire(),
+        }
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.ts
+    }
+
+    /// Read without loading `key` into the cache, so snapshots neither evict the working set nor
+    /// wait for the cache, only for the entry of a cached key while it is held
+    pub fn get(&self, key: u32) -> Result<Option<u64>> {
+        let (values, _) = self.buffer.peek_range(key, 1, false)?;
+        // Checked after the current value is read: a commit since then has recorded what it
+        // overwrote, and the snapshot still holds the history
+        match self.buffer.versions().value_at(key, self.ts) {
+            Some(value) => Ok(value),
+            None => Ok(values[0]),
+        }
+    }
+}
+
+impl<'a> Drop for Snapshot<'a> {
+    fn drop(&mut self) {
+        self.buffer.versions().release(self.ts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use buffer::{test_buffer, Buffer};
+
+    #[test]
+    fn snapshot_reads_old_values() {
+        let buffer = test_buffer();
+        *buffer.lock(0).unwrap().get_mut().unwrap() = Some(1);
+
+        {
+            let snapshot = buffer.snapshot();
+
+            // Evicted and loaded again while the snapshot is running
+            for key in 0..100 {
+                *buffer.lock(key).unwrap().get_mut().unwrap() = Some(2);
+            }
+            let mut tx = buffer.transaction(&[0]);
+            tx.put(0, 3).unwrap();
+            tx.commit().unwrap();
+
+            // Read without loading anything into the cache
+            let stats = buffer.stats();
+            assert_eq!(snapshot.get(0).unwrap(), Some(1));
+            assert_eq!(snapshot.get(1).unwrap(), None);
+            assert_eq!(buffer.stats().misses, stats.misses);
+            assert_eq!(buffer.stats().hits, stats.hits);
+            assert_eq!(buffer.get(0).unwrap(), Some(3));
+            assert!(!buffer.versions().inner().history.is_empty());
+        }
+
+        assert!(buffer.versions().inner().history.is_empty());
+    }
+
+    #[test]
+    fn threaded_snapshot_reads() {
+        let n_accounts: u32 = 20;
+
+        let buffer = Arc::new(test_buffer());
+        for key in 0..n_accounts {
+            *buffer.lock(key).unwrap().get_mut().unwrap() = Some(100);
+        }
+
+        let writer = {
+            let buffer = buffer.clone();
+            thread::spawn(move || {
+                for i in 0..2000 {
+                    let from = i % n_accounts;
+                    let to = (i * 7 + 3) % n_accounts;
+                    if from == to {
+                        continue;
+                    }
+                    let mut tx = buffer.transaction(&[from, to]);
+                    let balance = tx.get(from).unwrap().unwrap();
+                    if balance > 0 {
+                        tx.put(from, balance - 1).unwrap();
+                        let balance = tx.get(to).unwrap().unwrap();
+                        tx.put(to, balance + 1).unwrap();
+                        tx.commit().unwrap();
+                    }
+                }
+            })
+        };
+
+        let mut readers = Vec::new();
+        for _ in 0..2 {
+            let buffer = buffer.clone();
+            readers.push(thread::spawn(move || {
+                for _ in 0..50 {
+                    let snapshot = buffer.snapshot();
+                    let sum: u64 = (0..n_accounts)
+                        .map(|key| snapshot.get(key).unwrap().unwrap())
+                        .sum();
+                    assert_eq!(sum, 100 * n_accounts as u64);
+                }
+            }));
+        }
+
+        writer.join().unwrap();
+        for t in readers {
+            t.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Snapshot of cache statistics
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub dirty_entries: u64,
+}
+
+/// Snapshot of buffer statistics
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Dirty entries written back on eviction
+    pub write_backs: u64,
+    pub storage_reads: u64,
+    pub storage_writes: u64,
+    /// Values deleted because they expired
+    pub expirations: u64,
+    pub syncs: u64,
+    /// Total time spent in `sync`
+    pub sync_time: Duration,
+    /// Total time spent waiting for the cache in `lock`
+    pub lock_wait_time: Duration,
+    pub dirty_entries: u64,
+}
+
+impl Stats {
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn incr(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn decr(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn add_duration(&self, duration: Duration) {
+        self.add(duration.as_secs() * 1_000_000_000 + u64::from(duration.subsec_nanos()));
+    }
+
+    pub fn get_duration(&self) -> Duration {
+        let nanos = self.get();
+        Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CacheCounters {
+    pub hits: Counter,
+    pub misses: Counter,
+    pub evictions: Counter,
+    /// Shared with the entries, which count themselves in `Entry::set_state`
+    pub dirty_entries: Arc<Counter>,
+}
+
+impl CacheCounters {
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.get(),
+            misses: self.misses.get(),
+            evictions: self.evictions.get(),
+            dirty_entries: self.dirty_entries.get(),
+        }
+    }
+}
@@ -4,23 +4,247 @@ use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
 use std::io::{self, SeekFrom};
-use std::mem::{size_of, transmute};
+use std::mem::{size_of, size_of_val};
 use std::path::Path;
 use std::ptr::NonNull;
 use std::slice;
 
+/// What storage keeps for a key
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Slot {
+    /// `None` if the key was never set or has been deleted
+    pub value: Option<u64>,
+    /// Expiry time as returned by `ttl::deadline`, or 0 if it never expires
+    pub expires_at: u64,
+}
+
+impl Slot {
+    pub fn new(value: Option<u64>, expires_at: u64) -> Slot {
+        Slot { value, expires_at }
+    }
+
+    /// Whether it has a value that expired at or before `now`
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.value.is_some() && self.expires_at != 0 && self.expires_at <= now
+    }
+}
+
 pub trait Storage {
-    fn read(&mut self, key: u32, dst: NonNull<u64>) -> io::Result<()>;
-    fn write(&mut self, key: u32, src: NonNull<u64>) -> io::Result<()>;
+    fn read(&mut self, key: u32, dst: NonNull<Slot>) -> io::Result<()>;
+    fn write(&mut self, key: u32, src: NonNull<Slot>) -> io::Result<()>;
     fn sync(&mut self) -> io::Result<()>;
+
+    /// Number of keys it has room for
+    fn n_keys(&self) -> u32;
+
+    /// Read the slots of consecutive keys starting at `key`
+    fn read_range(&mut self, key: u32, dst: &mut [Slot]) -> io::Result<()> {
+        for (i, slot) in dst.iter_mut().enumerate() {
+            self.read(key + i as u32, NonNull::from(slot))?;
+        }
+        Ok(())
+    }
+
+    /// Write the slots of consecutive keys starting at `key`
+    fn write_range(&mut self, key: u32, src: &[Slot]) -> io::Result<()> {
+        for (i, slot) in src.iter().enumerate() {
+            let mut slot = *slot;
+            self.write(key + i as u32, NonNull::from(&mut slot))?;
+        }
+        Ok(())
+    }
+}
+
+/// Words per key: a header and the value
+pub(crate) const SLOT_WORDS: usize = 2;
+pub(crate) const SLOT_SIZE: u64 = (SLOT_WORDS * size_of::<u64>()) as u64;
+
+/// Header bit of a slot holding a value. A zeroed slot is absent, so a new file is empty. The
+/// other bits hold the expiry time.
+const PRESENT: u64 = 1;
+
+/// Latest expiry time the header of a slot can hold
+pub const MAX_EXPIRES_AT: u64 = u64::MAX >> 1;
+
+pub(crate) fn encode(slot: Slot, words: &mut [u64]) {
+    match slot.value {
+        Some(value) => {
+            words[0] = slot.expires_at << 1 | PRESENT;
+            words[1] = value;
+        }
+        None => {
+            words[0] = 0;
+            words[1] = 0;
+        }
+    }
+}
+
+pub(crate) fn decode(words: &[u64]) -> Slot {
+    if words[0] & PRESENT != 0 {
+        Slot::new(Some(words[1]), words[0] >> 1)
+    } else {
+        Slot::default()
+    }
+}
+
+/// Whether the words of an absent slot are not all zeros, which no write produces
+pub(crate) fn is_malformed(words: &[u64]) -> bool {
+    words[0] & PRESENT == 0 && words.iter().any(|&word| word != 0)
+}
+
+/// Data files start with a header of `HEADER_SIZE` bytes: this magic, the format version as a
+/// little-endian u32, then zeros, but for the `BackupInfo` of backups at `BACKUP_INFO_POS`.
+/// Format 1 had 8 bytes per key and no header.
+const MAGIC: &[u8; 8] = b"PIYOKVS\0";
+const FORMAT_VERSION: u32 = 2;
+pub(crate) const HEADER_SIZE: u64 = 64;
+const BACKUP_INFO_POS: u64 = 16;
+
+/// What the header of a backup records about it, all zeros in other data files
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BackupInfo {
+    /// Chain of incremental backups started by the full backup, as in `DeltaInfo::chain`
+    pub chain: u64,
+    /// Last delta of the chain applied, 0 for the full backup alone
+    pub seq: u32,
+    /// Sequence number of the next archive record when the last backup applied was taken, 0
+    /// without an archive. The file holds every change archived before it.
+    pub archive_seq: u64,
+}
+
+impl BackupInfo {
+    const SIZE: usize = 20;
+
+    fn encode(&self) -> [u8; BackupInfo::SIZE] {
+        let mut buf = [0; BackupInfo::SIZE];
+        buf[..8].copy_from_slice(&self.chain.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.seq.to_le_bytes());
+        buf[12..20].copy_from_slice(&self.archive_seq.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8; BackupInfo::SIZE]) -> BackupInfo {
+        let mut chain = [0; 8];
+        chain.copy_from_slice(&buf[..8]);
+        let mut seq = [0; 4];
+        seq.copy_from_slice(&buf[8..12]);
+        let mut archive_seq = [0; 8];
+        archive_seq.copy_from_slice(&buf[12..20]);
+        BackupInfo {
+            chain: u64::from_le_bytes(chain),
+            seq: u32::from_le_bytes(seq),
+            archive_seq: u64::from_le_bytes(archive_seq),
+        }
+    }
+}
+
+/// Read the `BackupInfo` of a data file whose header has been checked
+pub(crate) fn read_backup_info<R>(reader: &mut R) -> io::Result<BackupInfo>
+where
+    R: Read + Seek,
+{
+    let mut buf = [0; BackupInfo::SIZE];
+    reader.seek(SeekFrom::Start(BACKUP_INFO_POS))?;
+    reader.read_exact(&mut buf)?;
+    Ok(BackupInfo::decode(&buf))
+}
+
+fn write_header<W>(writer: &mut W) -> io::Result<()>
+where
+    W: Write + Seek,
+{
+    let mut header = [0u8; HEADER_SIZE as usize];
+    header[..8].copy_from_slice(MAGIC);
+    header[8..12].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    writer.seek(SeekFrom::Start(0))?;
+    writer.write_all(&header)
+}
+
+/// Fail unless the file starts with the header of the current format
+pub(crate) fn check_header<R>(reader: &mut R) -> io::Result<()>
+where
+    R: Read + Seek,
+{
+    let mut header = [0u8; HEADER_SIZE as usize];
+    reader.seek(SeekFrom::Start(0))?;
+    let found = match reader.read_exact(&mut header) {
+        Ok(()) if &header[..8] == MAGIC => Some(u32::from_le_bytes([
+            header[8], header[9], header[10], header[11],
+        ])),
+        Ok(()) => None,
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => None,
+        Err(err) => return Err(err),
+    };
+    match found {
+        Some(FORMAT_VERSION) => Ok(()),
+        Some(version) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported data file format {}", version),
+        )),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("not a data file of format {}", FORMAT_VERSION),
+        )),
+    }
+}
+
+pub(crate) fn slot_pos(key: u32) -> u64 {
+    HEADER_SIZE + u64::from(key) * SLOT_SIZE
+}
+
+/// Number of slots in a data file of `len` bytes, and the bytes after the last one
+pub(crate) fn n_slots(len: u64) -> (u64, u64) {
+    let len = len.saturating_sub(HEADER_SIZE);
+    (len / SLOT_SIZE, len % SLOT_SIZE)
 }
 
 pub struct StorageImpl {
     file: File,
+    n_data: u32,
 }
 
 impl StorageImpl {
     pub fn new<P>(path: P, n_data: u32) -> io::Result<StorageImpl>
+    where
+        P: AsRef<Path>,
+    {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(true);
+        StorageImpl::create(&options, path.as_ref(), n_data)
+    }
+
+    /// Like `new`, but failing if a file is already at `path` rather than truncating it
+    pub fn create_new<P>(path: P, n_data: u32) -> io::Result<StorageImpl>
+    where
+        P: AsRef<Path>,
+    {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create_new(true);
+        StorageImpl::create(&options, path.as_ref(), n_data)
+    }
+
+    fn create(options: &OpenOptions, path: &Path, n_data: u32) -> io::Result<StorageImpl> {
+        assert!(n_data > 0);
+
+        let mut file = options.open(path)?;
+        write_header(&mut file)?;
+        write_zeros(&mut file, (n_data as u64) * SLOT_SIZE)?;
+
+        Ok(StorageImpl { file, n_data })
+    }
+
+    pub fn backup_info(&mut self) -> io::Result<BackupInfo> {
+        read_backup_info(&mut self.file)
+    }
+
+    /// Record `info` in the header, durable at the next sync
+    pub fn set_backup_info(&mut self, info: BackupInfo) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(BACKUP_INFO_POS))?;
+        self.file.write_all(&info.encode())
+    }
+
+    /// Open the storage at `path` keeping its contents, and grow it to at least `n_data` keys
+    pub fn open<P>(path: P, n_data: u32) -> io::Result<StorageImpl>
     where
         P: AsRef<Path>,
     {
@@ -30,31 +254,73 @@ impl StorageImpl {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(path.as_ref())?;
-        write_zeros(&mut file, (n_data as u64) * (size_of::<u64>() as u64))?;
 
-        Ok(StorageImpl { file })
+        let mut len = file.metadata()?.len();
+        if len == 0 {
+            write_header(&mut file)?;
+            len = HEADER_SIZE;
+        } else {
+            check_header(&mut file)?;
+        }
+
+        let size = slot_pos(0) + (n_data as u64) * SLOT_SIZE;
+        if len < size {
+            file.seek(SeekFrom::Start(len))?;
+            write_zeros(&mut file, size - len)?;
+        }
+
+        let (n_data, _) = n_slots(cmp::max(size, len));
+        Ok(StorageImpl {
+            file,
+            n_data: cmp::min(n_data, u64::from(u32::MAX)) as u32,
+        })
     }
 }
 
 impl Storage for StorageImpl {
-    fn read(&mut self, key: u32, dst: NonNull<u64>) -> io::Result<()> {
-        let pos = key as u64 * size_of::<u64>() as u64;
-        read_at(&mut self.file, pos, dst.as_ptr())
+    fn read(&mut self, key: u32, dst: NonNull<Slot>) -> io::Result<()> {
+        let mut words = [0; SLOT_WORDS];
+        read_at(&mut self.file, slot_pos(key), &mut words)?;
+        unsafe { *dst.as_ptr() = decode(&words) };
+        Ok(())
     }
 
-    fn write(&mut self, key: u32, src: NonNull<u64>) -> io::Result<()> {
-        let pos = key as u64 * size_of::<u64>() as u64;
-        write_at(&mut self.file, pos, src.as_ptr() as *const u64)
+    fn write(&mut self, key: u32, src: NonNull<Slot>) -> io::Result<()> {
+        let mut words = [0; SLOT_WORDS];
+        encode(unsafe { *src.as_ref() }, &mut words);
+        write_at(&mut self.file, slot_pos(key), &words)
     }
 
     fn sync(&mut self) -> io::Result<()> {
         self.file.sync_data()
     }
+
+    fn n_keys(&self) -> u32 {
+        self.n_data
+    }
+
+    fn read_range(&mut self, key: u32, dst: &mut [Slot]) -> io::Result<()> {
+        let mut words = vec![0; dst.len() * SLOT_WORDS];
+        read_at(&mut self.file, slot_pos(key), &mut words)?;
+        for (slot, words) in dst.iter_mut().zip(words.chunks(SLOT_WORDS)) {
+            *slot = decode(words);
+        }
+        Ok(())
+    }
+
+    fn write_range(&mut self, key: u32, src: &[Slot]) -> io::Result<()> {
+        let mut words = vec![0; src.len() * SLOT_WORDS];
+        for (slot, words) in src.iter().zip(words.chunks_mut(SLOT_WORDS)) {
+            encode(*slot, words);
+        }
+        write_at(&mut self.file, slot_pos(key), &words)
+    }
 }
 
 /// Write `size` zeros
-fn write_zeros<W>(writer: &mut W, mut size: u64) -> io::Result<()>
+pub(crate) fn write_zeros<W>(writer: &mut W, mut size: u64) -> io::Result<()>
 where
     W: Write,
 {
@@ -70,7 +336,7 @@ where
     Ok(())
 }
 
-fn read_at<R>(reader: &mut R, pos: u64, dst: *mut u64) -> io::Result<()>
+pub(crate) fn read_at<R>(reader: &mut R, pos: u64, dst: &mut [u64]) -> io::Result<()>
 where
     R: Read + Seek,
 {
@@ -78,7 +344,7 @@ where
     read(reader, dst)
 }
 
-fn write_at<W>(writer: &mut W, pos: u64, src: *const u64) -> io::Result<()>
+pub(crate) fn write_at<W>(writer: &mut W, pos: u64, src: &[u64]) -> io::Result<()>
 where
     W: Write + Seek,
 {
@@ -86,29 +352,30 @@ where
     write(writer, src)
 }
 
-fn read<R>(reader: &mut R, dst: *mut u64) -> io::Result<()>
+fn read<R>(reader: &mut R, dst: &mut [u64]) -> io::Result<()>
 where
     R: Read,
 {
+    let len = size_of_val(dst);
     unsafe {
-        let ptr: *mut u8 = transmute(dst);
-        reader.read_exact(slice::from_raw_parts_mut(ptr, size_of::<u64>()))
+        let ptr = dst.as_mut_ptr() as *mut u8;
+        reader.read_exact(slice::from_raw_parts_mut(ptr, len))
     }
 }
 
-fn write<W>(writer: &mut W, src: *const u64) -> io::Result<()>
+fn write<W>(writer: &mut W, src: &[u64]) -> io::Result<()>
 where
     W: Write,
 {
     unsafe {
-        let ptr: *const u8 = transmute(src);
-        writer.write_all(slice::from_raw_parts(ptr, size_of::<u64>()))
+        let ptr = src.as_ptr() as *const u8;
+        writer.write_all(slice::from_raw_parts(ptr, size_of_val(src)))
     }
 }
 
 #[cfg(test)]
 pub struct StorageMock {
-    data: HashMap<u32, u64>,
+    data: HashMap<u32, Slot>,
 }
 
 #[cfg(test)]
@@ -120,27 +387,44 @@ impl StorageMock {
     }
 }
 
+#[cfg(test)]
+impl Default for StorageMock {
+    fn default() -> StorageMock {
+        StorageMock::new()
+    }
+}
+
 #[cfg(test)]
 impl Storage for StorageMock {
-    fn read(&mut self, key: u32, mut dst: NonNull<u64>) -> io::Result<()> {
-        unsafe { *dst.as_mut() = *self.data.entry(key).or_insert(0) };
+    fn read(&mut self, key: u32, dst: NonNull<Slot>) -> io::Result<()> {
+        unsafe { *dst.as_ptr() = self.data.get(&key).cloned().unwrap_or_default() };
         Ok(())
     }
 
-    fn write(&mut self, key: u32, src: NonNull<u64>) -> io::Result<()> {
-        self.data.insert(key, unsafe { *src.as_ref() });
+    fn write(&mut self, key: u32, src: NonNull<Slot>) -> io::Result<()> {
+        let slot = unsafe { *src.as_ref() };
+        if slot.value.is_some() {
+            self.data.insert(key, slot);
+        } else {
+            self.data.remove(&key);
+        }
         Ok(())
     }
 
     fn sync(&mut self) -> io::Result<()> {
         Ok(())
     }
+
+    /// Up to the highest key written
+    fn n_keys(&self) -> u32 {
+        self.data.keys().max().map_or(0, |&key| key + 1)
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
     use std::io::Cursor;
-    use std::mem::uninitialized;
     use std::sync::{Arc, Mutex};
     use std::thread;
 
@@ -164,28 +448,25 @@ mod tests {
     #[test]
     fn test_read() {
         let mut reader = Cursor::new([0u8; size_of::<u64>()]);
-        let mut data: u64 = 42;
-        read(&mut reader, &mut data as *mut u64).unwrap();
-        assert_eq!(data, 0);
+        let mut data = [42u64];
+        read(&mut reader, &mut data).unwrap();
+        assert_eq!(data, [0]);
     }
 
     #[test]
     fn test_write() {
         let mut writer = Vec::with_capacity(size_of::<u64>());
-        let data: u64 = 0;
-        write(&mut writer, &data as *const u64).unwrap();
+        write(&mut writer, &[0u64]).unwrap();
         assert_eq!(&writer, &[0u8; size_of::<u64>()]);
     }
 
     fn assert_data(n_data: u32, storage: &mut impl Storage) {
         for key in 0..n_data {
-            unsafe {
-                let mut data = uninitialized();
-                let dst = NonNull::new_unchecked(&mut data);
-                storage.read(key, dst).unwrap();
+            let mut data = Slot::default();
+            let dst = unsafe { NonNull::new_unchecked(&mut data) };
+            storage.read(key, dst).unwrap();
 
-                assert_eq!(data, key as u64);
-            }
+            assert_eq!(data.value, Some(key as u64));
         }
     }
 
@@ -195,7 +476,7 @@ mod tests {
         let mut storage = StorageImpl::new("tmp/storage_1.db", n_data).unwrap();
 
         for key in 0..n_data {
-            let mut data = key as u64;
+            let mut data = Slot::new(Some(key as u64), 0);
             let src = unsafe { NonNull::new_unchecked(&mut data) };
             storage.write(key, src).unwrap();
         }
@@ -221,7 +502,7 @@ mod tests {
 
             let t = thread::spawn(move || {
                 for key in (start..).take(count) {
-                    let mut data = key as u64;
+                    let mut data = Slot::new(Some(key as u64), 0);
                     let src = unsafe { NonNull::new_unchecked(&mut data) };
                     storage.lock().unwrap().write(key, src).unwrap();
                 }
@@ -236,13 +517,35 @@ mod tests {
         assert_data(n_data, &mut *storage.lock().unwrap());
     }
 
+    #[test]
+    fn refuse_other_formats() {
+        let path = "tmp/storage_6.db";
+        StorageImpl::new(path, 10).unwrap();
+        let mut storage = StorageImpl::open(path, 20).unwrap();
+        assert_eq!(storage.n_keys(), 20);
+        let mut slot = Slot::new(Some(1), 0);
+        storage.write(19, NonNull::from(&mut slot)).unwrap();
+        let mut bytes = fs::read(path).unwrap();
+        assert_eq!(bytes.len() as u64, HEADER_SIZE + 20 * SLOT_SIZE);
+
+        bytes[8] = 3;
+        fs::write(path, &bytes).unwrap();
+        let err = StorageImpl::open(path, 10).err().unwrap();
+        assert!(err.to_string().contains("format 3"));
+
+        // 8 bytes per key and no header
+        fs::write(path, [0u8; 80]).unwrap();
+        let err = StorageImpl::open(path, 10).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
     #[test]
     fn mock_single_write() {
         let n_data: u32 = 10000;
         let mut storage = StorageMock::new();
 
         for key in 0..n_data {
-            let mut data = key as u64;
+            let mut data = Slot::new(Some(key as u64), 0);
             let src = unsafe { NonNull::new_unchecked(&mut data) };
             storage.write(key, src).unwrap();
         }
@@ -268,7 +571,7 @@ mod tests {
 
             let t = thread::spawn(move || {
                 for key in (start..).take(count) {
-                    let mut data = key as u64;
+                    let mut data = Slot::new(Some(key as u64), 0);
                     let src = unsafe { NonNull::new_unchecked(&mut data) };
                     storage.lock().unwrap().write(key, src).unwrap();
                 }
@@ -282,4 +585,52 @@ mod tests {
 
         assert_data(n_data, &mut *storage.lock().unwrap());
     }
+
+    #[test]
+    fn range_read_and_write() {
+        let mut storage = StorageImpl::new("tmp/storage_3.db", 100).unwrap();
+
+        let src = (0..10).map(|i| Slot::new(Some(i), i)).collect::<Vec<_>>();
+        storage.write_range(40, &src).unwrap();
+
+        let mut dst = vec![Slot::new(Some(u64::MAX), 0); 12];
+        storage.read_range(39, &mut dst).unwrap();
+        assert_eq!(dst[0], Slot::default());
+        assert_eq!(&dst[1..11], &src[..]);
+        assert_eq!(dst[11], Slot::default());
+    }
+
+    #[test]
+    fn write_absent() {
+        let mut storage = StorageImpl::new("tmp/storage_4.db", 10).unwrap();
+
+        let mut data = Slot::new(Some(0), 0);
+        storage.write(3, NonNull::from(&mut data)).unwrap();
+        data = Slot::new(None, 0);
+        storage.write(4, NonNull::from(&mut data)).unwrap();
+
+        let mut dst = vec![Slot::new(Some(1), 1); 3];
+        storage.read_range(2, &mut dst).unwrap();
+        let values = dst.iter().map(|slot| slot.value).collect::<Vec<_>>();
+        assert_eq!(values, vec![None, Some(0), None]);
+    }
+
+    #[test]
+    fn reopen_keeps_data() {
+        let path = "tmp/storage_5.db";
+        {
+            let mut storage = StorageImpl::new(path, 10).unwrap();
+            let mut data = Slot::new(Some(42), 0);
+            storage.write(9, NonNull::from(&mut data)).unwrap();
+        }
+
+        let mut storage = StorageImpl::open(path, 5).unwrap();
+        assert_eq!(storage.n_keys(), 10);
+        let mut data = Slot::default();
+        storage.read(9, NonNull::from(&mut data)).unwrap();
+        assert_eq!(data.value, Some(42));
+
+        let storage = StorageImpl::open(path, 20).unwrap();
+        assert_eq!(storage.n_keys(), 20);
+    }
 }
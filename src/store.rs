@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+use buffer::Buffer;
+use error::Result;
+
+/// Value operations shared by an in-process `Buffer` and a `RemoteClient`, so that code written
+/// against it runs either way
+pub trait Store {
+    fn get(&self, key: u32) -> Result<Option<u64>>;
+    fn put(&self, key: u32, value: u64) -> Result<()>;
+    fn put_with_ttl(&self, key: u32, value: u64, ttl: Duration) -> Result<()>;
+    fn expire(&self, key: u32, ttl: Duration) -> Result<bool>;
+    fn ttl(&self, key: u32) -> Result<Option<Duration>>;
+    fn delete(&self, key: u32) -> Result<bool>;
+    fn get_versioned(&self, key: u32) -> Result<(Option<u64>, u64)>;
+    fn compare_and_swap(&self, key: u32, expected_version: u64, value: u64) -> Result<Option<u64>>;
+    fn get_many(&self, keys: &[u32]) -> Result<Vec<Option<u64>>>;
+    fn put_many(&self, writes: &[(u32, u64)]) -> Result<()>;
+    fn fetch_add(&self, key: u32, delta: u64) -> Result<u64>;
+    fn fetch_sub(&self, key: u32, delta: u64) -> Result<u64>;
+    fn saturating_fetch_add(&self, key: u32, delta: u64) -> Result<u64>;
+    fn saturating_fetch_sub(&self, key: u32, delta: u64) -> Result<u64>;
+    fn fetch_max(&self, key: u32, value: u64) -> Result<u64>;
+    fn fetch_min(&self, key: u32, value: u64) -> Result<u64>;
+    fn sync(&self) -> Result<()>;
+}
+
+impl<T: Buffer + ?Sized> Store for T {
+    fn get(&self, key: u32) -> Result<Option<u64>> {
+        Buffer::get(self, key)
+    }
+
+    fn put(&self, key: u32, value: u64) -> Result<()> {
+        Buffer::put(self, key, value)
+    }
+
+    fn put_with_ttl(&self, key: u32, value: u64, ttl: Duration) -> Result<()> {
+        Buffer::put_with_ttl(self, key, value, ttl)
+    }
+
+    fn expire(&self, key: u32, ttl: Duration) -> Result<bool> {
+        Buffer::expire(self, key, ttl)
+    }
+
+    fn ttl(&self, key: u32) -> Result<Option<Duration>> {
+        Buffer::ttl(self, key)
+    }
+
+    fn delete(&self, key: u32) -> Result<bool> {
+        Buffer::delete(self, key)
+    }
+
+    fn get_versioned(&self, key: u32) -> Result<(Option<u64>, u64)> {
+        Buffer::get_versioned(self, key)
+    }
+
+    fn compare_and_swap(&self, key: u32, expected_version: u64, value: u64) -> Result<Option<u64>> {
+        Buffer::compare_and_swap(self, key, expected_version, value)
+    }
+
+    fn get_many(&self, keys: &[u32]) -> Result<Vec<Option<u64>>> {
+        Buffer::get_many(self, keys)
+    }
+
+    fn put_many(&self, writes: &[(u32, u64)]) -> Result<()> {
+        Buffer::put_many(self, writes)
+    }
+
+    fn fetch_add(&self, key: u32, delta: u64) -> Result<u64> {
+        Buffer::fetch_add(self, key, delta)
+    }
+
+    fn fetch_sub(&self, key: u32, delta: u64) -> Result<u64> {
+        Buffer::fetch_sub(self, key, delta)
+    }
+
+    fn saturating_fetch_add(&self, key: u32, delta: u64) -> Result<u64> {
+        Buffer::saturating_fetch_add(self, key, delta)
+    }
+
+    fn saturating_fetch_sub(&self, key: u32, delta: u64) -> Result<u64> {
+        Buffer::saturating_fetch_sub(self, key, delta)
+    }
+
+    fn fetch_max(&self, key: u32, value: u64) -> Result<u64> {
+        Buffer::fetch_max(self, key, value)
+    }
+
+    fn fetch_min(&self, key: u32, value: u64) -> Result<u64> {
+        Buffer::fetch_min(self, key, value)
+    }
+
+    fn sync(&self) -> Result<()> {
+        Buffer::sync(self)
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+
+use buffer::BufferImpl;
+use error::{Error, Result};
+
+/// Keys held by running transactions
+#[derive(Default)]
+pub struct KeyLocks {
+    keys: Mutex<HashSet<u32>>,
+    released: Condvar,
+}
+
+impl KeyLocks {
+    fn keys(&self) -> MutexGuard<'_, HashSet<u32>> {
+        self.keys.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock `keys`, which must be sorted, waiting for other transactions to release them
+    fn lock(&self, keys: &[u32]) {
+        let mut locked = self.keys();
+        for &key in keys {
+            while locked.contains(&key) {
+                locked = self
+                    .released
+                    .wait(locked)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+            locked.insert(key);
+        }
+    }
+
+    fn unlock(&self, keys: &[u32]) {
+        let mut locked = self.keys();
+        for key in keys {
+            locked.remove(key);
+        }
+        self.released.notify_all();
+    }
+
+    pub fn is_locked(&self, key: u32) -> bool {
+        self.keys().contains(&key)
+    }
+
+    /// Wait until no transaction holds `key`
+    pub fn wait(&self, key: u32) {
+        let mut locked = self.keys();
+        while locked.contains(&key) {
+            locked = self
+                .released
+                .wait(locked)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+/// A set of keys locked together. Writes are buffered until `commit`, and dropping the
+/// transaction without committing rolls it back.
+pub struct Transaction<'a> {
+    buffer: &'a BufferImpl,
+    keys: Vec<u32>,
+    writes: BTreeMap<u32, Option<u64>>,
+}
+
+impl<'a> Transaction<'a> {
+    /// Lock `keys` in ascending order, so that transactions never wait for each other in a cycle
+    pub fn new(buffer: &'a BufferImpl, keys: &[u32]) -> Transaction<'a> {
+        let mut keys = keys.to_vec();
+        keys.sort();
+        keys.dedup();
+
+        buffer.key_locks().lock(&keys);
+
+        Transaction {
+            buffer,
+            keys,
+            writes: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, key: u32) -> Result<Option<u64>> {
+        self.check(key)?;
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(*value);
+        }
+        let entry = self.buffer.lock_entry(key)?;
+        Ok(*entry.get()?)
+    }
+
+    pub fn put(&mut self, key: u32, value: u64) -> Result<()> {
+        self.check(key)?;
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: u32) -> Result<()> {
+        self.check(key)?;
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    /// Apply all writes. If applying one fails, those applied before it are undone, in the buffer's
+    /// log too, and the error is returned.
+    pub fn commit(self) -> Result<()> {
+        let writes = self
+            .writes
+            .iter()
+            .map(|(key, value)| (*key, *value))
+            .collect::<Vec<_>>();
+        self.buffer.commit(&writes)
+    }
+
+    pub fn rollback(self) {}
+
+    fn check(&self, key: u32) -> Result<()> {
+        if self.keys.binary_search(&key).is_ok() {
+            Ok(())
+        } else {
+            Err(Error::NotInTransaction(key))
+        }
+    }
+}
+
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        self.buffer.key_locks().unlock(&self.keys);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::ptr::NonNull;
+    use std::sync::Arc;
+    use std::thread;
+
+    use buffer::{test_buffer, Buffer};
+    use cache::LruCache;
+    use log::Log;
+    use storage::{Slot, Storage, StorageMock};
+
+    use super::*;
+
+    #[test]
+    fn commit_and_rollback() {
+        let buffer = test_buffer();
+
+        let mut tx = buffer.transaction(&[1, 0]);
+        tx.put(0, 10).unwrap();
+        tx.put(1, 20).unwrap();
+        tx.delete(1).unwrap();
+        assert_eq!(tx.get(0).unwrap(), Some(10));
+        assert_eq!(tx.get(1).unwrap(), None);
+        match tx.put(2, 10) {
+            Err(Error::NotInTransaction(2)) => {}
+            _ => panic!("key outside the transaction must be rejected"),
+        }
+        tx.commit().unwrap();
+
+        let mut tx = buffer.transaction(&[0]);
+        tx.put(0, 20).unwrap();
+        tx.rollback();
+
+        assert_eq!(buffer.get(0).unwrap(), Some(10));
+        assert_eq!(buffer.get(1).unwrap(), None);
+    }
+
+    #[test]
+    fn threaded_transfers() {
+        let n_accounts: u32 = 20;
+        let n_threads: u32 = 4;
+
+        let buffer = Arc::new(test_buffer());
+        for key in 0..n_accounts {
+            *buffer.lock(key).unwrap().get_mut().unwrap() = Some(100);
+        }
+
+        let mut threads = Vec::with_capacity(n_threads as usize);
+        for i in 0..n_threads {
+            let buffer = buffer.clone();
+            let t = thread::spawn(move || {
+                for j in 0..1000 {
+                    let from = (i + j) % n_accounts;
+                    let to = (i * 7 + j * 3 + 1) % n_accounts;
+                    if from == to {
+                        continue;
+                    }
+
+                    let mut tx = buffer.transaction(&[from, to]);
+                    let balance = tx.get(from).unwrap().unwrap();
+                    if balance > 0 {
+                        tx.put(from, balance - 1).unwrap();
+                        let balance = tx.get(to).unwrap().unwrap();
+                        tx.put(to, balance + 1).unwrap();
+                        tx.commit().unwrap();
+                    }
+                }
+            });
+            threads.push(t);
+        }
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let sum: u64 = (0..n_accounts)
+            .map(|key| buffer.get(key).unwrap().unwrap())
+            .sum();
+        assert_eq!(sum, 100 * n_accounts as u64);
+    }
+
+    #[test]
+    fn replay_commit_after_crash() {
+        let path = "tmp/transaction_1.log";
+        let _ = fs::remove_file(path);
+
+        {
+            let cache = Box::new(LruCache::new(10));
+            let storage = Box::new(StorageMock::new());
+            let buffer = BufferImpl::with_log(cache, storage, Log::open(path).unwrap()).unwrap();
+
+            let mut tx = buffer.transaction(&[0, 1]);
+            tx.put(0, 10).unwrap();
+            tx.put(1, 20).unwrap();
+            tx.commit().unwrap();
+
+            // Crash before anything is written back
+        }
+
+        let mut storage = StorageMock::new();
+        Log::open(path).unwrap().replay(&mut storage).unwrap();
+        for &(key, expected) in &[(0, 10), (1, 20)] {
+            let mut slot = Slot::default();
+            storage.read(key, NonNull::from(&mut slot)).unwrap();
+            assert_eq!(slot.value, Some(expected));
+        }
+    }
+
+    #[test]
+    fn failed_commit_is_undone() {
+        let path = "tmp/transaction_2.log";
+        let _ = fs::remove_file(path);
+
+        {
+            let cache = Box::new(LruCache::new(10));
+            let storage = Box::new(StorageMock::new());
+            let log = Log::open(path).unwrap();
+            let buffer = Arc::new(BufferImpl::with_log(cache, storage, log).unwrap());
+
+            let mut tx = buffer.transaction(&[0]);
+            tx.put(0, 10).unwrap();
+            tx.commit().unwrap();
+
+            let poisoner = buffer.clone();
+            let result = thread::spawn(move || {
+                let _entry = poisoner.lock(5).unwrap();
+                panic!("poison 5");
+            })
+            .join();
+            assert!(result.is_err());
+
+            // 0 is written before 5 fails to lock
+            let mut tx = buffer.transaction(&[0, 5]);
+            tx.put(0, 20).unwrap();
+            tx.put(5, 30).unwrap();
+            match tx.commit() {
+                Err(Error::Poisoned) => {}
+                _ => panic!("poisoned key must fail the commit"),
+            }
+            assert_eq!(buffer.get(0).unwrap(), Some(10));
+        }
+
+        let mut storage = StorageMock::new();
+        Log::open(path).unwrap().replay(&mut storage).unwrap();
+        let mut slot = Slot::default();
+        storage.read(0, NonNull::from(&mut slot)).unwrap();
+        assert_eq!(slot.value, Some(10));
+    }
+}
@@ -0,0 +1,157 @@
+use std::cmp;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use buffer::Buffer;
+
+/// Latest expiry time that fits in a storage slot header
+const MAX_DEADLINE: u64 = u64::MAX >> 1;
+
+/// Milliseconds since the Unix epoch, the unit of expiry times
+pub fn now() -> u64 {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    millis(elapsed)
+}
+
+/// Expiry time `ttl` from now
+pub fn deadline(ttl: Duration) -> u64 {
+    cmp::min(now().saturating_add(millis(ttl)), MAX_DEADLINE)
+}
+
+/// Time left until `expires_at`
+pub fn remaining(expires_at: u64) -> Duration {
+    Duration::from_millis(expires_at.saturating_sub(now()))
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration
+        .as_secs()
+        .saturating_mul(1000)
+        .saturating_add(u64::from(duration.subsec_millis()))
+}
+
+/// Background thread deleting expired values from the buffer every `interval`, so that keys
+/// nobody reads again do not stay in storage. Stops when dropped.
+pub struct Sweeper {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    pub fn spawn(buffer: Arc<dyn Buffer + Send + Sync>, interval: Duration) -> Sweeper {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || loop {
+                let (ref stopped, ref wakeup) = *stop;
+                let stopped = stopped.lock().unwrap_or_else(PoisonError::into_inner);
+                if *stopped {
+                    return;
+                }
+                let (stopped, _) = wakeup
+                    .wait_timeout(stopped, interval)
+                    .unwrap_or_else(PoisonError::into_inner);
+                if *stopped {
+                    return;
+                }
+                drop(stopped);
+
+                // Whatever fails is retried on the next round
+                let _ = buffer.sweep();
+            })
+        };
+
+        Sweeper {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        let (ref stopped, ref wakeup) = *self.stop;
+        *stopped.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        wakeup.notify_all();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use buffer::test_buffer;
+    use error::Result;
+
+    use super::*;
+
+    #[test]
+    fn lazy_expiry() {
+        let buffer = test_buffer();
+
+        buffer
+            .put_with_ttl(0, 1, Duration::from_millis(20))
+            .unwrap();
+        buffer.put(1, 1).unwrap();
+        assert!(buffer.expire(1, Duration::from_secs(60)).unwrap());
+        assert!(!buffer.expire(2, Duration::from_secs(60)).unwrap());
+
+        // Updating the value in place keeps the expiry, putting a new one clears it
+        assert_eq!(buffer.fetch_add(0, 1).unwrap(), 1);
+        assert!(buffer.ttl(0).unwrap().unwrap() <= Duration::from_millis(20));
+        buffer.put(1, 2).unwrap();
+        assert_eq!(buffer.ttl(1).unwrap(), None);
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(buffer.get(0).unwrap(), None);
+        assert_eq!(buffer.ttl(0).unwrap(), None);
+        assert_eq!(buffer.get(1).unwrap(), Some(2));
+        assert_eq!(buffer.stats().expirations, 1);
+    }
+
+    #[test]
+    fn sweep_expired_keys() {
+        let buffer = test_buffer();
+
+        // Most of them are evicted and written back before they expire
+        for key in 0..20 {
+            buffer
+                .put_with_ttl(key, key as u64, Duration::from_millis(1))
+                .unwrap();
+        }
+        buffer.put(20, 20).unwrap();
+        buffer.sync().unwrap();
+
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(buffer.scan().count(), 1);
+        assert_eq!(buffer.sweep().unwrap(), 20);
+        assert_eq!(buffer.sweep().unwrap(), 0);
+
+        buffer.sync().unwrap();
+        let items = buffer.scan().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(items, vec![(20, 20)]);
+    }
+
+    #[test]
+    fn background_sweeper() {
+        let buffer = Arc::new(test_buffer());
+        buffer.put_with_ttl(0, 1, Duration::from_millis(1)).unwrap();
+        buffer.sync().unwrap();
+
+        let sweeper = Sweeper::spawn(buffer.clone(), Duration::from_millis(5));
+        let start = Instant::now();
+        while buffer.stats().expirations == 0 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(sweeper);
+    }
+}
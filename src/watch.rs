@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// Condition variables of keys that threads are waiting on to change
+#[derive(Default)]
+pub struct Watchers {
+    keys: Mutex<HashMap<u32, Watched>>,
+}
+
+struct Watched {
+    waiters: usize,
+    changed: Arc<Condvar>,
+}
+
+impl Watchers {
+    fn keys(&self) -> MutexGuard<'_, HashMap<u32, Watched>> {
+        self.keys.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Wake threads waiting on `key`. Must be called while holding its entry.
+    pub fn notify(&self, key: u32) {
+        if let Some(watched) = self.keys().get(&key) {
+            watched.changed.notify_all();
+        }
+    }
+
+    /// Release `entry`, which must hold `key`, and wait until the key changes or `timeout`
+    /// passes. May also return spuriously.
+    pub fn wait<T>(&self, key: u32, entry: T, timeout: Duration) {
+        let mut keys = self.keys();
+        let changed = {
+            let watched = keys.entry(key).or_insert_with(|| Watched {
+                waiters: 0,
+                changed: Arc::new(Condvar::new()),
+            });
+            watched.waiters += 1;
+            watched.changed.clone()
+        };
+
+        // Registered before releasing the entry, so a change right after is not missed
+        drop(entry);
+        let (mut keys, _) = changed
+            .wait_timeout(keys, timeout)
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let remove = {
+            let watched = keys.get_mut(&key).unwrap();
+            watched.waiters -= 1;
+            watched.waiters == 0
+        };
+        if remove {
+            keys.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+
+    use buffer::{test_buffer, Buffer};
+
+    use super::*;
+
+    #[test]
+    fn watch_times_out() {
+        let buffer = test_buffer();
+        let (_, version) = buffer.get_versioned(0).unwrap();
+
+        let start = Instant::now();
+        let timeout = Duration::from_millis(20);
+        assert_eq!(buffer.watch(0, version, timeout).unwrap(), None);
+        assert!(start.elapsed() >= timeout);
+
+        // Already changed since the version was seen
+        buffer.put(0, 1).unwrap();
+        let (value, new_version) = buffer.watch(0, version, timeout).unwrap().unwrap();
+        assert_eq!(value, Some(1));
+        assert_ne!(new_version, version);
+        assert!(buffer.watchers().keys().is_empty());
+    }
+
+    #[test]
+    fn watch_wakes_on_change() {
+        let buffer = Arc::new(test_buffer());
+        buffer.put(0, 0).unwrap();
+        let (_, version) = buffer.get_versioned(0).unwrap();
+
+        let watchers = (0..2)
+            .map(|_| {
+                let buffer = buffer.clone();
+                thread::spawn(move || {
+                    buffer
+                        .watch(0, version, Duration::from_secs(5))
+                        .unwrap()
+                        .unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // Neither unrelated keys nor unchanged values wake them
+        thread::sleep(Duration::from_millis(20));
+        buffer.put(1, 1).unwrap();
+        buffer.put(0, 0).unwrap();
+        buffer.fetch_add(0, 2).unwrap();
+
+        for t in watchers {
+            let (value, _) = t.join().unwrap();
+            assert_eq!(value, Some(2));
+        }
+    }
+}
@@ -0,0 +1,614 @@
+use std::cmp;
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use buffer::Buffer;
+use error::{Error, Result};
+use server;
+use stats::Stats;
+
+/// Largest frame accepted, so that a bad length cannot exhaust memory
+const MAX_FRAME_LEN: usize = 1 << 24;
+/// Most pairs returned for one `Scan`, keeping the response well under `MAX_FRAME_LEN`
+pub const MAX_SCAN_LEN: u32 = 1 << 16;
+/// Most keys read by one `GetMany`, whose response would otherwise outgrow `MAX_FRAME_LEN`
+pub const MAX_GET_MANY_LEN: usize = 1 << 20;
+
+/// A request of the binary protocol. Every frame is a little-endian `u32` length followed by an
+/// opcode and the arguments as little-endian integers, vectors being prefixed by a `u32`
+/// length. Requests can be pipelined: responses come back in the same order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    Get(u32),
+    Put(u32, u64),
+    PutWithTtl(u32, u64, Duration),
+    Delete(u32),
+    /// At most `MAX_GET_MANY_LEN` keys
+    GetMany(Vec<u32>),
+    PutMany(Vec<(u32, u64)>),
+    FetchAdd(u32, u64),
+    Sync,
+    /// Present keys from the first one up to a count, capped at `MAX_SCAN_LEN`
+    Scan(u32, u32),
+    Stats,
+    Expire(u32, Duration),
+    Ttl(u32),
+    GetVersioned(u32),
+    /// Key, expected version and value
+    CompareAndSwap(u32, u64, u64),
+    FetchSub(u32, u64),
+    SaturatingFetchAdd(u32, u64),
+    SaturatingFetchSub(u32, u64),
+    FetchMax(u32, u64),
+    FetchMin(u32, u64),
+}
+
+/// A response, tagged with its kind
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    Done,
+    Value(Option<u64>),
+    Bool(bool),
+    Values(Vec<Option<u64>>),
+    Integer(u64),
+    Error(String),
+    Pairs(Vec<(u32, u64)>),
+    Stats(Stats),
+    Ttl(Option<Duration>),
+    /// Value and version
+    Versioned(Option<u64>, u64),
+}
+
+impl Request {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match *self {
+            Request::Get(key) => {
+                buf.push(1);
+                put_u32(buf, key);
+            }
+            Request::Put(key, value) => {
+                buf.push(2);
+                put_u32(buf, key);
+                put_u64(buf, value);
+            }
+            Request::PutWithTtl(key, value, ttl) => {
+                buf.push(3);
+                put_u32(buf, key);
+                put_u64(buf, value);
+                put_duration(buf, ttl);
+            }
+            Request::Delete(key) => {
+                buf.push(4);
+                put_u32(buf, key);
+            }
+            Request::GetMany(ref keys) => {
+                buf.push(5);
+                put_u32(buf, keys.len() as u32);
+                for &key in keys {
+                    put_u32(buf, key);
+                }
+            }
+            Request::PutMany(ref writes) => {
+                buf.push(6);
+                put_u32(buf, writes.len() as u32);
+                for &(key, value) in writes {
+                    put_u32(buf, key);
+                    put_u64(buf, value);
+                }
+            }
+            Request::FetchAdd(key, delta) => {
+                buf.push(7);
+                put_u32(buf, key);
+                put_u64(buf, delta);
+            }
+            Request::Sync => buf.push(8),
+            Request::Scan(first, limit) => {
+                buf.push(9);
+                put_u32(buf, first);
+                put_u32(buf, limit);
+            }
+            Request::Stats => buf.push(10),
+            Request::Expire(key, ttl) => {
+                buf.push(11);
+                put_u32(buf, key);
+                put_duration(buf, ttl);
+            }
+            Request::Ttl(key) => {
+                buf.push(12);
+                put_u32(buf, key);
+            }
+            Request::GetVersioned(key) => {
+                buf.push(13);
+                put_u32(buf, key);
+            }
+            Request::CompareAndSwap(key, expected_version, value) => {
+                buf.push(14);
+                put_u32(buf, key);
+                put_u64(buf, expected_version);
+                put_u64(buf, value);
+            }
+            Request::FetchSub(key, n) => put_fetch(buf, 15, key, n),
+            Request::SaturatingFetchAdd(key, n) => put_fetch(buf, 16, key, n),
+            Request::SaturatingFetchSub(key, n) => put_fetch(buf, 17, key, n),
+            Request::FetchMax(key, n) => put_fetch(buf, 18, key, n),
+            Request::FetchMin(key, n) => put_fetch(buf, 19, key, n),
+        }
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Request> {
+        let mut decoder = Decoder { buf };
+        let request = match decoder.u8()? {
+            1 => Request::Get(decoder.u32()?),
+            2 => Request::Put(decoder.u32()?, decoder.u64()?),
+            3 => {
+                let (key, value) = (decoder.u32()?, decoder.u64()?);
+                Request::PutWithTtl(key, value, decoder.duration()?)
+            }
+            4 => Request::Delete(decoder.u32()?),
+            5 => {
+                let len = decoder.len(4)?;
+                Request::GetMany((0..len).map(|_| decoder.u32()).collect::<io::Result<_>>()?)
+            }
+            6 => {
+                let len = decoder.len(12)?;
+                Request::PutMany(
+                    (0..len)
+                        .map(|_| Ok((decoder.u32()?, decoder.u64()?)))
+                        .collect::<io::Result<_>>()?,
+                )
+            }
+            7 => Request::FetchAdd(decoder.u32()?, decoder.u64()?),
+            8 => Request::Sync,
+            9 => Request::Scan(decoder.u32()?, decoder.u32()?),
+            10 => Request::Stats,
+            11 => Request::Expire(decoder.u32()?, decoder.duration()?),
+            12 => Request::Ttl(decoder.u32()?),
+            13 => Request::GetVersioned(decoder.u32()?),
+            14 => Request::CompareAndSwap(decoder.u32()?, decoder.u64()?, decoder.u64()?),
+            15 => Request::FetchSub(decoder.u32()?, decoder.u64()?),
+            16 => Request::SaturatingFetchAdd(decoder.u32()?, decoder.u64()?),
+            17 => Request::SaturatingFetchSub(decoder.u32()?, decoder.u64()?),
+            18 => Request::FetchMax(decoder.u32()?, decoder.u64()?),
+            19 => Request::FetchMin(decoder.u32()?, decoder.u64()?),
+            _ => return Err(invalid("unknown opcode")),
+        };
+        decoder.finish()?;
+        Ok(request)
+    }
+}
+
+impl Response {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match *self {
+            Response::Done => buf.push(0),
+            Response::Value(value) => {
+                buf.push(1);
+                put_value(buf, value);
+            }
+            Response::Bool(b) => {
+                buf.push(2);
+                buf.push(b as u8);
+            }
+            Response::Values(ref values) => {
+                buf.push(3);
+                put_u32(buf, values.len() as u32);
+                for &value in values {
+                    put_value(buf, value);
+                }
+            }
+            Response::Integer(n) => {
+                buf.push(4);
+                put_u64(buf, n);
+            }
+            Response::Error(ref message) => {
+                buf.push(5);
+                buf.extend_from_slice(message.as_bytes());
+            }
+            Response::Pairs(ref pairs) => {
+                buf.push(6);
+                put_u32(buf, pairs.len() as u32);
+                for &(key, value) in pairs {
+                    put_u32(buf, key);
+                    put_u64(buf, value);
+                }
+            }
+            Response::Stats(ref stats) => {
+                buf.push(7);
+                for &n in &[
+                    stats.hits,
+                    stats.misses,
+                    stats.evictions,
+                    stats.write_backs,
+                    stats.storage_reads,
+                    stats.storage_writes,
+                    stats.expirations,
+                    stats.syncs,
+                ] {
+                    put_u64(buf, n);
+                }
+                put_duration(buf, stats.sync_time);
+                put_duration(buf, stats.lock_wait_time);
+                put_u64(buf, stats.dirty_entries);
+            }
+            Response::Ttl(ttl) => {
+                buf.push(8);
+                buf.push(ttl.is_some() as u8);
+                put_duration(buf, ttl.unwrap_or_default());
+            }
+            Response::Versioned(value, version) => {
+                buf.push(9);
+                put_value(buf, value);
+                put_u64(buf, version);
+            }
+        }
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Response> {
+        let mut decoder = Decoder { buf };
+        let response = match decoder.u8()? {
+            0 => Response::Done,
+            1 => Response::Value(decoder.value()?),
+            2 => Response::Bool(decoder.u8()? != 0),
+            3 => {
+                let len = decoder.len(9)?;
+                Response::Values(
+                    (0..len)
+                        .map(|_| decoder.value())
+                        .collect::<io::Result<_>>()?,
+                )
+            }
+            4 => Response::Integer(decoder.u64()?),
+            5 => {
+                let message = String::from_utf8_lossy(decoder.buf).into_owned();
+                decoder.buf = &[];
+                Response::Error(message)
+            }
+            6 => {
+                let len = decoder.len(12)?;
+                Response::Pairs(
+                    (0..len)
+                        .map(|_| Ok((decoder.u32()?, decoder.u64()?)))
+                        .collect::<io::Result<_>>()?,
+                )
+            }
+            7 => Response::Stats(Stats {
+                hits: decoder.u64()?,
+                misses: decoder.u64()?,
+                evictions: decoder.u64()?,
+                write_backs: decoder.u64()?,
+                storage_reads: decoder.u64()?,
+                storage_writes: decoder.u64()?,
+                expirations: decoder.u64()?,
+                syncs: decoder.u64()?,
+                sync_time: decoder.duration()?,
+                lock_wait_time: decoder.duration()?,
+                dirty_entries: decoder.u64()?,
+            }),
+            8 => {
+                let tag = decoder.u8()?;
+                let ttl = decoder.duration()?;
+                Response::Ttl(if tag == 0 { None } else { Some(ttl) })
+            }
+            9 => Response::Versioned(decoder.value()?, decoder.u64()?),
+            _ => return Err(invalid("unknown response kind")),
+        };
+        decoder.finish()?;
+        Ok(response)
+    }
+}
+
+fn put_u32(buf: &mut Vec<u8>, n: u32) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, n: u64) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+/// Opcode, key and operand of the fetch operations
+fn put_fetch(buf: &mut Vec<u8>, opcode: u8, key: u32, n: u64) {
+    buf.push(opcode);
+    put_u32(buf, key);
+    put_u64(buf, n);
+}
+
+/// Seconds, then nanoseconds as a `u32`
+fn put_duration(buf: &mut Vec<u8>, duration: Duration) {
+    put_u64(buf, duration.as_secs());
+    put_u32(buf, duration.subsec_nanos());
+}
+
+/// A tag of 0 for `None`, then the value
+fn put_value(buf: &mut Vec<u8>, value: Option<u64>) {
+    buf.push(value.is_some() as u8);
+    put_u64(buf, value.unwrap_or(0));
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(invalid("truncated frame"));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn duration(&mut self) -> io::Result<Duration> {
+        let (secs, nanos) = (self.u64()?, self.u32()?);
+        // `Duration::new` would carry the excess into the seconds, and panic on overflow
+        if nanos >= 1_000_000_000 {
+            return Err(invalid("invalid nanoseconds"));
+        }
+        Ok(Duration::new(secs, nanos))
+    }
+
+    fn value(&mut self) -> io::Result<Option<u64>> {
+        let tag = self.u8()?;
+        let value = self.u64()?;
+        Ok(if tag == 0 { None } else { Some(value) })
+    }
+
+    /// Length of a vector whose items take `item_size` bytes, checked against what is left
+    fn len(&mut self, item_size: usize) -> io::Result<usize> {
+        let len = self.u32()? as usize;
+        if len * item_size > self.buf.len() {
+            return Err(invalid("truncated frame"));
+        }
+        Ok(len)
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            return Err(invalid("trailing bytes in frame"));
+        }
+        Ok(())
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Write a frame holding `payload`
+pub fn write_frame<W: Write>(out: &mut W, payload: &[u8]) -> io::Result<()> {
+    out.write_all(&(payload.len() as u32).to_le_bytes())?;
+    out.write_all(payload)
+}
+
+/// Read the payload of the next frame. Returns `None` at the end of the stream.
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; 4];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+
+    let len = u32::from_le_bytes(header) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(invalid("frame too large"));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+/// Run one request against `buffer`
+pub fn execute(buffer: &dyn Buffer, request: Request) -> Response {
+    let response = match request {
+        Request::Get(key) => buffer.get(key).map(Response::Value),
+        Request::Put(key, value) => buffer.put(key, value).map(|()| Response::Done),
+        Request::PutWithTtl(key, value, ttl) => buffer
+            .put_with_ttl(key, value, ttl)
+            .map(|()| Response::Done),
+        Request::Delete(key) => buffer.delete(key).map(Response::Bool),
+        Request::GetMany(ref keys) if keys.len() > MAX_GET_MANY_LEN => Ok(Response::Error(
+            format!("more than {} keys in one request", MAX_GET_MANY_LEN),
+        )),
+        Request::GetMany(keys) => buffer.get_many(&keys).map(Response::Values),
+        Request::PutMany(writes) => buffer.put_many(&writes).map(|()| Response::Done),
+        Request::FetchAdd(key, delta) => buffer.fetch_add(key, delta).map(Response::Integer),
+        Request::Sync => buffer.sync().map(|()| Response::Done),
+        Request::Scan(first, limit) => buffer
+            .scan()
+            .starting_at(first)
+            .take(cmp::min(limit, MAX_SCAN_LEN) as usize)
+            .collect::<Result<_>>()
+            .map(Response::Pairs),
+        Request::Stats => Ok(Response::Stats(buffer.stats())),
+        Request::Expire(key, ttl) => buffer.expire(key, ttl).map(Response::Bool),
+        Request::Ttl(key) => buffer.ttl(key).map(Response::Ttl),
+        Request::GetVersioned(key) => buffer
+            .get_versioned(key)
+            .map(|(value, version)| Response::Versioned(value, version)),
+        Request::CompareAndSwap(key, expected_version, value) => buffer
+            .compare_and_swap(key, expected_version, value)
+            .map(Response::Value),
+        Request::FetchSub(key, delta) => buffer.fetch_sub(key, delta).map(Response::Integer),
+        Request::SaturatingFetchAdd(key, delta) => buffer
+            .saturating_fetch_add(key, delta)
+            .map(Response::Integer),
+        Request::SaturatingFetchSub(key, delta) => buffer
+            .saturating_fetch_sub(key, delta)
+            .map(Response::Integer),
+        Request::FetchMax(key, value) => buffer.fetch_max(key, value).map(Response::Integer),
+        Request::FetchMin(key, value) => buffer.fetch_min(key, value).map(Response::Integer),
+    };
+    response.unwrap_or_else(|err: Error| Response::Error(err.to_string()))
+}
+
+/// Serve the binary protocol for `buffer` on `addr` from background threads, one per
+/// connection. Returns the bound address.
+pub fn spawn<A>(addr: A, buffer: Arc<dyn Buffer + Send + Sync>) -> io::Result<SocketAddr>
+where
+    A: ToSocketAddrs,
+{
+    server::spawn(addr, buffer, handle)
+}
+
+/// Accept connections on `listener` like `server::serve`
+pub fn serve(listener: &TcpListener, buffer: &Arc<dyn Buffer + Send + Sync>) {
+    server::serve(listener, buffer, handle)
+}
+
+fn handle(stream: TcpStream, buffer: &dyn Buffer) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut buf = Vec::new();
+
+    // A malformed frame ends the connection, since the stream cannot be resynchronized
+    while let Some(payload) = read_frame(&mut reader)? {
+        let response = execute(buffer, Request::decode(&payload)?);
+
+        buf.clear();
+        response.encode(&mut buf);
+        write_frame(&mut writer, &buf)?;
+
+        // Pipelined requests are answered together
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()
+}
+
+/// Check that `response` is not an error
+pub(crate) fn check(response: Response) -> Result<Response> {
+    match response {
+        Response::Error(message) => Err(Error::Remote(message)),
+        response => Ok(response),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use buffer::test_buffer;
+
+    use super::*;
+
+    #[test]
+    fn encode_and_decode() {
+        let requests = vec![
+            Request::Get(1),
+            Request::Put(1, u64::MAX),
+            Request::PutWithTtl(2, 3, Duration::from_millis(1500)),
+            Request::Delete(4),
+            Request::GetMany(vec![1, 2, 3]),
+            Request::PutMany(vec![(1, 2), (3, 4)]),
+            Request::FetchAdd(5, 6),
+            Request::Sync,
+            Request::Scan(7, 8),
+            Request::Stats,
+            Request::Expire(9, Duration::from_secs(10)),
+            Request::Ttl(11),
+            Request::GetVersioned(12),
+            Request::CompareAndSwap(13, 14, 15),
+            Request::FetchSub(16, 17),
+            Request::SaturatingFetchAdd(18, 19),
+            Request::SaturatingFetchSub(20, 21),
+            Request::FetchMax(22, 23),
+            Request::FetchMin(24, 25),
+        ];
+        for request in requests {
+            let mut buf = Vec::new();
+            request.encode(&mut buf);
+            assert_eq!(Request::decode(&buf).unwrap(), request);
+        }
+
+        let responses = vec![
+            Response::Done,
+            Response::Value(None),
+            Response::Value(Some(7)),
+            Response::Bool(true),
+            Response::Values(vec![Some(1), None]),
+            Response::Integer(8),
+            Response::Error("oops".to_string()),
+            Response::Pairs(vec![(1, 2), (3, 4)]),
+            Response::Stats(Stats {
+                hits: 9,
+                sync_time: Duration::from_millis(10),
+                ..Default::default()
+            }),
+            Response::Ttl(None),
+            Response::Ttl(Some(Duration::from_millis(11))),
+            Response::Versioned(Some(12), 13),
+        ];
+        for response in responses {
+            let mut buf = Vec::new();
+            response.encode(&mut buf);
+            assert_eq!(Response::decode(&buf).unwrap(), response);
+        }
+
+        assert!(Request::decode(&[1, 0]).is_err());
+        assert!(Request::decode(&[5, 255, 255, 255, 255]).is_err());
+        assert!(Request::decode(&[8, 0]).is_err());
+        assert!(Request::decode(&[42]).is_err());
+
+        let mut buf = Vec::new();
+        Request::PutWithTtl(2, 3, Duration::from_secs(u64::MAX)).encode(&mut buf);
+        let len = buf.len();
+        buf[len - 4..].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Request::decode(&buf).is_err());
+    }
+
+    #[test]
+    fn frames() {
+        let mut out = Vec::new();
+        write_frame(&mut out, b"abc").unwrap();
+        write_frame(&mut out, b"").unwrap();
+
+        let mut input = Cursor::new(out);
+        assert_eq!(read_frame(&mut input).unwrap(), Some(b"abc".to_vec()));
+        assert_eq!(read_frame(&mut input).unwrap(), Some(vec![]));
+        assert_eq!(read_frame(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn execute_requests() {
+        let buffer = test_buffer();
+
+        assert_eq!(execute(&buffer, Request::Put(1, 2)), Response::Done);
+        assert_eq!(
+            execute(&buffer, Request::FetchAdd(1, 3)),
+            Response::Integer(2)
+        );
+        assert_eq!(
+            execute(&buffer, Request::GetMany(vec![1, 2])),
+            Response::Values(vec![Some(5), None])
+        );
+        assert_eq!(execute(&buffer, Request::Delete(1)), Response::Bool(true));
+        assert_eq!(execute(&buffer, Request::Get(1)), Response::Value(None));
+
+        let keys = vec![0; MAX_GET_MANY_LEN + 1];
+        match execute(&buffer, Request::GetMany(keys)) {
+            Response::Error(_) => {}
+            response => panic!("unexpected {:?}", response),
+        }
+    }
+}
@@ -36,11 +36,7 @@ fn random_increments() {
 
     buffer.sync().unwrap();
 
-    let mut sum = 0;
-    for key in 0..n_data {
-        let mut entry = buffer.lock(key).unwrap();
-        sum += *entry.as_ref();
-    }
+    let sum: u64 = buffer.scan().map(|item| item.unwrap().1).sum();
     assert_eq!(sum, n_data as u64);
 }
 
@@ -74,7 +70,7 @@ fn random_read_and_increments() {
             let mut sum = 0;
             for key in keys {
                 let entry = buffer.lock(key).unwrap();
-                sum += *entry.as_ref();
+                sum += entry.get().unwrap().unwrap_or(0);
             }
             sum
         });
@@ -87,10 +83,6 @@ fn random_read_and_increments() {
 
     buffer.sync().unwrap();
 
-    let mut sum = 0;
-    for key in 0..n_data {
-        let entry = buffer.lock(key).unwrap();
-        sum += *entry.as_ref();
-    }
+    let sum: u64 = buffer.scan().map(|item| item.unwrap().1).sum();
     assert_eq!(sum, n_data as u64);
 }